@@ -0,0 +1,540 @@
+//! POSIX ACLs, `-A`: the ACLs following file entries since protocol 30, as rsync's acls.c. They
+//! are read and written as the `system.posix_acl_*` xattrs, which is what libacl does on Linux.
+
+use std::io;
+use std::path::Path;
+
+use eyre::{bail, ensure, eyre, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::envelope::{RsyncReadExt, RsyncWriteExt};
+use crate::file_list::{FileEntry, IndexedValues};
+use crate::uid_list::IdNames;
+use crate::xattrs::MAX_XATTR_VALUE;
+use crate::EnvelopedConn;
+
+const XMIT_USER_OBJ: u8 = 1 << 0;
+const XMIT_GROUP_OBJ: u8 = 1 << 1;
+const XMIT_MASK_OBJ: u8 = 1 << 2;
+const XMIT_OTHER_OBJ: u8 = 1 << 3;
+const XMIT_NAME_LIST: u8 = 1 << 4;
+
+// The low bits of the permissions of named entries.
+const XFLAG_NAME_FOLLOWS: u32 = 1 << 0;
+const XFLAG_NAME_IS_USER: u32 = 1 << 1;
+
+const ACCESS_XATTR: &str = "system.posix_acl_access";
+const DEFAULT_XATTR: &str = "system.posix_acl_default";
+
+// Linux's xattr representation, see posix_acl_xattr.h.
+const ACL_XATTR_VERSION: u32 = 2;
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+/// Named entries that fit in an xattr value, after the header and the 4 other entries.
+const MAX_ACL_NAMES: usize = (MAX_XATTR_VALUE - 4) / 8 - 4;
+
+/// An ACL the way rsync sends it, `rwx` bits per entry. Access ACLs leave out what the mode
+/// already tells, an empty default ACL means there is none.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Hash)]
+pub struct Acl {
+    pub user_obj: Option<u8>,
+    pub group_obj: Option<u8>,
+    pub mask_obj: Option<u8>,
+    pub other_obj: Option<u8>,
+    /// Named users and groups.
+    pub names: Vec<AclName>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct AclName {
+    pub user: bool,
+    pub id: u32,
+    pub perms: u8,
+}
+
+/// Access and default ACLs already in the file list, repeated ones are sent as their index.
+#[derive(Debug, Default)]
+pub struct AclLists {
+    access: IndexedValues<Acl>,
+    default: IndexedValues<Acl>,
+}
+
+impl Acl {
+    /// Drop what `mode` tells from an access ACL, as rsync's `rsync_acl_strip_perms`.
+    fn strip_perms(mut self, mode: u32) -> Self {
+        let group_perms = (mode >> 3 & 7) as u8;
+        self.user_obj = None;
+        if self.mask_obj.is_none() {
+            self.group_obj = None;
+        } else {
+            if self.group_obj == Some(group_perms) {
+                self.group_obj = None;
+            }
+            if !self.names.is_empty() && self.mask_obj == Some(group_perms) {
+                self.mask_obj = None;
+            }
+        }
+        self.other_obj = None;
+        self
+    }
+
+    fn from_xattr(value: &[u8]) -> Result<Self> {
+        let Some((version, entries)) = value.split_first_chunk::<4>() else {
+            bail!("ACL too short");
+        };
+        ensure!(
+            u32::from_le_bytes(*version) == ACL_XATTR_VERSION && entries.len() % 8 == 0,
+            "unsupported ACL format"
+        );
+        let mut acl = Self::default();
+        for entry in entries.chunks_exact(8) {
+            let tag = u16::from_le_bytes([entry[0], entry[1]]);
+            let perms = (u16::from_le_bytes([entry[2], entry[3]]) & 7) as u8;
+            let id = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]);
+            match tag {
+                ACL_USER_OBJ => acl.user_obj = Some(perms),
+                ACL_GROUP_OBJ => acl.group_obj = Some(perms),
+                ACL_MASK => acl.mask_obj = Some(perms),
+                ACL_OTHER => acl.other_obj = Some(perms),
+                ACL_USER | ACL_GROUP => acl.names.push(AclName {
+                    user: tag == ACL_USER,
+                    id,
+                    perms,
+                }),
+                _ => bail!("unknown ACL tag {:#x}", tag),
+            }
+        }
+        Ok(acl)
+    }
+
+    /// The xattr value of this ACL, with the entries left out taken from `mode`. None when
+    /// there's nothing the mode doesn't tell already.
+    fn to_xattr(&self, mode: u32, default: bool) -> Option<Vec<u8>> {
+        if default && *self == Self::default() || !default && self.names.is_empty() {
+            return None;
+        }
+        let perms = |obj: Option<u8>, shift: u32| obj.map_or(mode >> shift & 7, u32::from);
+        let mut names = self.names.clone();
+        names.sort_by_key(|name| (!name.user, name.id));
+        names.dedup_by_key(|name| (name.user, name.id));
+        // The mode wins over an access ACL, as rsync's `change_sacl_perms`. Its group bits are
+        // the mask.
+        let (user_obj, mask_obj, other_obj) = if default {
+            (self.user_obj, self.mask_obj, self.other_obj)
+        } else {
+            (None, None, None)
+        };
+
+        let mut entries = vec![(ACL_USER_OBJ, perms(user_obj, 6), ACL_UNDEFINED_ID)];
+        let named = |user: bool| {
+            let tag = if user { ACL_USER } else { ACL_GROUP };
+            names
+                .iter()
+                .filter(move |name| name.user == user)
+                .map(move |name| (tag, u32::from(name.perms), name.id))
+        };
+        entries.extend(named(true));
+        entries.push((ACL_GROUP_OBJ, perms(self.group_obj, 3), ACL_UNDEFINED_ID));
+        entries.extend(named(false));
+        if mask_obj.is_some() || !names.is_empty() {
+            entries.push((ACL_MASK, perms(mask_obj, 3), ACL_UNDEFINED_ID));
+        }
+        entries.push((ACL_OTHER, perms(other_obj, 0), ACL_UNDEFINED_ID));
+
+        let mut value = ACL_XATTR_VERSION.to_le_bytes().to_vec();
+        for (tag, perms, id) in entries {
+            value.extend_from_slice(&tag.to_le_bytes());
+            value.extend_from_slice(&(perms as u16).to_le_bytes());
+            value.extend_from_slice(&id.to_le_bytes());
+        }
+        Some(value)
+    }
+}
+
+/// The access ACL of `path` without what its `mode` tells, and for directories its default ACL.
+pub fn get_acls(path: &Path, mode: u32) -> Result<(Acl, Acl)> {
+    let access = read_acl(path, ACCESS_XATTR)?
+        .map(|acl| acl.strip_perms(mode))
+        .unwrap_or_default();
+    let default = if unix_mode::is_dir(mode) {
+        read_acl(path, DEFAULT_XATTR)?.unwrap_or_default()
+    } else {
+        Acl::default()
+    };
+    Ok((access, default))
+}
+
+/// Give `path` the ACLs of `entry`, filling in the access ACL from `mode`.
+pub fn set_acls(path: &Path, entry: &FileEntry, mode: u32) -> Result<()> {
+    set_acl(path, ACCESS_XATTR, entry.acl.to_xattr(mode, false))?;
+    if unix_mode::is_dir(entry.mode) {
+        set_acl(path, DEFAULT_XATTR, entry.default_acl.to_xattr(mode, true))?;
+    }
+    Ok(())
+}
+
+fn read_acl(path: &Path, name: &str) -> Result<Option<Acl>> {
+    match xattr::get(path, name) {
+        Ok(value) => value.as_deref().map(Acl::from_xattr).transpose(),
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => Ok(None),
+        Err(e) => Err(eyre!("can't read ACL of {}: {}", path.display(), e)),
+    }
+}
+
+fn set_acl(path: &Path, name: &str, value: Option<Vec<u8>>) -> Result<()> {
+    let current = match xattr::get(path, name) {
+        Ok(current) => current,
+        Err(e) if e.kind() == io::ErrorKind::Unsupported && value.is_none() => return Ok(()),
+        Err(e) => return Err(eyre!("can't read ACL of {}: {}", path.display(), e)),
+    };
+    if current == value {
+        return Ok(());
+    }
+    match value {
+        Some(value) => xattr::set(path, name, &value),
+        None => xattr::remove(path, name),
+    }
+    .map_err(|e| eyre!("can't set ACL of {}: {}", path.display(), e))
+}
+
+impl<R: AsyncRead + Unpin + Send, W: AsyncWrite + Unpin + Send> EnvelopedConn<R, W> {
+    /// Send the ACLs of `entry` after it, the default one only for directories.
+    pub async fn send_acls(&mut self, entry: &FileEntry, lists: &mut AclLists) -> Result<()> {
+        self.send_acl(&entry.acl, &mut lists.access).await?;
+        if unix_mode::is_dir(entry.mode) {
+            self.send_acl(&entry.default_acl, &mut lists.default)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn send_acl(&mut self, acl: &Acl, sent: &mut IndexedValues<Acl>) -> Result<()> {
+        if let Some(ndx) = sent.find_or_insert(acl) {
+            self.tx.write_varint(ndx as i32 + 1).await?;
+            return Ok(());
+        }
+        self.tx.write_varint(0).await?;
+
+        let objs = [
+            (acl.user_obj, XMIT_USER_OBJ),
+            (acl.group_obj, XMIT_GROUP_OBJ),
+            (acl.mask_obj, XMIT_MASK_OBJ),
+            (acl.other_obj, XMIT_OTHER_OBJ),
+        ];
+        let mut flags = 0;
+        for (obj, flag) in objs {
+            if obj.is_some() {
+                flags |= flag;
+            }
+        }
+        if !acl.names.is_empty() {
+            flags |= XMIT_NAME_LIST;
+        }
+        self.tx.write_u8(flags).await?;
+        for perms in objs.into_iter().filter_map(|(obj, _)| obj) {
+            self.tx.write_varint(perms as i32).await?;
+        }
+        if !acl.names.is_empty() {
+            // Names are sent in the id lists.
+            self.tx.write_varint(acl.names.len() as i32).await?;
+            for name in &acl.names {
+                let mut xbits = (name.perms as u32) << 2;
+                if name.user {
+                    xbits |= XFLAG_NAME_IS_USER;
+                }
+                self.tx.write_varint(name.id as i32).await?;
+                self.tx.write_varint(xbits as i32).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The counterpart of `send_acls`.
+    pub async fn recv_acls(
+        &mut self,
+        entry: &mut FileEntry,
+        lists: &mut AclLists,
+        names: &mut IdNames,
+    ) -> Result<()> {
+        entry.acl = self.recv_acl(&mut lists.access, names).await?;
+        if unix_mode::is_dir(entry.mode) {
+            entry.default_acl = self.recv_acl(&mut lists.default, names).await?;
+        }
+        Ok(())
+    }
+
+    async fn recv_acl(
+        &mut self,
+        received: &mut IndexedValues<Acl>,
+        names: &mut IdNames,
+    ) -> Result<Acl> {
+        let ndx = self.rx.read_varint().await?;
+        if ndx != 0 {
+            return usize::try_from(ndx - 1)
+                .ok()
+                .and_then(|ndx| received.get(ndx))
+                .cloned()
+                .ok_or_else(|| eyre!("ACL index {} out of range", ndx));
+        }
+
+        let flags = self.rx.read_u8().await?;
+        let mut acl = Acl::default();
+        for (obj, flag) in [
+            (&mut acl.user_obj, XMIT_USER_OBJ),
+            (&mut acl.group_obj, XMIT_GROUP_OBJ),
+            (&mut acl.mask_obj, XMIT_MASK_OBJ),
+            (&mut acl.other_obj, XMIT_OTHER_OBJ),
+        ] {
+            if flags & flag != 0 {
+                let perms = self.rx.read_varint().await? as u32;
+                ensure!(perms & !7 == 0, "invalid ACL permissions {:#o}", perms);
+                *obj = Some(perms as u8);
+            }
+        }
+        if flags & XMIT_NAME_LIST != 0 {
+            let count = self.rx.read_varint().await?;
+            ensure!(
+                usize::try_from(count).is_ok_and(|count| count <= MAX_ACL_NAMES),
+                "invalid ACL entry count {}",
+                count
+            );
+            for _ in 0..count {
+                let id = self.rx.read_varint().await? as u32;
+                let xbits = self.rx.read_varint().await? as u32;
+                let perms = xbits >> 2;
+                ensure!(perms & !7 == 0, "invalid ACL permissions {:#o}", perms);
+                let user = xbits & XFLAG_NAME_IS_USER != 0;
+                if xbits & XFLAG_NAME_FOLLOWS != 0 {
+                    let name = self.recv_id_name().await?;
+                    let names = if user {
+                        &mut names.users
+                    } else {
+                        &mut names.groups
+                    };
+                    names.insert(id, name);
+                }
+                acl.names.push(AclName {
+                    user,
+                    id,
+                    perms: perms as u8,
+                });
+            }
+        }
+
+        if acl.names.is_empty() {
+            // A mask without names only limits the group.
+            if let Some(mask) = acl.mask_obj.take() {
+                acl.group_obj = acl.group_obj.map(|group| group & mask);
+            }
+        } else if acl.mask_obj.is_none() {
+            let named = acl.names.iter().fold(0, |bits, name| bits | name.perms);
+            acl.mask_obj = Some(named | acl.group_obj.unwrap_or(0));
+        }
+        received.push(acl.clone());
+        Ok(acl)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(user: bool, id: u32, perms: u8) -> AclName {
+        AclName { user, id, perms }
+    }
+
+    #[test]
+    fn xattr_format() {
+        let acl = Acl {
+            user_obj: Some(7),
+            group_obj: Some(5),
+            mask_obj: Some(7),
+            other_obj: Some(0),
+            names: vec![name(false, 100, 4), name(true, 1000, 6)],
+        };
+        let value = acl.to_xattr(0o40755, true).unwrap();
+        assert_eq!(value.len(), 4 + 6 * 8);
+        // Sorted the way libacl does, named users after their owner.
+        let parsed = Acl::from_xattr(&value).unwrap();
+        assert_eq!(parsed.names, [name(true, 1000, 6), name(false, 100, 4)]);
+        assert_eq!(parsed.to_xattr(0o40755, true).unwrap(), value);
+
+        // The mode fills in an access ACL, including the mask.
+        let parsed = Acl::from_xattr(&acl.to_xattr(0o100640, false).unwrap()).unwrap();
+        assert_eq!(
+            (
+                parsed.user_obj,
+                parsed.group_obj,
+                parsed.mask_obj,
+                parsed.other_obj
+            ),
+            (Some(6), Some(5), Some(4), Some(0))
+        );
+
+        // Nothing the mode doesn't tell.
+        assert_eq!(Acl::default().to_xattr(0o40755, true), None);
+        let plain = Acl {
+            group_obj: Some(7),
+            ..Acl::default()
+        };
+        assert_eq!(plain.to_xattr(0o100644, false), None);
+
+        for value in [
+            &b"\x02\0\0"[..],
+            b"\x01\0\0\0",
+            b"\x02\0\0\0\x01\0",
+            b"\x02\0\0\0\x40\0\0\0\0\0\0\0",
+        ] {
+            assert!(Acl::from_xattr(value).is_err(), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn strip_perms() {
+        let acl = Acl {
+            user_obj: Some(7),
+            group_obj: Some(5),
+            mask_obj: None,
+            other_obj: Some(5),
+            names: vec![],
+        };
+        assert_eq!(acl.strip_perms(0o100755), Acl::default());
+
+        // The mode's group bits are the mask, the owning group's stay.
+        let acl = Acl {
+            user_obj: Some(7),
+            group_obj: Some(7),
+            mask_obj: Some(5),
+            other_obj: Some(0),
+            names: vec![name(true, 1000, 7)],
+        };
+        let stripped = acl.clone().strip_perms(0o100750);
+        assert_eq!(
+            stripped,
+            Acl {
+                group_obj: Some(7),
+                names: vec![name(true, 1000, 7)],
+                ..Acl::default()
+            }
+        );
+        // A mask without names is kept.
+        let acl = Acl {
+            names: vec![],
+            group_obj: Some(5),
+            ..acl
+        };
+        assert_eq!(
+            acl.strip_perms(0o100750),
+            Acl {
+                mask_obj: Some(5),
+                ..Acl::default()
+            }
+        );
+    }
+
+    async fn round_trip(list: &[FileEntry]) -> (Vec<FileEntry>, IdNames) {
+        let mut conn = EnvelopedConn::loopback(30);
+        let mut sent = AclLists::default();
+        for entry in list {
+            conn.send_acls(entry, &mut sent).await.unwrap();
+        }
+        conn.tx.flush().await.unwrap();
+
+        let mut received = AclLists::default();
+        let mut names = IdNames::default();
+        let mut entries = vec![];
+        for entry in list {
+            let mut entry = FileEntry::bare(&entry.name_lossy(), entry.mode);
+            conn.recv_acls(&mut entry, &mut received, &mut names)
+                .await
+                .unwrap();
+            entries.push(entry);
+        }
+        (entries, names)
+    }
+
+    #[tokio::test]
+    async fn wire_round_trip() {
+        let mut dir = FileEntry::bare("dir", 0o40755);
+        dir.acl = Acl {
+            group_obj: Some(7),
+            mask_obj: Some(7),
+            names: vec![name(true, 1000, 7), name(false, 100, 5)],
+            ..Acl::default()
+        };
+        dir.default_acl = Acl {
+            user_obj: Some(7),
+            group_obj: Some(5),
+            other_obj: Some(5),
+            ..Acl::default()
+        };
+        let mut file = FileEntry::bare("dir/file", 0o100644);
+        // Sent as the index of the directory's.
+        file.acl = dir.acl.clone();
+        let mut other = FileEntry::bare("dir/other", 0o100644);
+        // The mask is the union of the named entries and the group without one, and limits the
+        // group when there are none.
+        other.acl = Acl {
+            group_obj: Some(4),
+            names: vec![name(false, 100, 2)],
+            ..Acl::default()
+        };
+        let mut masked = FileEntry::bare("dir/masked", 0o100644);
+        masked.acl = Acl {
+            group_obj: Some(7),
+            mask_obj: Some(5),
+            ..Acl::default()
+        };
+
+        let (received, names) = round_trip(&[dir.clone(), file.clone(), other, masked]).await;
+        assert!(names.users.is_empty() && names.groups.is_empty());
+        assert_eq!(received[0].acl, dir.acl);
+        assert_eq!(received[0].default_acl, dir.default_acl);
+        assert_eq!(received[1].acl, file.acl);
+        assert_eq!(received[1].default_acl, Acl::default());
+        assert_eq!(received[2].acl.mask_obj, Some(6));
+        assert_eq!(
+            received[3].acl,
+            Acl {
+                group_obj: Some(5),
+                ..Acl::default()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn recv_names_and_limits() {
+        let mut conn = EnvelopedConn::loopback(30);
+        let xbits = 6 << 2 | XFLAG_NAME_IS_USER | XFLAG_NAME_FOLLOWS;
+        conn.tx.write_all(&[0, XMIT_NAME_LIST, 1]).await.unwrap();
+        conn.tx.write_varint(1000).await.unwrap();
+        conn.tx.write_varint(xbits as i32).await.unwrap();
+        conn.tx.write_all(b"\x05alice").await.unwrap();
+        conn.tx.write_all(&[0, XMIT_NAME_LIST]).await.unwrap();
+        conn.tx
+            .write_varint(MAX_ACL_NAMES as i32 + 1)
+            .await
+            .unwrap();
+        conn.tx.flush().await.unwrap();
+
+        let mut lists = AclLists::default();
+        let mut names = IdNames::default();
+        let mut entry = FileEntry::bare("file", 0o100644);
+        conn.recv_acls(&mut entry, &mut lists, &mut names)
+            .await
+            .unwrap();
+        assert_eq!(entry.acl.names, [name(true, 1000, 6)]);
+        assert_eq!(names.users[&1000], b"alice");
+
+        let err = conn
+            .recv_acls(&mut entry, &mut lists, &mut names)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "invalid ACL entry count 8188");
+    }
+}
@@ -0,0 +1,162 @@
+//! Daemon authentication (`@RSYNCD: AUTHREQD <challenge>`).
+
+use std::fmt::{Display, Formatter};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use eyre::{eyre, Context, Result};
+use md4::{Digest, Md4};
+use md5::Md5;
+
+use crate::opts::Opts;
+
+/// Environment variable consulted when neither `Opts::password` nor `Opts::password_file` is set.
+pub const PASSWORD_ENV: &str = "RSYNC_PASSWORD";
+
+/// Digests we can answer a challenge with, in order of preference. Sent in our greeting.
+pub const AUTH_DIGESTS: &str = "md5 md4";
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AuthDigest {
+    /// MD4 with a zero seed prepended, used before protocol 30.
+    Md4Old,
+    Md4,
+    Md5,
+}
+
+impl AuthDigest {
+    /// Pick the first digest in the daemon's list we support. Daemons that don't send a list use
+    /// MD5 since protocol 30.
+    pub fn negotiate(protocol: i32, daemon_digests: &[String]) -> Result<Self> {
+        if protocol < 30 {
+            return Ok(AuthDigest::Md4Old);
+        } else if daemon_digests.is_empty() {
+            return Ok(AuthDigest::Md5);
+        }
+        daemon_digests
+            .iter()
+            .find_map(|digest| match digest.as_str() {
+                "md5" => Some(AuthDigest::Md5),
+                "md4" => Some(AuthDigest::Md4),
+                _ => None,
+            })
+            .ok_or_else(|| eyre!("no common auth digest: {}", daemon_digests.join(" ")))
+    }
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    /// The daemon asked for a password but none was configured.
+    NoPassword { module: String },
+    /// The password file is readable by other users.
+    InsecurePasswordFile,
+    /// The daemon rejected our credentials.
+    Rejected { module: String, msg: String },
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::NoPassword { module } => write!(
+                f,
+                "module {} requires authentication, but no password is given",
+                module
+            ),
+            AuthError::InsecurePasswordFile => {
+                write!(f, "password file must not be other-accessible")
+            }
+            AuthError::Rejected { module, msg } => {
+                write!(f, "authentication failed on module {}: {}", module, msg)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+#[derive(Debug)]
+pub struct Credentials {
+    pub user: String,
+    pub password: String,
+}
+
+impl Credentials {
+    /// Resolve credentials in the same order as rsync: url user, then `Opts::user`, then `$USER`
+    /// for the name; `Opts::password`, then `Opts::password_file`, then `$RSYNC_PASSWORD` for the
+    /// password.
+    pub fn resolve(url_user: &str, module: &str, opts: &Opts) -> Result<Self> {
+        let user = Some(url_user)
+            .filter(|user| !user.is_empty())
+            .map(ToString::to_string)
+            .or_else(|| opts.user.clone())
+            .or_else(|| std::env::var("USER").ok())
+            .or_else(|| std::env::var("LOGNAME").ok())
+            .unwrap_or_else(|| String::from("nobody"));
+
+        let password = if let Some(password) = &opts.password {
+            password.clone()
+        } else if let Some(path) = &opts.password_file {
+            read_password_file(path)?
+        } else if let Ok(password) = std::env::var(PASSWORD_ENV) {
+            password
+        } else {
+            return Err(AuthError::NoPassword {
+                module: module.to_string(),
+            }
+            .into());
+        };
+
+        Ok(Self { user, password })
+    }
+
+    /// Response line to the daemon challenge, without the trailing newline.
+    pub fn response(&self, challenge: &str, digest: AuthDigest) -> String {
+        let hash = match digest {
+            AuthDigest::Md4Old | AuthDigest::Md4 => {
+                let mut hasher = Md4::default();
+                if digest == AuthDigest::Md4Old {
+                    hasher.update(0i32.to_le_bytes());
+                }
+                hasher.update(self.password.as_bytes());
+                hasher.update(challenge.as_bytes());
+                hasher.finalize().to_vec()
+            }
+            AuthDigest::Md5 => {
+                let mut hasher = Md5::default();
+                hasher.update(self.password.as_bytes());
+                hasher.update(challenge.as_bytes());
+                hasher.finalize().to_vec()
+            }
+        };
+        format!("{} {}", self.user, base64_encode(&hash))
+    }
+}
+
+fn read_password_file(path: &Path) -> Result<String> {
+    let meta = std::fs::metadata(path)
+        .with_context(|| format!("can't stat password file {}", path.display()))?;
+    if meta.permissions().mode() & 0o006 != 0 {
+        return Err(AuthError::InsecurePasswordFile.into());
+    }
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("can't read password file {}", path.display()))?;
+    // Only the first line is used.
+    Ok(content.lines().next().unwrap_or_default().to_string())
+}
+
+/// rsync flavoured base64: standard alphabet, no padding.
+fn base64_encode(buf: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::with_capacity((buf.len() * 4).div_ceil(3));
+    for chunk in buf.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |acc, (i, b)| acc | (*b as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+    out
+}
@@ -1,9 +1,13 @@
 use std::cmp::max;
 
-use eyre::Result;
+use eyre::{ensure, eyre, Result};
 use md4::{Digest, Md4};
-use num::integer::Roots;
+use md5::Md5;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use xxhash_rust::xxh3::{xxh3_128_with_seed, xxh3_64_with_seed, Xxh3};
+use xxhash_rust::xxh64::{xxh64, Xxh64};
+
+use crate::protocol::Protocol;
 
 #[derive(Debug, Default)]
 pub struct SumHead {
@@ -14,32 +18,155 @@ pub struct SumHead {
 }
 
 const BLOCK_SIZE: u64 = 700;
+const OLD_MAX_BLOCK_SIZE: u64 = 1 << 29;
+pub const MAX_BLOCK_SIZE: u64 = 1 << 17;
+/// Strong sums are sized for a chance of 1 in 2^BLOCKSUM_BIAS of a bad file.
+const BLOCKSUM_BIAS: i32 = 10;
+const SHORT_SUM_LENGTH: i32 = 2;
 
-impl SumHead {
-    pub fn sum_sizes_sqroot(len: u64) -> Self {
-        let block_len = if len <= BLOCK_SIZE * BLOCK_SIZE {
-            BLOCK_SIZE
+/// Our strong checksums in order of preference, as sent in the negotiation since protocol 30.
+pub const CHECKSUM_LIST: &str = "xxh128 xxh3 xxh64 md5 md4";
+
+/// Strong checksum of block sums and whole files, see rsync's checksum.c.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum StrongHash {
+    Md4,
+    Md5,
+    Xxh64,
+    /// 64 bit XXH3.
+    Xxh3,
+    /// 128 bit XXH3.
+    Xxh128,
+}
+
+impl StrongHash {
+    /// The one used without negotiation. Protocol 30 switched from MD4 to MD5.
+    pub fn for_protocol(version: i32) -> Self {
+        if version < 30 {
+            StrongHash::Md4
         } else {
-            // Note: this is different from original implementation.
-            // We use floor sqrt instead of ceil sqrt.
-            let b = len.sqrt();
+            StrongHash::Md5
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "md4" => Some(StrongHash::Md4),
+            "md5" => Some(StrongHash::Md5),
+            "xxh64" | "xxhash" => Some(StrongHash::Xxh64),
+            "xxh3" => Some(StrongHash::Xxh3),
+            "xxh128" => Some(StrongHash::Xxh128),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            StrongHash::Md4 => "md4",
+            StrongHash::Md5 => "md5",
+            StrongHash::Xxh64 => "xxh64",
+            StrongHash::Xxh3 => "xxh3",
+            StrongHash::Xxh128 => "xxh128",
+        }
+    }
+
+    pub fn digest_len(self) -> usize {
+        match self {
+            StrongHash::Md4 | StrongHash::Md5 | StrongHash::Xxh128 => 16,
+            StrongHash::Xxh64 | StrongHash::Xxh3 => 8,
+        }
+    }
+
+    /// Pick from the space separated lists of both sides like rsync: the first of the client's
+    /// that the server has too.
+    pub fn negotiate(client_list: &[u8], server_list: &[u8]) -> Result<Self> {
+        let server_list = String::from_utf8_lossy(server_list);
+        let server_names: Vec<_> = server_list.split_whitespace().collect();
+        String::from_utf8_lossy(client_list)
+            .split_whitespace()
+            .filter(|name| server_names.contains(name))
+            .find_map(Self::from_name)
+            .ok_or_else(|| eyre!("failed to negotiate a checksum choice"))
+    }
+}
+
+/// `--block-size=SIZE`, capped at the protocol 30 maximum whatever the version, as rsync does.
+pub fn parse_block_size(size: &str) -> Result<u32> {
+    let size: u32 = size.parse()?;
+    ensure!(
+        size > 0 && u64::from(size) <= MAX_BLOCK_SIZE,
+        "--block-size={} is invalid (max: {})",
+        size,
+        MAX_BLOCK_SIZE
+    );
+    Ok(size)
+}
 
-            // For unknown reason this value is rounded up to a multiple of 8.
-            // This won't overflow because sqrt of u64 must be much smaller than u64::MAX.
-            let b = b + 7 & !7;
+/// `--checksum-choice=STR[,STR]`. The second one is for `--checksum`, which we don't do, and
+/// `auto` means negotiating.
+pub fn parse_checksum_choice(choice: &str) -> Result<Option<StrongHash>> {
+    let name = choice.split(',').next().unwrap_or_default();
+    if name == "auto" {
+        return Ok(None);
+    }
+    StrongHash::from_name(name)
+        .map(Some)
+        .ok_or_else(|| eyre!("unknown checksum name: {}", name))
+}
 
-            max(b, BLOCK_SIZE)
+impl SumHead {
+    /// Block and strong sum sizes for a basis file of `len` bytes, the same as rsync's
+    /// `sum_sizes_sqroot`. `block_size` is `--block-size`. Strong sums are at most as long as the
+    /// digest of the negotiated checksum.
+    pub fn sum_sizes_sqroot(
+        len: u64,
+        block_size: Option<u32>,
+        protocol: &Protocol,
+    ) -> Result<Self> {
+        let max_block_len = if protocol.version < 30 {
+            OLD_MAX_BLOCK_SIZE
+        } else {
+            MAX_BLOCK_SIZE
         };
+        let block_len = if let Some(block_size) = block_size {
+            u64::from(block_size)
+        } else if len <= BLOCK_SIZE * BLOCK_SIZE {
+            BLOCK_SIZE
+        } else {
+            // The square root, rounded down to a multiple of 8.
+            let mut c = 1 << (len.ilog2() / 2);
+            if c >= max_block_len {
+                max_block_len
+            } else {
+                let mut block_len = 0;
+                while c >= 8 {
+                    block_len |= c;
+                    if len < block_len * block_len {
+                        block_len &= !c;
+                    }
+                    c >>= 1;
+                }
+                max(block_len, BLOCK_SIZE)
+            }
+        };
+        ensure!(block_len > 0, "invalid block size 0");
 
-        // TODO blocksum_bits = BLOCKSUM_EXP + 2*log2(file_len) - log2(block_len)
-        let checksum_len = 16;
+        // blocksum_bits = BLOCKSUM_BIAS + 2*log2(len) - log2(block_len), minus the 32 bits of the
+        // rolling checksum, in bytes.
+        let bits = (BLOCKSUM_BIAS + 2 * len.checked_ilog2().unwrap_or(0) as i32
+            - block_len.ilog2() as i32)
+            .max(0);
+        let checksum_len = ((bits + 1 - 32 + 7) / 8)
+            .clamp(SHORT_SUM_LENGTH, protocol.checksum.digest_len() as i32);
 
-        Self {
-            checksum_count: i32::try_from((len + block_len - 1) / block_len).expect("overflow"),
-            block_len: i32::try_from(block_len).expect("overflow"),
+        let checksum_count = i32::try_from(len.div_ceil(block_len));
+        ensure!(checksum_count.is_ok(), "too many blocks for {} bytes", len);
+        Ok(Self {
+            checksum_count: checksum_count.expect("checked above"),
+            block_len: i32::try_from(block_len)?,
             checksum_len,
-            remainder_len: i32::try_from(len % block_len).expect("overflow"),
-        }
+            remainder_len: (len % block_len) as i32,
+        })
     }
     pub async fn read_from<R: AsyncReadExt + Unpin>(rx: &mut R) -> Result<Self> {
         let checksum_count = rx.read_i32_le().await?;
@@ -101,9 +228,270 @@ pub fn checksum_1(buf: &[u8]) -> u32 {
     (s1 & 0xffff) + (s2 << 16)
 }
 
-pub fn checksum_2(seed: i32, buf: &[u8]) -> Vec<u8> {
-    let mut hasher = Md4::default();
-    hasher.update(buf);
-    hasher.update(&seed.to_le_bytes());
-    hasher.finalize().to_vec()
+/// `checksum_1` of a window sliding over a buffer, updated in constant time per byte like rsync's
+/// `hash_search`. The window may also shrink at the end of the buffer.
+#[derive(Debug, Copy, Clone)]
+pub struct RollingChecksum {
+    /// Sum of the bytes in the window.
+    s1: u32,
+    /// Sum of the prefix sums of the window.
+    s2: u32,
+    len: u32,
+}
+
+impl RollingChecksum {
+    pub fn new(buf: &[u8]) -> Self {
+        let (s1, s2) = buf.iter().fold((0u32, 0u32), |(s1, s2), b| {
+            let s1 = s1.wrapping_add(sign_extend(*b));
+            (s1, s2.wrapping_add(s1))
+        });
+        Self {
+            s1,
+            s2,
+            len: buf.len() as u32,
+        }
+    }
+
+    /// Slide the window by one byte, `out_byte` leaving it and `in_byte` entering it.
+    pub fn roll(&mut self, out_byte: u8, in_byte: u8) {
+        self.roll_out(out_byte);
+        self.s1 = self.s1.wrapping_add(sign_extend(in_byte));
+        self.s2 = self.s2.wrapping_add(self.s1);
+        self.len += 1;
+    }
+
+    /// Shrink the window by its first byte, `out_byte`.
+    pub fn roll_out(&mut self, out_byte: u8) {
+        let out = sign_extend(out_byte);
+        self.s1 = self.s1.wrapping_sub(out);
+        self.s2 = self.s2.wrapping_sub(out.wrapping_mul(self.len));
+        self.len -= 1;
+    }
+
+    /// Same as `checksum_1` of the window.
+    pub fn digest(&self) -> u32 {
+        (self.s1 & 0xffff) + (self.s2 << 16)
+    }
+}
+
+/// Strong block checksum, with the seed mixed in.
+pub fn checksum_2(seed: i32, buf: &[u8], protocol: &Protocol) -> Vec<u8> {
+    // The xxHash seeds are 64 bits, sign extended like rsync's int.
+    let xxh_seed = seed as u64;
+    let seed = Some(seed.to_le_bytes()).filter(|_| seed != 0);
+    match protocol.checksum {
+        StrongHash::Md4 => {
+            let mut hasher = Md4::default();
+            hasher.update(buf);
+            if let Some(seed) = seed {
+                hasher.update(seed);
+            }
+            hasher.finalize().to_vec()
+        }
+        StrongHash::Md5 => {
+            let mut hasher = Md5::default();
+            if let Some(seed) = seed.filter(|_| protocol.proper_seed_order()) {
+                hasher.update(seed);
+            }
+            hasher.update(buf);
+            if let Some(seed) = seed.filter(|_| !protocol.proper_seed_order()) {
+                hasher.update(seed);
+            }
+            hasher.finalize().to_vec()
+        }
+        StrongHash::Xxh64 => xxh64(buf, xxh_seed).to_le_bytes().to_vec(),
+        StrongHash::Xxh3 => xxh3_64_with_seed(buf, xxh_seed).to_le_bytes().to_vec(),
+        // The low half first.
+        StrongHash::Xxh128 => xxh3_128_with_seed(buf, xxh_seed).to_le_bytes().to_vec(),
+    }
+}
+
+/// Hasher for the whole-file checksum sent after the file data.
+pub enum FileHasher {
+    /// Prepends the seed.
+    Md4(Md4),
+    /// Doesn't use the seed at all, nor do the others.
+    Md5(Md5),
+    Xxh64(Xxh64),
+    Xxh3(Box<Xxh3>),
+    Xxh128(Box<Xxh3>),
+}
+
+impl FileHasher {
+    pub fn new(seed: i32, protocol: &Protocol) -> Self {
+        match protocol.checksum {
+            StrongHash::Md4 => {
+                let mut hasher = Md4::default();
+                // Only rsync's old MD4 whole-file sum is seeded.
+                if protocol.version < 30 {
+                    hasher.update(seed.to_le_bytes());
+                }
+                FileHasher::Md4(hasher)
+            }
+            StrongHash::Md5 => FileHasher::Md5(Md5::default()),
+            StrongHash::Xxh64 => FileHasher::Xxh64(Xxh64::new(0)),
+            StrongHash::Xxh3 => FileHasher::Xxh3(Box::default()),
+            StrongHash::Xxh128 => FileHasher::Xxh128(Box::default()),
+        }
+    }
+
+    pub fn update(&mut self, buf: &[u8]) {
+        match self {
+            FileHasher::Md4(hasher) => hasher.update(buf),
+            FileHasher::Md5(hasher) => hasher.update(buf),
+            FileHasher::Xxh64(hasher) => hasher.update(buf),
+            FileHasher::Xxh3(hasher) | FileHasher::Xxh128(hasher) => hasher.update(buf),
+        }
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            FileHasher::Md4(hasher) => hasher.finalize().to_vec(),
+            FileHasher::Md5(hasher) => hasher.finalize().to_vec(),
+            FileHasher::Xxh64(hasher) => hasher.digest().to_le_bytes().to_vec(),
+            FileHasher::Xxh3(hasher) => hasher.digest().to_le_bytes().to_vec(),
+            FileHasher::Xxh128(hasher) => hasher.digest128().to_le_bytes().to_vec(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// (len, --block-size, protocol, [count, block_len, checksum_len, remainder]) as computed by
+    /// rsync's `sum_sizes_sqroot`: the function copied verbatim out of rsync 3.2.7's generator.c
+    /// into a harness that sets `protocol_version` and `block_size` for each row (csum_length 2,
+    /// as for a delta transfer) and prints `sum`, built and run with `cc -o golden golden.c &&
+    /// ./golden`.
+    const GOLDEN: &[(u64, u32, i32, [i32; 4])] = &[
+        (0, 0, 29, [0, 700, 2, 0]),
+        (1, 0, 29, [1, 700, 2, 1]),
+        (700, 0, 29, [1, 700, 2, 0]),
+        (490000, 0, 29, [700, 700, 2, 0]),
+        (490001, 0, 29, [701, 700, 2, 1]),
+        (1000000, 0, 29, [1000, 1000, 2, 0]),
+        (1048576, 0, 29, [1024, 1024, 2, 0]),
+        (123456789, 0, 29, [11119, 11104, 3, 2517]),
+        (734003200, 0, 29, [27097, 27088, 3, 26752]),
+        (4700000000, 0, 29, [68562, 68552, 4, 6328]),
+        (68719476736, 0, 29, [262144, 262144, 5, 0]),
+        (1099511627776, 0, 29, [1048576, 1048576, 5, 0]),
+        (0, 0, 31, [0, 700, 2, 0]),
+        (490001, 0, 31, [701, 700, 2, 1]),
+        (123456789, 0, 31, [11119, 11104, 3, 2517]),
+        (4700000000, 0, 31, [68562, 68552, 4, 6328]),
+        (68719476736, 0, 31, [524288, 131072, 5, 0]),
+        (1099511627776, 0, 31, [8388608, 131072, 6, 0]),
+        (0, 2048, 31, [0, 2048, 2, 0]),
+        (700, 2048, 31, [1, 2048, 2, 700]),
+        (490000, 2048, 31, [240, 2048, 2, 528]),
+        (1048576, 2048, 31, [512, 2048, 2, 0]),
+        (123456789, 2048, 29, [60282, 2048, 3, 1301]),
+        (734003200, 2048, 31, [358400, 2048, 4, 0]),
+        (4700000000, 2048, 31, [2294922, 2048, 4, 1792]),
+        (1099511627776, 2048, 29, [536870912, 2048, 6, 0]),
+    ];
+
+    #[test]
+    fn sum_sizes_match_rsync() {
+        for &(len, block_size, version, expected) in GOLDEN {
+            let block_size = Some(block_size).filter(|size| *size != 0);
+            let head = SumHead::sum_sizes_sqroot(len, block_size, &Protocol::new(version)).unwrap();
+            assert_eq!(
+                [
+                    head.checksum_count,
+                    head.block_len,
+                    head.checksum_len,
+                    head.remainder_len
+                ],
+                expected,
+                "len {} block size {:?} protocol {}",
+                len,
+                block_size,
+                version
+            );
+        }
+    }
+
+    #[test]
+    fn sum_len_capped_at_digest() {
+        let mut protocol = Protocol::new(29);
+        let head = SumHead::sum_sizes_sqroot(1 << 58, None, &protocol).unwrap();
+        assert_eq!(head.checksum_len, 9);
+        protocol.checksum = StrongHash::Xxh64;
+        let head = SumHead::sum_sizes_sqroot(1 << 58, None, &protocol).unwrap();
+        assert_eq!(head.checksum_len, 8);
+    }
+
+    #[test]
+    fn negotiate_client_preference() {
+        let rsync_list = b"xxh128 xxh3 xxh64 md5 md4 sha1 none";
+        assert_eq!(
+            StrongHash::negotiate(rsync_list, CHECKSUM_LIST.as_bytes()).unwrap(),
+            StrongHash::Xxh128
+        );
+        assert_eq!(
+            StrongHash::negotiate(b"sha1 md5 xxh64", CHECKSUM_LIST.as_bytes()).unwrap(),
+            StrongHash::Md5
+        );
+        assert!(StrongHash::negotiate(b"sha1 none", CHECKSUM_LIST.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn too_many_blocks() {
+        assert!(SumHead::sum_sizes_sqroot(1 << 40, Some(8), &Protocol::new(31)).is_err());
+    }
+
+    #[test]
+    fn md4_file_sum_seeded_before_protocol_30() {
+        let seed = 0x1234_5678;
+        let data = b"whole file";
+        let file_sum = |version| {
+            let mut protocol = Protocol::new(version);
+            protocol.checksum = StrongHash::Md4;
+            let mut hasher = FileHasher::new(seed, &protocol);
+            hasher.update(data);
+            hasher.finalize()
+        };
+        let seeded = Md4::new()
+            .chain_update(seed.to_le_bytes())
+            .chain_update(data)
+            .finalize();
+        assert_eq!(file_sum(29), seeded.to_vec());
+        assert_eq!(file_sum(30), Md4::digest(data).to_vec());
+        assert_eq!(file_sum(31), Md4::digest(data).to_vec());
+    }
+
+    #[test]
+    fn rolling_checksum_matches_checksum_1() {
+        // A simple LCG, half of its bytes are sign extended by the checksum.
+        let mut state = 0x2545_f491_u32;
+        let data: Vec<u8> = (0..3000)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (state >> 24) as u8
+            })
+            .collect();
+        assert!(data.iter().any(|b| *b >= 0x80));
+
+        for window in [1, 7, 700] {
+            let mut sum = RollingChecksum::new(&data[..window]);
+            for offset in 0..data.len() {
+                let end = data.len().min(offset + window);
+                assert_eq!(
+                    sum.digest(),
+                    checksum_1(&data[offset..end]),
+                    "window {} offset {}",
+                    window,
+                    offset
+                );
+                if end < data.len() {
+                    sum.roll(data[offset], data[end]);
+                } else {
+                    sum.roll_out(data[offset]);
+                }
+            }
+        }
+    }
 }
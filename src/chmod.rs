@@ -0,0 +1,228 @@
+//! `--chmod` style permission overrides, like `D755,F644` or `Dg+s,ug+w,o-rwx,a+rX`.
+
+use eyre::{eyre, Result};
+
+use crate::file_list::FileEntry;
+
+/// Operators of a symbolic clause.
+const OPS: [char; 3] = ['+', '-', '='];
+
+/// Comma separated chmod clauses, applied in order.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Chmod {
+    clauses: Vec<Clause>,
+}
+
+/// A single operation, `u+r-w` makes two.
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Clause {
+    /// `D` or `F` prefix, only apply to directories or only to the rest.
+    dirs: Option<bool>,
+    op: Op,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Op {
+    /// Octal, replaces all permission bits.
+    Set(u32),
+    Symbolic {
+        /// Bits selected by `ugoa`.
+        who: u32,
+        action: u8,
+        /// Bits selected by `rwxst`.
+        perms: u32,
+        /// `X`, execute for directories and already executable files.
+        cond_exec: bool,
+    },
+}
+
+impl Chmod {
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut clauses = vec![];
+        for clause in s.split(',') {
+            clauses.extend(Clause::parse(clause).ok_or_else(|| eyre!("invalid chmod: {}", s))?);
+        }
+        Ok(Self { clauses })
+    }
+
+    /// Apply the clauses of `other` after ours, for repeated `--chmod` options.
+    pub fn append(&mut self, mut other: Chmod) {
+        self.clauses.append(&mut other.clauses);
+    }
+
+    /// Tweak the permission bits of `mode`, keeping the file type.
+    pub fn apply(&self, mode: u32) -> u32 {
+        let is_dir = unix_mode::is_dir(mode);
+        let mut perms = mode & 0o7777;
+        for clause in &self.clauses {
+            if clause.dirs.is_some_and(|dirs| dirs != is_dir) {
+                continue;
+            }
+            perms = match clause.op {
+                Op::Set(bits) => bits,
+                Op::Symbolic {
+                    who,
+                    action,
+                    perms: mut bits,
+                    cond_exec,
+                } => {
+                    if cond_exec && (is_dir || perms & 0o111 != 0) {
+                        bits |= 0o111;
+                    }
+                    bits &= who;
+                    match action {
+                        b'+' => perms | bits,
+                        b'-' => perms & !bits,
+                        _ => perms & !who | bits,
+                    }
+                }
+            };
+        }
+        mode & !0o7777 | perms
+    }
+
+    /// Tweak the modes of a file list about to be sent. Like rsync, symlinks keep theirs.
+    pub fn apply_to_list(&self, list: &mut [FileEntry]) {
+        for entry in list
+            .iter_mut()
+            .filter(|entry| !unix_mode::is_symlink(entry.mode))
+        {
+            entry.mode = self.apply(entry.mode);
+        }
+    }
+}
+
+impl Clause {
+    fn parse(s: &str) -> Option<Vec<Self>> {
+        let (dirs, s) = match s.as_bytes().first() {
+            Some(b'D') => (Some(true), &s[1..]),
+            Some(b'F') => (Some(false), &s[1..]),
+            _ => (None, s),
+        };
+
+        if !s.is_empty() && s.bytes().all(|c| c.is_ascii_digit()) {
+            let bits = u32::from_str_radix(s, 8)
+                .ok()
+                .filter(|bits| *bits <= 0o7777)?;
+            return Some(vec![Self {
+                dirs,
+                op: Op::Set(bits),
+            }]);
+        }
+
+        let split = s.find(OPS)?;
+        let (who_chars, mut rest) = s.split_at(split);
+        let mut who = 0;
+        for c in who_chars.bytes() {
+            who |= match c {
+                b'u' => 0o4700,
+                b'g' => 0o2070,
+                b'o' => 0o1007,
+                b'a' => 0o7777,
+                _ => return None,
+            };
+        }
+        if who == 0 {
+            who = 0o7777;
+        }
+
+        let mut clauses = vec![];
+        while let Some(&action) = rest.as_bytes().first() {
+            let end = rest[1..].find(OPS).map_or(rest.len(), |i| i + 1);
+            let mut perms = 0;
+            let mut cond_exec = false;
+            for c in rest[1..end].bytes() {
+                match c {
+                    b'r' => perms |= 0o444,
+                    b'w' => perms |= 0o222,
+                    b'x' => perms |= 0o111,
+                    b'X' => cond_exec = true,
+                    b's' => perms |= 0o6000,
+                    b't' => perms |= 0o1000,
+                    _ => return None,
+                }
+            }
+            clauses.push(Self {
+                dirs,
+                op: Op::Symbolic {
+                    who,
+                    action,
+                    perms,
+                    cond_exec,
+                },
+            });
+            rest = &rest[end..];
+        }
+        Some(clauses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIR: u32 = 0o040000;
+    const FILE: u32 = 0o100000;
+
+    fn apply(chmod: &str, mode: u32) -> u32 {
+        Chmod::parse(chmod).unwrap().apply(mode)
+    }
+
+    #[test]
+    fn parse() {
+        for chmod in [
+            "D755,F644",
+            "Dg+s,ug+w,o-rwx,a+rX",
+            "u+r-w",
+            "go=",
+            "+t",
+            "F=r",
+        ] {
+            assert!(Chmod::parse(chmod).is_ok(), "{}", chmod);
+        }
+        for chmod in ["", "D", "u", "10000", "8", "u+q", "z+r", "a+r,", "Du+r,Fx"] {
+            assert!(Chmod::parse(chmod).is_err(), "{}", chmod);
+        }
+        assert_eq!(Chmod::parse("u+r-w").unwrap().clauses.len(), 2);
+    }
+
+    #[test]
+    fn octal() {
+        assert_eq!(apply("640", FILE | 0o4777), FILE | 0o640);
+        assert_eq!(apply("D755,F644", DIR | 0o700), DIR | 0o755);
+        assert_eq!(apply("D755,F644", FILE | 0o700), FILE | 0o644);
+    }
+
+    #[test]
+    fn symbolic() {
+        assert_eq!(apply("go-w", FILE | 0o666), FILE | 0o644);
+        assert_eq!(apply("a+r", FILE | 0o600), FILE | 0o644);
+        assert_eq!(apply("o=", FILE | 0o777), FILE | 0o770);
+        assert_eq!(apply("u+s,g+s", FILE | 0o755), FILE | 0o6755);
+        assert_eq!(apply("+t", DIR | 0o777), DIR | 0o1777);
+        // Execute only for directories and what some user may already execute.
+        assert_eq!(apply("a+rX", FILE | 0o600), FILE | 0o644);
+        assert_eq!(apply("a+rX", FILE | 0o700), FILE | 0o755);
+        assert_eq!(apply("a+rX", DIR | 0o700), DIR | 0o755);
+    }
+
+    #[test]
+    fn dirs_and_files() {
+        assert_eq!(apply("Dg+s,Fo-rwx", DIR | 0o775), DIR | 0o2775);
+        assert_eq!(apply("Dg+s,Fo-rwx", FILE | 0o775), FILE | 0o770);
+        assert_eq!(apply("Fa-x", DIR | 0o755), DIR | 0o755);
+    }
+
+    #[test]
+    fn multiple_ops() {
+        assert_eq!(apply("u+r-w", FILE | 0o200), FILE | 0o400);
+        assert_eq!(apply("go=r+x", FILE | 0o777), FILE | 0o755);
+        assert_eq!(apply("a=rX,u+w", FILE | 0o700), FILE | 0o755);
+        assert_eq!(apply("a=rX,u+w", FILE | 0o666), FILE | 0o644);
+        assert_eq!(apply("a=rX,u+w", DIR | 0o700), DIR | 0o755);
+
+        let mut chmod = Chmod::parse("F600").unwrap();
+        chmod.append(Chmod::parse("Fg+r").unwrap());
+        assert_eq!(chmod.apply(FILE | 0o777), FILE | 0o640);
+    }
+}
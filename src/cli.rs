@@ -0,0 +1,360 @@
+//! The client's command line, a subset of rsync's.
+
+use std::path::{Path, PathBuf};
+
+use eyre::{bail, ensure, eyre, Result};
+use url::Url;
+
+use crate::chksum::{parse_block_size, parse_checksum_choice};
+use crate::chmod::Chmod;
+use crate::delete::DeleteMode;
+use crate::filter::{load_exclude_from, load_include_from, Rule};
+use crate::opts::{Opts, UnsafeLinks};
+use crate::uid_list::IdMap;
+
+/// One side of a transfer.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Location {
+    /// `rsync://[user@]host[:port]/module/path` or `[user@]host::module/path`.
+    Daemon(Url),
+    /// `[user@]host:path`, reached through the remote shell.
+    Shell {
+        host: String,
+        path: String,
+    },
+    Local(PathBuf),
+}
+
+impl Location {
+    fn parse(arg: &str) -> Result<Self> {
+        if arg.starts_with("rsync://") {
+            return Ok(Location::Daemon(Url::parse(arg)?));
+        }
+        // Like rsync, a colon before any slash makes it remote.
+        match arg.split_once(':') {
+            Some((host, path)) if !host.is_empty() && !host.contains('/') => {
+                if let Some(path) = path.strip_prefix(':') {
+                    Ok(Location::Daemon(Url::parse(&format!(
+                        "rsync://{}/{}",
+                        host, path
+                    ))?))
+                } else {
+                    Ok(Location::Shell {
+                        host: host.to_string(),
+                        path: path.to_string(),
+                    })
+                }
+            }
+            _ => Ok(Location::Local(PathBuf::from(arg))),
+        }
+    }
+}
+
+pub struct Args {
+    /// `dest` is set when pulling.
+    pub opts: Opts,
+    pub src: Location,
+    /// Missing when listing the modules of a daemon.
+    pub dest: Option<Location>,
+    /// `-e`, the remote shell with its arguments.
+    pub rsh: String,
+    /// `--rsync-path`, the program to run on the other end.
+    pub rsync_path: String,
+}
+
+impl Args {
+    /// Filter files are read as their options are met, so `--from0` must come first, like with
+    /// rsync.
+    pub async fn parse(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut opts = Opts::default();
+        let mut rsh = String::from("ssh");
+        let mut rsync_path = String::from("rsync");
+        let mut positional = vec![];
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if let Some(long) = arg.strip_prefix("--") {
+                let (name, value) = match long.split_once('=') {
+                    Some((name, value)) => (name, Some(value)),
+                    None => (long, None),
+                };
+                let value = || value.ok_or_else(|| eyre!("--{} needs a value", name));
+                match name {
+                    "archive" => archive(&mut opts),
+                    "recursive" => (),
+                    "links" => opts.links = true,
+                    "times" => opts.times = true,
+                    "perms" => opts.perms = true,
+                    "owner" => opts.owner = true,
+                    "group" => opts.group = true,
+                    "devices" => opts.devices = true,
+                    "specials" => opts.specials = true,
+                    "hard-links" => opts.hard_links = true,
+                    "acls" => opts.acls = true,
+                    "xattrs" => opts.xattrs = true,
+                    "safe-links" => opts.unsafe_links = UnsafeLinks::Skip,
+                    "copy-unsafe-links" => opts.unsafe_links = UnsafeLinks::Copy,
+                    "chmod" => {
+                        let chmod = Chmod::parse(value()?)?;
+                        match &mut opts.chmod {
+                            Some(chmods) => chmods.append(chmod),
+                            None => opts.chmod = Some(chmod),
+                        }
+                    }
+                    "numeric-ids" => opts.numeric_ids = true,
+                    "usermap" => opts.usermap = Some(IdMap::parse_usermap(value()?)?),
+                    "groupmap" => opts.groupmap = Some(IdMap::parse_groupmap(value()?)?),
+                    "block-size" => opts.block_size = Some(parse_block_size(value()?)?),
+                    "checksum-choice" => opts.checksum_choice = parse_checksum_choice(value()?)?,
+                    "files-from" => opts.files_from = Some(PathBuf::from(value()?)),
+                    "from0" => opts.from0 = true,
+                    "filter" => opts.filters.push(Rule::parse(value()?.as_bytes())?),
+                    "exclude" => opts.filters.push(Rule::parse(&prefixed(b"- ", value()?))?),
+                    "include" => opts.filters.push(Rule::parse(&prefixed(b"+ ", value()?))?),
+                    "exclude-from" => opts
+                        .filters
+                        .extend(load_exclude_from(Path::new(value()?), opts.from0).await?),
+                    "include-from" => opts
+                        .filters
+                        .extend(load_include_from(Path::new(value()?), opts.from0).await?),
+                    // rsync deletes before the transfer with servers older than protocol 30,
+                    // which makes no difference to us.
+                    "delete" => {
+                        opts.delete.get_or_insert(DeleteMode::During);
+                    }
+                    "delete-before" => opts.delete = Some(DeleteMode::Before),
+                    "delete-during" => opts.delete = Some(DeleteMode::During),
+                    "delete-delay" => opts.delete = Some(DeleteMode::Delay),
+                    "delete-after" => opts.delete = Some(DeleteMode::After),
+                    "max-delete" => opts.max_delete = Some(value()?.parse()?),
+                    "password-file" => opts.password_file = Some(PathBuf::from(value()?)),
+                    "rsh" => rsh = value()?.to_string(),
+                    "rsync-path" => rsync_path = value()?.to_string(),
+                    _ => bail!("unsupported option: {}", arg),
+                }
+            } else if let Some(short) = arg.strip_prefix('-').filter(|s| !s.is_empty()) {
+                for (i, flag) in short.char_indices() {
+                    match flag {
+                        'a' => archive(&mut opts),
+                        'r' => (),
+                        'l' => opts.links = true,
+                        't' => opts.times = true,
+                        'p' => opts.perms = true,
+                        'o' => opts.owner = true,
+                        'g' => opts.group = true,
+                        'D' => {
+                            opts.devices = true;
+                            opts.specials = true;
+                        }
+                        'H' => opts.hard_links = true,
+                        'A' => opts.acls = true,
+                        'X' => opts.xattrs = true,
+                        // The value is the rest of the argument, or the next one.
+                        'e' | 'f' => {
+                            let value = match &short[i + 1..] {
+                                "" => args
+                                    .next()
+                                    .ok_or_else(|| eyre!("-{} needs a value", flag))?,
+                                rest => rest.to_string(),
+                            };
+                            if flag == 'e' {
+                                rsh = value;
+                            } else {
+                                opts.filters.push(Rule::parse(value.as_bytes())?);
+                            }
+                            break;
+                        }
+                        _ => bail!("unsupported option: -{}", flag),
+                    }
+                }
+            } else {
+                positional.push(arg);
+            }
+        }
+
+        let (src, dest) = match positional.as_slice() {
+            [src] => (Location::parse(src)?, None),
+            [src, dest] => (Location::parse(src)?, Some(Location::parse(dest)?)),
+            _ => bail!("usage: rsync-poc [OPTION]... SRC [DEST]"),
+        };
+        match (&src, &dest) {
+            // Lists the modules.
+            (Location::Daemon(url), None) => {
+                ensure!(
+                    url.path().trim_matches('/').is_empty(),
+                    "no destination given"
+                );
+            }
+            (Location::Daemon(_) | Location::Shell { .. }, Some(Location::Local(dest))) => {
+                opts.dest = dest.clone();
+            }
+            (Location::Local(_), Some(Location::Daemon(_) | Location::Shell { .. })) => {
+                ensure!(
+                    opts.delete.is_none(),
+                    "--delete is only supported when pulling"
+                );
+            }
+            _ => bail!("one side must be local and the other remote"),
+        }
+        ensure!(!rsh.trim().is_empty(), "empty remote shell");
+        Ok(Self {
+            opts,
+            src,
+            dest,
+            rsh,
+            rsync_path,
+        })
+    }
+}
+
+/// `-a`, `-rlptgoD`.
+fn archive(opts: &mut Opts) {
+    opts.links = true;
+    opts.times = true;
+    opts.perms = true;
+    opts.owner = true;
+    opts.group = true;
+    opts.devices = true;
+    opts.specials = true;
+}
+
+fn prefixed(prefix: &[u8], pattern: &str) -> Vec<u8> {
+    [prefix, pattern.as_bytes()].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse(args: &[&str]) -> Result<Args> {
+        Args::parse(args.iter().map(|arg| arg.to_string())).await
+    }
+
+    #[test]
+    fn locations() {
+        let daemon = |url: &str| Location::Daemon(Url::parse(url).unwrap());
+        assert_eq!(
+            Location::parse("rsync://host:8873/m/p").unwrap(),
+            daemon("rsync://host:8873/m/p")
+        );
+        assert_eq!(
+            Location::parse("user@host::m/p").unwrap(),
+            daemon("rsync://user@host/m/p")
+        );
+        assert_eq!(
+            Location::parse("host:dir/file").unwrap(),
+            Location::Shell {
+                host: String::from("host"),
+                path: String::from("dir/file")
+            }
+        );
+        assert_eq!(
+            Location::parse("./a:b").unwrap(),
+            Location::Local(PathBuf::from("./a:b"))
+        );
+        assert_eq!(
+            Location::parse(":x").unwrap(),
+            Location::Local(PathBuf::from(":x"))
+        );
+    }
+
+    #[tokio::test]
+    async fn options() {
+        let args = parse(&[
+            "-avHe",
+            "ssh -p 2222",
+            "--exclude=*.pyc",
+            "host:src/",
+            "dest",
+        ])
+        .await;
+        assert_eq!(args.err().unwrap().to_string(), "unsupported option: -v");
+
+        let args = parse(&[
+            "-aHe",
+            "ssh -p 2222",
+            "--exclude=*.pyc",
+            "-f+ keep.pyc",
+            "--rsync-path=sudo rsync",
+            "--block-size=2048",
+            "--chmod=D755",
+            "--chmod=Fu+r-w",
+            "host:src/",
+            "dest",
+        ])
+        .await
+        .unwrap();
+        let opts = &args.opts;
+        assert!(opts.links && opts.times && opts.perms && opts.owner && opts.group);
+        assert!(opts.devices && opts.specials && opts.hard_links && !opts.acls);
+        assert_eq!(opts.block_size, Some(2048));
+        let chmod = opts.chmod.as_ref().unwrap();
+        assert_eq!(chmod.apply(0o040700), 0o040755);
+        assert_eq!(chmod.apply(0o100600), 0o100400);
+        assert_eq!(opts.dest, PathBuf::from("dest"));
+        let filters: Vec<_> = opts.filters.iter().map(ToString::to_string).collect();
+        assert_eq!(filters, ["- *.pyc", "+ keep.pyc"]);
+        assert_eq!(args.rsh, "ssh -p 2222");
+        assert_eq!(args.rsync_path, "sudo rsync");
+    }
+
+    #[tokio::test]
+    async fn filter_files() {
+        let excludes = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(excludes.path(), b"*.o\0+ keep.o\0").unwrap();
+        let excludes = format!("--exclude-from={}", excludes.path().display());
+        let args = parse(&[
+            "--include=a",
+            "--from0",
+            &excludes,
+            "--exclude=b",
+            "host:",
+            ".",
+        ])
+        .await
+        .unwrap();
+        let filters: Vec<_> = args.opts.filters.iter().map(ToString::to_string).collect();
+        assert_eq!(filters, ["+ a", "- *.o", "+ keep.o", "- b"]);
+
+        assert!(parse(&["--include-from=/nonexistent", "host:", "."])
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn directions() {
+        assert!(parse(&["rsync://host/"]).await.unwrap().dest.is_none());
+        assert!(parse(&["src", "host::m/"]).await.is_ok());
+        assert!(parse(&["rsync://host/m/"]).await.is_err());
+        assert!(parse(&["src", "dest"]).await.is_err());
+        assert!(parse(&["host:a", "rsync://host/m/"]).await.is_err());
+        assert!(parse(&["--block-size", "host:a", "b"]).await.is_err());
+        assert!(parse(&["--block-size=0", "host:a", "b"]).await.is_err());
+        assert!(parse(&["--block-size=131073", "host:a", "b"])
+            .await
+            .is_err());
+        assert!(parse(&["--block-size=131072", "host:a", "b"]).await.is_ok());
+        assert!(parse(&["--delete", "src", "host::m/"]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn delete_modes() {
+        let mode = |args: &'static [&'static str]| async move {
+            let args = [args, &["host:a", "b"]].concat();
+            parse(&args).await.unwrap().opts.delete
+        };
+        assert_eq!(mode(&[]).await, None);
+        assert_eq!(mode(&["--delete"]).await, Some(DeleteMode::During));
+        assert_eq!(
+            mode(&["--delete-after", "--delete"]).await,
+            Some(DeleteMode::After)
+        );
+        assert_eq!(mode(&["--delete-before"]).await, Some(DeleteMode::Before));
+        assert_eq!(mode(&["--delete-delay"]).await, Some(DeleteMode::Delay));
+
+        let args = parse(&["--delete-during", "--max-delete=3", "host:a", "b"])
+            .await
+            .unwrap();
+        assert_eq!(args.opts.delete, Some(DeleteMode::During));
+        assert_eq!(args.opts.max_delete, Some(3));
+    }
+}
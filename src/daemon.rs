@@ -0,0 +1,610 @@
+//! rsyncd-style server: greeting, module selection, and serving a local directory to either pull
+//! or push clients.
+
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use eyre::{bail, ensure, Result};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tracing::{debug, info, info_span, warn, Instrument};
+
+use crate::chksum::{parse_block_size, parse_checksum_choice, StrongHash, CHECKSUM_LIST};
+use crate::chmod::Chmod;
+use crate::envelope::{EnvelopeRead, EnvelopeWrite, RsyncReadExt, RsyncWriteExt};
+use crate::file_list::{scan_file_list, scan_files_from, ListOptions};
+use crate::filter::{FilterList, Side};
+use crate::generator::{touch_up_dirs, Generator};
+use crate::hlink::link_followers;
+use crate::ndx::NDX_DONE;
+use crate::opts::{Opts, UnsafeLinks};
+use crate::protocol::{
+    Protocol, CF_AVOID_XATTR_OPTIM, CF_CHKSUM_SEED_FIX, CF_SAFE_FLIST, CF_VARINT_FLIST_FLAGS,
+    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use crate::recv::Receiver;
+use crate::sender::Sender;
+use crate::uid_list::{map_ids, IdMap, IdOptions};
+use crate::{parse_greeting, Conn, EnvelopedConn};
+
+#[derive(Debug, Clone)]
+pub struct Module {
+    pub name: String,
+    pub path: PathBuf,
+    pub comment: String,
+    /// Reject clients pushing files, like rsyncd's `read only` which is on by default.
+    pub read_only: bool,
+    /// rsyncd's `incoming chmod`, tweaks the modes of pushed files.
+    pub incoming_chmod: Option<Chmod>,
+    /// rsyncd's `outgoing chmod`, tweaks the modes of the files we send.
+    pub outgoing_chmod: Option<Chmod>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DaemonConfig {
+    pub modules: Vec<Module>,
+    /// Sent to every client after the greeting.
+    pub motd: Option<String>,
+}
+
+impl DaemonConfig {
+    fn module(&self, name: &str) -> Option<&Module> {
+        self.modules.iter().find(|module| module.name == name)
+    }
+}
+
+/// Accept clients on `addr` until an accept error occurs. Each connection is served on its own
+/// task.
+#[allow(dead_code)]
+pub async fn start_daemon(addr: impl ToSocketAddrs, config: DaemonConfig) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!(addr = ?listener.local_addr()?, "daemon listening");
+
+    let config = Arc::new(config);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let config = config.clone();
+        tokio::spawn(
+            async move {
+                let (rx, tx) = stream.into_split();
+                if let Err(e) = Conn::new(rx, tx).serve(&config).await {
+                    warn!("connection failed: {:?}", e);
+                }
+            }
+            .instrument(info_span!("client", %peer)),
+        );
+    }
+}
+
+/// Capabilities we accept from the client's `-e` option, see `CLIENT_INFO`.
+fn compat_flags(client_info: &str) -> u32 {
+    client_info.chars().fold(0, |flags, c| match c {
+        'f' => flags | CF_SAFE_FLIST,
+        'x' => flags | CF_AVOID_XATTR_OPTIM,
+        'C' => flags | CF_CHKSUM_SEED_FIX,
+        'v' => flags | CF_VARINT_FLIST_FLAGS,
+        _ => flags,
+    })
+}
+
+/// The part of the client's command line we understand.
+#[derive(Debug, Default, Eq, PartialEq)]
+struct ServerArgs {
+    /// Whether we send the files.
+    sender: bool,
+    /// `-l`, symlinks are in the file list.
+    links: bool,
+    /// `-t`, the client wants mtimes preserved when pushing.
+    times: bool,
+    /// `-p`, the client wants permissions preserved when pushing.
+    perms: bool,
+    /// `-o`, uids are in the file list.
+    owner: bool,
+    /// `-g`, gids are in the file list.
+    group: bool,
+    /// `--numeric-ids`, without the names of the ids.
+    numeric_ids: bool,
+    /// `--usermap` for pushes.
+    usermap: Option<IdMap>,
+    /// `--groupmap` for pushes.
+    groupmap: Option<IdMap>,
+    /// `--devices` or `-D`.
+    devices: bool,
+    /// `--specials` or `-D`.
+    specials: bool,
+    /// `-H`, hard links are marked in the file list.
+    hard_links: bool,
+    /// `-A`, entries are followed by their ACLs. Implies `perms`.
+    acls: bool,
+    /// `-X`, entries are followed by their xattrs.
+    xattrs: bool,
+    /// `--block-size` for pushes.
+    block_size: Option<u32>,
+    /// `--checksum-choice`, no checksum is negotiated then.
+    checksum_choice: Option<StrongHash>,
+    /// `-r`, only matters with `files_from` as we always send whole trees otherwise.
+    recursive: bool,
+    /// `--files-from=-`, the client sends the names to send after the filter rules.
+    files_from: bool,
+    /// `--from0`, `files_from` names end with NUL instead of newlines.
+    from0: bool,
+    /// `--safe-links` or `--copy-unsafe-links`.
+    unsafe_links: UnsafeLinks,
+    /// Capabilities following `e` in the short options, protocol 30+.
+    client_info: String,
+    /// `module/path`, or just `module`.
+    path: String,
+}
+
+impl ServerArgs {
+    fn parse(args: &[String]) -> Result<Self> {
+        let mut parsed = Self::default();
+        let mut server = false;
+        let mut positional = vec![];
+        let mut relative_or_dirs = false;
+        for arg in args {
+            if let Some(long) = arg.strip_prefix("--") {
+                match long {
+                    "server" => server = true,
+                    "sender" => parsed.sender = true,
+                    "files-from=-" => parsed.files_from = true,
+                    "from0" => parsed.from0 = true,
+                    "safe-links" => parsed.unsafe_links = UnsafeLinks::Skip,
+                    "copy-unsafe-links" => parsed.unsafe_links = UnsafeLinks::Copy,
+                    "numeric-ids" => parsed.numeric_ids = true,
+                    "devices" => parsed.devices = true,
+                    "specials" => parsed.specials = true,
+                    _ if long.starts_with("usermap=") => {
+                        parsed.usermap = Some(IdMap::parse_usermap(&long["usermap=".len()..])?)
+                    }
+                    _ if long.starts_with("groupmap=") => {
+                        parsed.groupmap = Some(IdMap::parse_groupmap(&long["groupmap=".len()..])?)
+                    }
+                    _ if long.starts_with("block-size=") => {
+                        parsed.block_size = Some(parse_block_size(&long["block-size=".len()..])?);
+                    }
+                    _ if long.starts_with("checksum-choice=") => {
+                        parsed.checksum_choice =
+                            parse_checksum_choice(&long["checksum-choice=".len()..])?
+                    }
+                    _ => bail!("unsupported option: {}", arg),
+                }
+            } else if let Some(short) = arg.strip_prefix('-').filter(|s| !s.is_empty()) {
+                // Anything after `e` is the client info.
+                let (flags, client_info) = short.split_once('e').unwrap_or((short, ""));
+                for flag in flags.chars() {
+                    match flag {
+                        'l' => parsed.links = true,
+                        't' => parsed.times = true,
+                        'p' => parsed.perms = true,
+                        'o' => parsed.owner = true,
+                        'g' => parsed.group = true,
+                        'D' => {
+                            parsed.devices = true;
+                            parsed.specials = true;
+                        }
+                        'H' => parsed.hard_links = true,
+                        'A' => {
+                            parsed.acls = true;
+                            parsed.perms = true;
+                        }
+                        'X' => parsed.xattrs = true,
+                        'r' => parsed.recursive = true,
+                        // Implied by `--files-from`, checked below.
+                        'R' | 'd' => relative_or_dirs = true,
+                        // Verbosity is up to us.
+                        'v' => (),
+                        _ => bail!("unsupported option: -{}", flag),
+                    }
+                }
+                parsed.client_info = client_info.to_string();
+            } else {
+                positional.push(arg);
+            }
+        }
+
+        ensure!(server, "not a server command line");
+        ensure!(
+            parsed.files_from || !relative_or_dirs,
+            "-R and -d are only supported with --files-from"
+        );
+        // The first positional argument is a placeholder, usually `.`.
+        match positional.as_slice() {
+            [_, path] => parsed.path = path.to_string(),
+            [_] => (),
+            _ => bail!("expect exactly one path, got {:?}", positional),
+        }
+        Ok(parsed)
+    }
+
+    fn list_options(&self) -> ListOptions {
+        ListOptions {
+            ids: IdOptions {
+                owner: self.owner,
+                group: self.group,
+                acls: self.acls,
+                numeric_ids: self.numeric_ids,
+            },
+            links: self.links,
+            devices: self.devices,
+            specials: self.specials,
+            hard_links: self.hard_links,
+            xattrs: self.xattrs,
+        }
+    }
+}
+
+/// Resolve `path` below the module root. Paths escaping the module are rejected.
+fn module_path(module: &Module, path: &str) -> Result<PathBuf> {
+    let relative = path
+        .strip_prefix(&module.name)
+        .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+        .unwrap_or(path);
+    let relative = Path::new(relative.trim_start_matches('/'));
+    ensure!(
+        relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir)),
+        "path outside of module: {}",
+        path
+    );
+    Ok(module.path.join(relative))
+}
+
+/// A `--files-from` name as a path relative to the transfer root. Leading slashes are dropped as
+/// rsync does, names escaping the root are rejected.
+fn files_from_path(name: &[u8]) -> Result<PathBuf> {
+    // TODO unix only
+    let path = Path::new(OsStr::from_bytes(name));
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::RootDir | Component::CurDir => {}
+            _ => bail!("path outside of module: {}", path.display()),
+        }
+    }
+    if relative.as_os_str().is_empty() {
+        relative.push(".");
+    }
+    Ok(relative)
+}
+
+impl<R: AsyncRead + Unpin + Send, W: AsyncWrite + Unpin + Send> Conn<R, W> {
+    /// Serve one client from greeting to goodbye.
+    pub async fn serve(mut self, config: &DaemonConfig) -> Result<()> {
+        self.accept_versions().await?;
+        if let Some(motd) = &config.motd {
+            for line in motd.lines() {
+                self.tx.write_all(format!("{}\n", line).as_bytes()).await?;
+            }
+        }
+
+        let mut name = String::new();
+        self.rx.read_line(&mut name).await?;
+        let name = name.trim_end();
+        if name.is_empty() || name == "#list" {
+            info!("list modules");
+            for module in &config.modules {
+                self.tx
+                    .write_all(format!("{:<15}\t{}\n", module.name, module.comment).as_bytes())
+                    .await?;
+            }
+            self.tx.write_all(b"@RSYNCD: EXIT\n").await?;
+            self.tx.shutdown().await?;
+            return Ok(());
+        }
+
+        let module = match config.module(name) {
+            Some(module) => module,
+            None => {
+                self.tx
+                    .write_all(format!("@ERROR: Unknown module '{}'\n", name).as_bytes())
+                    .await?;
+                bail!("unknown module {}", name);
+            }
+        };
+        info!(module = module.name, "module selected");
+        self.tx.write_all(b"@RSYNCD: OK\n").await?;
+        self.tx.flush().await?;
+
+        let args = ServerArgs::parse(&self.read_args().await?)?;
+        debug!(?args, "server args");
+        if !args.sender && module.read_only {
+            self.tx.write_all(b"@ERROR: module is read only\n").await?;
+            bail!("push to read only module {}", module.name);
+        }
+        let root = module_path(module, &args.path)?;
+        if let Err(e) = args.list_options().check_protocol(self.protocol) {
+            self.tx
+                .write_all(format!("@ERROR: {}\n", e).as_bytes())
+                .await?;
+            return Err(e);
+        }
+
+        let (seed, conn) = self.setup_protocol(&args).await?;
+        if args.sender {
+            conn.serve_sender(seed, &root, &args, module.outgoing_chmod.as_ref())
+                .await
+        } else {
+            conn.serve_receiver(seed, &root, &args, module.incoming_chmod.as_ref())
+                .await
+        }
+    }
+
+    /// Server side of `exchange_versions`. We don't authenticate, so no digests are offered.
+    async fn accept_versions(&mut self) -> Result<()> {
+        self.tx
+            .write_all(format!("@RSYNCD: {}.0\n", PROTOCOL_VERSION).as_bytes())
+            .await?;
+
+        let mut greeting = String::new();
+        self.rx.read_line(&mut greeting).await?;
+        let (remote_protocol, _) = parse_greeting(&greeting)?;
+        if remote_protocol < MIN_PROTOCOL_VERSION {
+            self.tx
+                .write_all(b"@ERROR: protocol version mismatch\n")
+                .await?;
+            bail!("Client version too old: {}", remote_protocol);
+        }
+        self.protocol = remote_protocol.min(PROTOCOL_VERSION);
+
+        info!(
+            remote_protocol,
+            local_protocol = PROTOCOL_VERSION,
+            protocol = self.protocol,
+            "Server Protocol"
+        );
+        Ok(())
+    }
+
+    /// Read the client's command line, terminated by an empty argument.
+    async fn read_args(&mut self) -> Result<Vec<String>> {
+        const MAX_ARGS: usize = 1024;
+
+        let eol = if self.protocol >= 30 { b'\0' } else { b'\n' };
+        let mut args = vec![];
+        loop {
+            let mut arg = vec![];
+            if self.rx.read_until(eol, &mut arg).await? == 0 {
+                bail!("connection closed while reading arguments");
+            }
+            if arg.last() == Some(&eol) {
+                arg.pop();
+            }
+            if arg.is_empty() {
+                break;
+            }
+            ensure!(args.len() < MAX_ARGS, "too many arguments");
+            args.push(String::from_utf8(arg)?);
+        }
+        Ok(args)
+    }
+
+    /// Server side of `handshake_done`: send compat flags, negotiate the checksum and send its
+    /// seed. Our output is always multiplexed, the client's only since protocol 30.
+    async fn setup_protocol(mut self, args: &ServerArgs) -> Result<(i32, EnvelopedConn<R, W>)> {
+        let mut protocol = Protocol::new(self.protocol);
+        if protocol.version >= 30 {
+            protocol.compat_flags = compat_flags(&args.client_info);
+            self.tx.write_varint(protocol.compat_flags as i32).await?;
+            debug!(compat_flags = protocol.compat_flags);
+        }
+        protocol.checksum = match args.checksum_choice {
+            Some(checksum) => checksum,
+            None if protocol.negotiated_strings() => {
+                self.tx.write_vstring(CHECKSUM_LIST.as_bytes()).await?;
+                self.tx.flush().await?;
+                let client_list = self.rx.read_vstring().await?;
+                StrongHash::negotiate(&client_list, CHECKSUM_LIST.as_bytes())?
+            }
+            None => protocol.checksum,
+        };
+        debug!(checksum = protocol.checksum.name());
+
+        // Same as rsync: time ^ (pid << 6).
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i32;
+        let seed = now ^ (std::process::id() << 6) as i32;
+        self.tx.write_i32_le(seed).await?;
+        debug!(seed);
+
+        let conn = EnvelopedConn {
+            tx: EnvelopeWrite::new(self.tx, true),
+            rx: EnvelopeRead::new(self.rx, protocol.version >= 30),
+            protocol,
+        };
+        Ok((seed, conn))
+    }
+}
+
+impl<R: AsyncRead + Unpin + Send, W: AsyncWrite + Unpin + Send> EnvelopedConn<R, W> {
+    /// The client pulls `root`, or the names it lists below it with `--files-from`.
+    async fn serve_sender(
+        mut self,
+        seed: i32,
+        root: &Path,
+        args: &ServerArgs,
+        chmod: Option<&Chmod>,
+    ) -> Result<()> {
+        let protocol = self.protocol;
+        let rules = self.recv_filter_rules().await?;
+        debug!(?rules, "filter rules");
+        let files_from = if args.files_from {
+            let names = self.recv_files_from(args.from0).await?;
+            let names = names
+                .iter()
+                .map(|name| files_from_path(name))
+                .collect::<Result<Vec<_>>>()?;
+            Some(names)
+        } else {
+            None
+        };
+
+        ensure!(
+            tokio::fs::metadata(root).await?.is_dir(),
+            "only directories can be served: {}",
+            root.display()
+        );
+        let mut filter = FilterList::new(&rules, Side::Sender, root);
+        let mut file_list = match &files_from {
+            Some(names) => {
+                scan_files_from(
+                    root,
+                    names,
+                    args.recursive,
+                    protocol.version,
+                    &mut filter,
+                    args.unsafe_links,
+                    args.list_options(),
+                )
+                .await?
+            }
+            None => {
+                scan_file_list(
+                    root,
+                    protocol.version,
+                    &mut filter,
+                    args.unsafe_links,
+                    args.list_options(),
+                )
+                .await?
+            }
+        };
+        if let Some(chmod) = chmod {
+            chmod.apply_to_list(&mut file_list);
+        }
+        info!(files = file_list.len(), "file list");
+        self.send_file_list(&file_list, 0, args.list_options())
+            .await?;
+
+        let mut sender = Sender::new(self.rx, self.tx, protocol);
+        sender.send_task(seed, root, &file_list).await?;
+
+        let Sender {
+            mut rx,
+            mut tx,
+            mut rx_ndx,
+            mut tx_ndx,
+            ..
+        } = sender;
+
+        // TODO count bytes read and written
+        let version = protocol.version;
+        let size = file_list
+            .iter()
+            .filter(|entry| unix_mode::is_file(entry.mode))
+            .map(|entry| entry.len as i64)
+            .sum();
+        for stat in [0, 0, size] {
+            tx.write_varlong30(version, stat, 3).await?;
+        }
+        if version >= 29 {
+            // flist_buildtime, flist_xfertime
+            tx.write_varlong30(version, 0, 3).await?;
+            tx.write_varlong30(version, 0, 3).await?;
+        }
+        tx.flush().await?;
+
+        // Goodbye, see `run_transfer`.
+        let done = rx_ndx.read_ndx(&mut rx, version).await?;
+        ensure!(done == NDX_DONE, "invalid packet at end of run");
+        if version >= 31 {
+            tx_ndx.write_ndx(&mut tx, version, NDX_DONE).await?;
+            tx.flush().await?;
+            let done = rx_ndx.read_ndx(&mut rx, version).await?;
+            ensure!(done == NDX_DONE, "invalid packet at end of run");
+        }
+        tx.shutdown().await?;
+
+        Ok(())
+    }
+
+    /// The client pushes files into `root`.
+    async fn serve_receiver(
+        mut self,
+        seed: i32,
+        root: &Path,
+        args: &ServerArgs,
+        chmod: Option<&Chmod>,
+    ) -> Result<()> {
+        let protocol = self.protocol;
+        let (mut file_list, id_names, io_errors) = self.recv_file_list(args.list_options()).await?;
+        map_ids(
+            &mut file_list,
+            &id_names,
+            args.list_options().ids,
+            args.usermap.as_ref(),
+            args.groupmap.as_ref(),
+        );
+        info!(files = file_list.len(), "file list");
+        if io_errors != 0 {
+            warn!("client reported IO errors: {}", io_errors);
+        }
+
+        let opts = Opts {
+            dest: root.to_path_buf(),
+            filters: vec![],
+            files_from: None,
+            from0: false,
+            delete: None,
+            max_delete: None,
+            links: args.links,
+            unsafe_links: args.unsafe_links,
+            perms: args.perms,
+            times: args.times,
+            chmod: chmod.cloned(),
+            owner: args.owner,
+            group: args.group,
+            numeric_ids: args.numeric_ids,
+            usermap: args.usermap.clone(),
+            groupmap: args.groupmap.clone(),
+            devices: args.devices,
+            specials: args.specials,
+            hard_links: args.hard_links,
+            acls: args.acls,
+            xattrs: args.xattrs,
+            block_size: args.block_size,
+            checksum_choice: args.checksum_choice,
+            user: None,
+            password: None,
+            password_file: None,
+            messages: None,
+        };
+        let mut generator = Generator::new(self.tx, protocol);
+        let mut receiver = Receiver::new(self.rx, protocol);
+        tokio::try_join!(
+            generator.generate_task(seed, &opts, &file_list, None),
+            receiver.recv_task(seed, &opts, &file_list),
+        )?;
+        link_followers(&opts, &file_list).await?;
+        touch_up_dirs(&opts, &file_list).await?;
+
+        let Generator {
+            mut tx, mut ndx, ..
+        } = generator;
+        let Receiver {
+            mut rx,
+            ndx: mut rx_ndx,
+            ..
+        } = receiver;
+
+        // Goodbye, see `run_upload`.
+        let version = protocol.version;
+        ndx.write_ndx(&mut tx, version, NDX_DONE).await?;
+        if version >= 31 {
+            tx.flush().await?;
+            let done = rx_ndx.read_ndx(&mut rx, version).await?;
+            ensure!(done == NDX_DONE, "invalid packet at end of run");
+            ndx.write_ndx(&mut tx, version, NDX_DONE).await?;
+        }
+        tx.shutdown().await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,336 @@
+//! Removal of destination files the sender doesn't have, as with rsync's `--delete`.
+
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::pin::Pin;
+
+use eyre::{Context, Result};
+use tokio::fs;
+use tracing::{debug, info, warn};
+
+use crate::file_list::FileEntry;
+use crate::filter::{FilterList, Side};
+use crate::opts::Opts;
+
+/// When extraneous files are deleted, relative to the transfer.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DeleteMode {
+    /// `--delete-before`: all of them before any file is requested.
+    Before,
+    /// `--delete-during`: in each directory as the generator reaches it.
+    During,
+    /// `--delete-delay`: found during the transfer, deleted after it.
+    Delay,
+    /// `--delete-after`: all of them after the transfer.
+    After,
+}
+
+/// Deletes local entries missing from the received file list. Entries excluded or protected by
+/// receiver side filter rules are kept.
+pub struct Deleter {
+    mode: DeleteMode,
+    dest: PathBuf,
+    /// Names in the file list.
+    names: HashSet<Vec<u8>>,
+    filter: FilterList,
+    max_delete: Option<usize>,
+    deleted: usize,
+    /// Deletions refused because of `max_delete`.
+    skipped: usize,
+    /// `DeleteMode::Delay` names found so far.
+    delayed: Vec<Vec<u8>>,
+}
+
+impl Deleter {
+    pub fn new(mode: DeleteMode, opts: &Opts, file_list: &[FileEntry]) -> Self {
+        Self {
+            mode,
+            dest: opts.dest.clone(),
+            names: file_list.iter().map(|entry| entry.name.clone()).collect(),
+            filter: FilterList::new(&opts.filters, Side::Receiver, &opts.dest),
+            max_delete: opts.max_delete,
+            deleted: 0,
+            skipped: 0,
+            delayed: vec![],
+        }
+    }
+
+    pub fn mode(&self) -> DeleteMode {
+        self.mode
+    }
+
+    /// Delete the extraneous entries of every directory in the file list, in list order.
+    pub async fn delete_all(&mut self, file_list: &[FileEntry]) -> Result<()> {
+        for entry in file_list {
+            if unix_mode::is_dir(entry.mode) {
+                self.delete_in_dir(&entry.name).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete the entries of directory `dir` missing from the file list, or remember them for
+    /// `finish` with `DeleteMode::Delay`. Directories above `dir` must have been visited first,
+    /// for their `dir-merge` files.
+    pub async fn delete_in_dir(&mut self, dir: &[u8]) -> Result<()> {
+        self.filter.enter_dir(dir, &self.dest).await?;
+
+        // TODO unix only
+        let path = self.dest.join(OsStr::from_bytes(dir));
+        // Like rsync's `link_stat`, a symlink in its place isn't followed.
+        match fs::symlink_metadata(&path).await {
+            Ok(meta) if meta.is_dir() => (),
+            // Not created yet, or replaced by something else.
+            Ok(_) => return Ok(()),
+            Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::NotADirectory) => {
+                return Ok(())
+            }
+            Err(e) => return Err(e).wrap_err_with(|| format!("can't stat {}", path.display())),
+        }
+        let mut children = fs::read_dir(&path)
+            .await
+            .wrap_err_with(|| format!("can't list {}", path.display()))?;
+        let mut extraneous = vec![];
+        while let Some(child) = children.next_entry().await? {
+            let mut name = if dir == b"." {
+                vec![]
+            } else {
+                [dir, b"/"].concat()
+            };
+            name.extend_from_slice(child.file_name().as_bytes());
+            if !self.names.contains(&name) {
+                extraneous.push(name);
+            }
+        }
+        // Same order on every run.
+        extraneous.sort();
+
+        for name in extraneous {
+            if self.mode == DeleteMode::Delay {
+                self.delayed.push(name);
+            } else {
+                self.delete(&name).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete everything extraneous with `DeleteMode::After`, what `DeleteMode::Delay` found
+    /// otherwise, and report entries kept by `max_delete`. Like rsync, nothing more is deleted
+    /// if the sender reported `io_errors` during the transfer.
+    pub async fn finish(&mut self, file_list: &[FileEntry], io_errors: i32) -> Result<()> {
+        if io_errors != 0 {
+            warn!("IO error encountered -- skipping file deletion");
+            self.delayed.clear();
+        } else if self.mode == DeleteMode::After {
+            self.delete_all(file_list).await?;
+        }
+        for name in std::mem::take(&mut self.delayed) {
+            self.delete(&name).await?;
+        }
+
+        if self.skipped > 0 {
+            warn!(
+                "Deletions stopped due to --max-delete limit ({} skipped)",
+                self.skipped
+            );
+        }
+        info!(deleted = self.deleted, "delete finish");
+        Ok(())
+    }
+
+    /// Delete `name`, along with the contents of directories. Returns whether it's gone, it isn't
+    /// if it or something inside it is excluded, or `max_delete` was reached.
+    fn delete<'a>(
+        &'a mut self,
+        name: &'a [u8],
+    ) -> Pin<Box<dyn Future<Output = Result<bool>> + Send + 'a>> {
+        Box::pin(async move {
+            // TODO unix only
+            let path = self.dest.join(OsStr::from_bytes(name));
+            let meta = match fs::symlink_metadata(&path).await {
+                Ok(meta) => meta,
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(true),
+                Err(e) => return Err(e).wrap_err_with(|| format!("can't stat {}", path.display())),
+            };
+            let is_dir = meta.is_dir();
+            if self.filter.is_excluded(name, is_dir) {
+                debug!(?path, "protected from deletion");
+                return Ok(false);
+            }
+
+            let mut empty = true;
+            if is_dir {
+                let mut children = vec![];
+                let mut dir = fs::read_dir(&path).await?;
+                while let Some(child) = dir.next_entry().await? {
+                    children.push([name, b"/", child.file_name().as_bytes()].concat());
+                }
+                children.sort();
+                for child in children {
+                    empty &= self.delete(&child).await?;
+                }
+            }
+
+            if !empty {
+                return Ok(false);
+            }
+            if self.max_delete.is_some_and(|max| self.deleted >= max) {
+                self.skipped += 1;
+                return Ok(false);
+            }
+
+            info!(
+                "deleting {}{}",
+                path.display(),
+                if is_dir { "/" } else { "" }
+            );
+            if is_dir {
+                fs::remove_dir(&path).await
+            } else {
+                fs::remove_file(&path).await
+            }
+            .wrap_err_with(|| format!("can't delete {}", path.display()))?;
+            self.deleted += 1;
+            Ok(true)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::filter::Rule;
+
+    const DIR: u32 = 0o040755;
+    const FILE: u32 = 0o100644;
+
+    /// A destination with `keep` and `dir/keep` on the list, and extraneous `old`, `dir/old.o`
+    /// and `gone/file`.
+    fn tree() -> (tempfile::TempDir, Vec<FileEntry>) {
+        let dest = tempfile::tempdir().unwrap();
+        for name in ["keep", "old", "dir/keep", "dir/old.o", "gone/file"] {
+            let path = dest.path().join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, name).unwrap();
+        }
+        let file_list = vec![
+            FileEntry::bare(".", DIR),
+            FileEntry::bare("dir", DIR),
+            FileEntry::bare("dir/keep", FILE),
+            FileEntry::bare("keep", FILE),
+        ];
+        (dest, file_list)
+    }
+
+    fn new_deleter(
+        mode: DeleteMode,
+        dest: &Path,
+        file_list: &[FileEntry],
+        rules: &[&str],
+    ) -> Deleter {
+        let opts = Opts {
+            dest: dest.to_path_buf(),
+            filters: rules
+                .iter()
+                .map(|rule| Rule::parse(rule.as_bytes()).unwrap())
+                .collect(),
+            ..Opts::default()
+        };
+        Deleter::new(mode, &opts, file_list)
+    }
+
+    fn left(dest: &Path) -> Vec<String> {
+        let mut names = vec![];
+        for dir in [".", "dir", "gone"] {
+            let Ok(children) = std::fs::read_dir(dest.join(dir)) else {
+                continue;
+            };
+            for child in children {
+                let name = child.unwrap().file_name().into_string().unwrap();
+                names.push(
+                    format!("{}/{}", dir, name)
+                        .trim_start_matches("./")
+                        .to_string(),
+                );
+            }
+        }
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn timings() {
+        let (dest, file_list) = tree();
+        let mut deleter = new_deleter(DeleteMode::Before, dest.path(), &file_list, &[]);
+        deleter.delete_all(&file_list).await.unwrap();
+        assert_eq!(left(dest.path()), ["dir", "dir/keep", "keep"]);
+        deleter.finish(&file_list, 0).await.unwrap();
+
+        // As the generator goes.
+        for mode in [DeleteMode::During, DeleteMode::Delay] {
+            let (dest, file_list) = tree();
+            let mut deleter = new_deleter(mode, dest.path(), &file_list, &[]);
+            deleter.delete_in_dir(b".").await.unwrap();
+            let delayed = left(dest.path()).contains(&String::from("old"));
+            assert_eq!(delayed, mode == DeleteMode::Delay);
+            deleter.delete_in_dir(b"dir").await.unwrap();
+            deleter.finish(&file_list, 0).await.unwrap();
+            assert_eq!(left(dest.path()), ["dir", "dir/keep", "keep"]);
+        }
+
+        let (dest, file_list) = tree();
+        let mut deleter = new_deleter(DeleteMode::After, dest.path(), &file_list, &[]);
+        deleter.finish(&file_list, 0).await.unwrap();
+        assert_eq!(left(dest.path()), ["dir", "dir/keep", "keep"]);
+    }
+
+    #[tokio::test]
+    async fn excluded_and_protected() {
+        let (dest, file_list) = tree();
+        // Hidden files are only excluded on the sending side.
+        let rules = ["- *.o", "P file", "H old"];
+        let mut deleter = new_deleter(DeleteMode::Before, dest.path(), &file_list, &rules);
+        deleter.delete_all(&file_list).await.unwrap();
+        deleter.finish(&file_list, 0).await.unwrap();
+        // `gone` is kept for what's inside.
+        assert_eq!(
+            left(dest.path()),
+            ["dir", "dir/keep", "dir/old.o", "gone", "gone/file", "keep"]
+        );
+    }
+
+    #[tokio::test]
+    async fn max_delete() {
+        let (dest, file_list) = tree();
+        let mut deleter = new_deleter(DeleteMode::Before, dest.path(), &file_list, &[]);
+        deleter.max_delete = Some(2);
+        deleter.delete_all(&file_list).await.unwrap();
+        deleter.finish(&file_list, 0).await.unwrap();
+        // `gone/file` and `gone` go first.
+        assert_eq!(deleter.skipped, 2);
+        assert_eq!(
+            left(dest.path()),
+            ["dir", "dir/keep", "dir/old.o", "keep", "old"]
+        );
+    }
+
+    #[tokio::test]
+    async fn io_errors() {
+        for mode in [DeleteMode::Delay, DeleteMode::After] {
+            let (dest, file_list) = tree();
+            let mut deleter = new_deleter(mode, dest.path(), &file_list, &[]);
+            if mode == DeleteMode::Delay {
+                deleter.delete_all(&file_list).await.unwrap();
+            }
+            deleter.finish(&file_list, 1).await.unwrap();
+            assert_eq!(left(dest.path()).len(), 7, "{:?}", mode);
+        }
+    }
+}
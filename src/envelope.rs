@@ -1,77 +1,235 @@
 //! Adopted from arrsync.
 
 use std::pin::Pin;
-use std::task::Poll;
+use std::task::{ready, Poll};
 
-use eyre::Result;
-use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, ReadBuf};
-use tracing::{trace, warn};
+use eyre::{bail, ensure, eyre, Result};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{debug, error, info, trace, warn};
 
-/// Strips rsync data frame headers and prints non-data frames as warning messages
+/// Frame tags are message codes offset by this.
+const MPLEX_BASE: u8 = 7;
+/// Tag of a data frame.
+const MPLEX_DATA: u8 = MPLEX_BASE + MsgCode::Data as u8;
+/// rsync never sends frames larger than its io buffer.
+const MAX_FRAME_LEN: usize = 32 * 1024;
+/// The length field of a frame header is 3 bytes.
+const MAX_MSG_LEN: usize = 0xff_ffff;
+/// Outgoing data is collected up to this size before it's written out.
+const WRITE_BUF_LEN: usize = 256 * 1024;
+
+/// Out-of-band message codes, see `enum msgcode` in rsync's rsync.h.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum MsgCode {
+    /// Raw data on the multiplexed stream.
+    Data = 0,
+    /// Error from the transfer.
+    ErrorXfer = 1,
+    Info = 2,
+    /// Error from the sender, receiver or generator.
+    Error = 3,
+    Warning = 4,
+    /// Error from the socket, sent in `@ERROR` form before multiplexing starts.
+    ErrorSocket = 5,
+    /// Sent to the daemon log only.
+    Log = 6,
+    /// Message for the client only.
+    Client = 7,
+    /// Filename conversion error.
+    ErrorUtf8 = 8,
+    /// Reprocess the file index.
+    Redo = 9,
+    /// Stats for the generator, protocol 30+.
+    Stats = 10,
+    /// The sender had an io error.
+    IoError = 22,
+    /// The peer's timeout, protocol 31+.
+    IoTimeout = 33,
+    /// Keep alive, protocol 30+.
+    Noop = 42,
+    /// Synchronized exit, protocol 31+.
+    ErrorExit = 86,
+    /// A file was transferred, for `--remove-source-files`.
+    Success = 100,
+    /// A file was deleted.
+    Deleted = 101,
+    /// The sender couldn't open a requested file, protocol 30+.
+    NoSend = 102,
+}
+
+impl TryFrom<u8> for MsgCode {
+    type Error = u8;
+
+    fn try_from(code: u8) -> Result<Self, u8> {
+        Ok(match code {
+            0 => MsgCode::Data,
+            1 => MsgCode::ErrorXfer,
+            2 => MsgCode::Info,
+            3 => MsgCode::Error,
+            4 => MsgCode::Warning,
+            5 => MsgCode::ErrorSocket,
+            6 => MsgCode::Log,
+            7 => MsgCode::Client,
+            8 => MsgCode::ErrorUtf8,
+            9 => MsgCode::Redo,
+            10 => MsgCode::Stats,
+            22 => MsgCode::IoError,
+            33 => MsgCode::IoTimeout,
+            42 => MsgCode::Noop,
+            86 => MsgCode::ErrorExit,
+            100 => MsgCode::Success,
+            101 => MsgCode::Deleted,
+            102 => MsgCode::NoSend,
+            _ => return Err(code),
+        })
+    }
+}
+
+/// A decoded out-of-band message, see `MsgCode`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Message {
+    ErrorXfer(String),
+    Info(String),
+    Error(String),
+    Warning(String),
+    ErrorSocket(String),
+    Log(String),
+    Client(String),
+    ErrorUtf8(String),
+    /// File index.
+    Redo(i32),
+    /// Raw payload, rsync only exchanges these between its local processes.
+    Stats(Vec<u8>),
+    /// IO error flags of the sender.
+    IoError(i32),
+    /// Timeout of the peer in seconds.
+    IoTimeout(i32),
+    Noop,
+    /// Exit code of the peer, if sent.
+    ErrorExit(Option<i32>),
+    /// File index.
+    Success(i32),
+    /// Name of the deleted file.
+    Deleted(Vec<u8>),
+    /// File index.
+    NoSend(i32),
+}
+
+impl Message {
+    fn decode(code: MsgCode, payload: Vec<u8>) -> Result<Self> {
+        let text = || {
+            let msg = String::from_utf8_lossy(&payload);
+            msg.strip_suffix('\n').unwrap_or(&msg).to_string()
+        };
+        let int = || -> Result<i32> {
+            let b: [u8; 4] = payload
+                .as_slice()
+                .try_into()
+                .map_err(|_| eyre!("{:?} message of invalid length {}", code, payload.len()))?;
+            Ok(i32::from_le_bytes(b))
+        };
+
+        Ok(match code {
+            MsgCode::Data => bail!("data frame is not a message"),
+            MsgCode::ErrorXfer => Message::ErrorXfer(text()),
+            MsgCode::Info => Message::Info(text()),
+            MsgCode::Error => Message::Error(text()),
+            MsgCode::Warning => Message::Warning(text()),
+            MsgCode::ErrorSocket => Message::ErrorSocket(text()),
+            MsgCode::Log => Message::Log(text()),
+            MsgCode::Client => Message::Client(text()),
+            MsgCode::ErrorUtf8 => Message::ErrorUtf8(text()),
+            MsgCode::Redo => Message::Redo(int()?),
+            MsgCode::Stats => Message::Stats(payload),
+            MsgCode::IoError => Message::IoError(int()?),
+            MsgCode::IoTimeout => Message::IoTimeout(int()?),
+            MsgCode::Noop => Message::Noop,
+            MsgCode::ErrorExit if payload.is_empty() => Message::ErrorExit(None),
+            MsgCode::ErrorExit => Message::ErrorExit(Some(int()?)),
+            MsgCode::Success => Message::Success(int()?),
+            MsgCode::Deleted => Message::Deleted(payload),
+            MsgCode::NoSend => Message::NoSend(int()?),
+        })
+    }
+
+    /// What we do with messages nobody asked for.
+    fn log(&self) {
+        match self {
+            Message::Info(msg) | Message::Client(msg) | Message::Log(msg) => {
+                info!("Remote: {}", msg)
+            }
+            Message::Warning(msg) => warn!("Remote: {}", msg),
+            Message::Error(msg)
+            | Message::ErrorXfer(msg)
+            | Message::ErrorSocket(msg)
+            | Message::ErrorUtf8(msg) => error!("Remote error: {}", msg),
+            Message::Deleted(name) => info!("Remote deleted {}", String::from_utf8_lossy(name)),
+            message => debug!(?message, "remote message"),
+        }
+    }
+}
+
+fn io_abort(e: impl Into<eyre::Report>) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::ConnectionAborted, e.into())
+}
+
+#[derive(Debug)]
+enum ReadState {
+    /// Header bytes read so far.
+    Header([u8; 4], usize),
+    /// Bytes left in the current data frame.
+    Data(usize),
+    /// Message body read so far, and its length.
+    Message(MsgCode, Vec<u8>, usize),
+}
+
+/// Strips rsync frame headers from the inbound stream. Out-of-band messages are decoded and handed
+/// to the sink if one is set, otherwise they are logged.
+///
+/// Passes data through as is when the peer doesn't multiplex.
 #[derive(Debug)]
 pub struct EnvelopeRead<T: AsyncBufRead + Unpin> {
-    // TODO: rebuild with an enum of pending body, pending error, and pending header
     read: T,
-    frame_remaining: usize,
-    pending_error: Option<(u8, Vec<u8>)>,
-    pending_header: Option<([u8; 4], u8)>,
+    multiplexed: bool,
+    state: ReadState,
+    sink: Option<UnboundedSender<Message>>,
+    /// Flags of all `MSG_IO_ERROR`s received.
+    io_error: i32,
 }
 
 impl<T: AsyncBufRead + Unpin> EnvelopeRead<T> {
-    pub fn new(t: T) -> EnvelopeRead<T> {
+    pub fn new(t: T, multiplexed: bool) -> EnvelopeRead<T> {
         EnvelopeRead {
             read: t,
-            frame_remaining: 0,
-            pending_error: None,
-            pending_header: None,
+            multiplexed,
+            state: ReadState::Header([0; 4], 0),
+            sink: None,
+            io_error: 0,
         }
     }
 
-    fn poll_err(
-        mut self: Pin<&mut Self>,
-        ctx: &mut std::task::Context<'_>,
-        repoll_buf: &mut ReadBuf,
-    ) -> Poll<Result<(), std::io::Error>> {
-        while self.frame_remaining > 0 {
-            let mut pe = self
-                .pending_error
-                .as_ref()
-                .expect("Error expected, but not present")
-                .1
-                .clone();
-            let pei = pe.len() - self.frame_remaining; // Ich check dich wek alter bowwochecka
-            let rb = &mut ReadBuf::new(&mut pe[pei..]);
-            match Pin::new(&mut self.read).poll_read(ctx, rb) {
-                Poll::Pending => return Poll::Pending,
-                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
-                Poll::Ready(Ok(())) => {
-                    if rb.filled().len() == 0 {
-                        break;
-                    }
-                    self.frame_remaining -= rb.filled().len();
-                    self.pending_error.as_mut().unwrap().1 = pe;
+    /// The sender's io errors reported out of band so far, e.g. for files that vanished while
+    /// it was sending them.
+    pub fn io_error(&self) -> i32 {
+        self.io_error
+    }
+
+    /// Deliver out-of-band messages to `sink` from now on. They are logged again if it's closed.
+    pub fn set_sink(&mut self, sink: UnboundedSender<Message>) {
+        self.sink = Some(sink);
+    }
+
+    fn deliver(&mut self, message: Message) {
+        match &self.sink {
+            Some(sink) => {
+                if let Err(e) = sink.send(message) {
+                    e.0.log();
                 }
-            };
-        }
-        let (typ, msg) = self.pending_error.take().unwrap();
-        let msg = String::from_utf8_lossy(&msg);
-        let msg = msg
-            .strip_suffix("\n")
-            .filter(|msg| msg.matches("\n").count() == 0)
-            .unwrap_or(&msg)
-            .to_owned();
-        let e = match typ {
-            8 => {
-                warn!("Sender: {}", msg);
-                return self.poll_read(ctx, repoll_buf);
             }
-            1 => eyre::eyre!("Server error: {}", msg),
-            t => eyre::eyre!("Unknown error {}: {}", t, msg),
-        };
-        return Poll::Ready(Err(std::io::Error::new(
-            std::io::ErrorKind::ConnectionAborted,
-            e,
-        )));
+            None => message.log(),
+        }
     }
 }
 
@@ -81,71 +239,240 @@ impl<T: AsyncBufRead + Unpin> AsyncRead for EnvelopeRead<T> {
         ctx: &mut std::task::Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<Result<(), std::io::Error>> {
-        if self.pending_error.is_some() {
-            return self.poll_err(ctx, buf);
-        }
-        while self.frame_remaining == 0 {
-            let no_pending_header = self.pending_header.is_none();
-            let pll = Pin::new(&mut self.read).poll_fill_buf(ctx);
-            match pll {
-                // Starting to wonder whether it wouldn't be easier to store the future returend by read_u32_le
-                Poll::Pending => return Poll::Pending,
-                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
-                Poll::Ready(Ok([])) if no_pending_header => return Poll::Ready(Ok(())),
-                Poll::Ready(Ok([])) => {
-                    return Poll::Ready(Err(std::io::Error::new(
-                        std::io::ErrorKind::ConnectionAborted,
-                        eyre::eyre!("Abort during header read"),
-                    )));
+        let this = &mut *self;
+        if !this.multiplexed {
+            return Pin::new(&mut this.read).poll_read(ctx, buf);
+        }
+
+        loop {
+            match &mut this.state {
+                ReadState::Data(0) => this.state = ReadState::Header([0; 4], 0),
+                ReadState::Data(remaining) => {
+                    let request = buf.remaining().min(*remaining);
+                    let mut rb = ReadBuf::new(buf.initialize_unfilled_to(request));
+                    ready!(Pin::new(&mut this.read).poll_read(ctx, &mut rb))?;
+                    let read = rb.filled().len();
+                    if read == 0 && request > 0 {
+                        return Poll::Ready(Err(io_abort(eyre!("Abort during data frame"))));
+                    }
+                    *remaining -= read;
+                    buf.advance(read);
+                    return Poll::Ready(Ok(()));
                 }
-                Poll::Ready(Ok(slice)) => {
-                    let slicehead = &mut [0u8; 4];
-                    let consumable = std::cmp::min(slice.len(), 4);
-                    slicehead[..consumable].copy_from_slice(&slice[..consumable]);
-                    let (mut ph, phl) = self.pending_header.take().unwrap_or(([0u8; 4], 0));
-                    let phl = phl as usize;
-                    let consumable = std::cmp::min(ph.len() - phl, consumable);
-                    ph[phl..(phl + consumable)].copy_from_slice(&slicehead[..consumable]);
-                    let phl = phl + consumable;
-                    Pin::new(&mut self.read).consume(consumable);
-                    if phl < ph.len() {
-                        self.pending_header = Some((ph, phl as u8));
-                    } else {
-                        let [b1, b2, b3, b4] = ph;
-                        let b1 = b1 as usize;
-                        let b2 = b2 as usize;
-                        let b3 = b3 as usize;
-                        self.frame_remaining = b1 + b2 * 0x100 + b3 * 0x100_00 as usize;
-                        trace!("Frame {} {}", b4, self.frame_remaining);
-                        match b4 {
-                            7 => (),
-                            t => {
-                                let mut errbuf = vec![];
-                                errbuf.resize(self.frame_remaining, 0);
-                                self.pending_error = Some((t, errbuf));
-                                return self.poll_err(ctx, buf);
+                ReadState::Header(header, filled) => {
+                    let available = ready!(Pin::new(&mut this.read).poll_fill_buf(ctx))?;
+                    if available.is_empty() {
+                        if *filled == 0 {
+                            return Poll::Ready(Ok(()));
+                        }
+                        return Poll::Ready(Err(io_abort(eyre!("Abort during header read"))));
+                    }
+                    let n = available.len().min(header.len() - *filled);
+                    header[*filled..*filled + n].copy_from_slice(&available[..n]);
+                    *filled += n;
+                    Pin::new(&mut this.read).consume(n);
+
+                    if *filled == header.len() {
+                        let [b0, b1, b2, tag] = *header;
+                        let len = u32::from_le_bytes([b0, b1, b2, 0]) as usize;
+                        trace!("Frame {} {}", tag, len);
+                        this.state = match tag.checked_sub(MPLEX_BASE).map(MsgCode::try_from) {
+                            Some(Ok(MsgCode::Data)) => ReadState::Data(len),
+                            Some(Ok(code)) => {
+                                ReadState::Message(code, Vec::with_capacity(len), len)
+                            }
+                            _ => {
+                                return Poll::Ready(Err(io_abort(eyre!(
+                                    "Unknown message tag {}",
+                                    tag
+                                ))))
                             }
                         };
                     }
                 }
+                ReadState::Message(code, body, len) if body.len() < *len => {
+                    let available = ready!(Pin::new(&mut this.read).poll_fill_buf(ctx))?;
+                    if available.is_empty() {
+                        return Poll::Ready(Err(io_abort(eyre!(
+                            "Abort during {:?} message",
+                            code
+                        ))));
+                    }
+                    let n = available.len().min(*len - body.len());
+                    body.extend_from_slice(&available[..n]);
+                    Pin::new(&mut this.read).consume(n);
+                }
+                ReadState::Message(code, body, _) => {
+                    let message = Message::decode(*code, std::mem::take(body)).map_err(io_abort)?;
+                    this.state = ReadState::Header([0; 4], 0);
+
+                    let exit = match message {
+                        // The peer is going away, nothing useful follows.
+                        Message::ErrorExit(code) => Some(code),
+                        Message::IoError(flags) => {
+                            this.io_error |= flags;
+                            None
+                        }
+                        _ => None,
+                    };
+                    this.deliver(message);
+                    if let Some(code) = exit {
+                        return Poll::Ready(Err(io_abort(eyre!(
+                            "Remote exited with code {}",
+                            code.unwrap_or_default()
+                        ))));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Wraps outgoing data into rsync data frames once multiplexing is on, otherwise just buffers it.
+/// Out-of-band messages can be interleaved with the data, see `write_msg`.
+///
+/// Callers must flush before waiting for the peer.
+#[derive(Debug)]
+pub struct EnvelopeWrite<T: AsyncWrite + Unpin> {
+    write: T,
+    multiplexed: bool,
+    buf: Vec<u8>,
+    written: usize,
+    /// Offset of the header of the last data frame in `buf`, if more data can be appended to it.
+    open_frame: Option<usize>,
+}
+
+impl<T: AsyncWrite + Unpin> EnvelopeWrite<T> {
+    pub fn new(t: T, multiplexed: bool) -> EnvelopeWrite<T> {
+        EnvelopeWrite {
+            write: t,
+            multiplexed,
+            buf: Vec::with_capacity(WRITE_BUF_LEN),
+            written: 0,
+            open_frame: None,
+        }
+    }
+
+    fn append(&mut self, data: &[u8]) -> usize {
+        if data.is_empty() {
+            return 0;
+        } else if !self.multiplexed {
+            let n = data.len().min(WRITE_BUF_LEN.saturating_sub(self.buf.len()));
+            self.buf.extend_from_slice(&data[..n]);
+            return n;
+        }
+
+        let header = match self.open_frame {
+            Some(header) => header,
+            None => {
+                self.buf.extend_from_slice(&[0, 0, 0, MPLEX_DATA]);
+                self.open_frame = Some(self.buf.len() - 4);
+                self.buf.len() - 4
             }
+        };
+        let frame_len = self.buf.len() - header - 4;
+        let n = data
+            .len()
+            .min(MAX_FRAME_LEN - frame_len)
+            .min(WRITE_BUF_LEN.saturating_sub(self.buf.len()));
+        self.buf.extend_from_slice(&data[..n]);
+
+        let frame_len = (frame_len + n) as u32;
+        self.buf[header..header + 3].copy_from_slice(&frame_len.to_le_bytes()[..3]);
+        if frame_len as usize == MAX_FRAME_LEN {
+            self.open_frame = None;
         }
-        let request = std::cmp::min(buf.capacity(), self.frame_remaining as usize);
-        let mut rb = buf.take(request);
-        match Pin::new(&mut self.read).poll_read(ctx, &mut rb) {
-            p @ Poll::Pending => p,
-            e @ Poll::Ready(Err(_)) => e,
-            r @ Poll::Ready(Ok(())) => {
-                let read = rb.filled().len();
-                drop(rb);
-                self.frame_remaining -= read;
-                buf.advance(read);
-                r
+        n
+    }
+
+    fn poll_drain(&mut self, ctx: &mut std::task::Context<'_>) -> Poll<std::io::Result<()>> {
+        while self.written < self.buf.len() {
+            match Pin::new(&mut self.write).poll_write(ctx, &self.buf[self.written..]) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Ready(Ok(0)) => {
+                    return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+                }
+                Poll::Ready(Ok(n)) => self.written += n,
             }
         }
+        self.buf.clear();
+        self.written = 0;
+        self.open_frame = None;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Queue an out-of-band message after the data written so far. Like data, it's only sent
+    /// when the buffer fills up or on flush.
+    pub async fn write_msg(&mut self, code: MsgCode, payload: &[u8]) -> Result<()> {
+        ensure!(
+            self.multiplexed,
+            "can't send {:?} before multiplexing starts",
+            code
+        );
+        ensure!(payload.len() <= MAX_MSG_LEN, "{:?} message too long", code);
+
+        if self.written > 0 || self.buf.len() + 4 + payload.len() > WRITE_BUF_LEN {
+            std::future::poll_fn(|ctx| self.poll_drain(ctx)).await?;
+        }
+        self.buf
+            .extend_from_slice(&(payload.len() as u32).to_le_bytes()[..3]);
+        self.buf.push(MPLEX_BASE + code as u8);
+        self.buf.extend_from_slice(payload);
+        // Data written afterwards goes into a new frame.
+        self.open_frame = None;
+        Ok(())
+    }
+
+    /// For messages carrying a single int, e.g. `MsgCode::Redo` or `MsgCode::NoSend` with a file
+    /// index.
+    pub async fn write_msg_int(&mut self, code: MsgCode, v: i32) -> Result<()> {
+        self.write_msg(code, &v.to_le_bytes()).await
     }
 }
 
+impl<T: AsyncWrite + Unpin> AsyncWrite for EnvelopeWrite<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        ctx: &mut std::task::Context<'_>,
+        data: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        // Leave room for a frame header, so some data can always be appended.
+        if self.written > 0 || self.buf.len() + 4 >= WRITE_BUF_LEN {
+            match self.poll_drain(ctx) {
+                Poll::Ready(Ok(())) => (),
+                p => return p.map_ok(|_| 0),
+            }
+        }
+        Poll::Ready(Ok(self.append(data)))
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        ctx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        match self.poll_drain(ctx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut self.write).poll_flush(ctx),
+            p => p,
+        }
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        ctx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        match self.poll_drain(ctx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut self.write).poll_shutdown(ctx),
+            p => p,
+        }
+    }
+}
+
+/// Number of extra bytes following the first byte of a varint.
+fn int_byte_extra(b: u8) -> usize {
+    (b.leading_ones() as usize).min(6)
+}
+
 #[async_trait::async_trait]
 pub trait RsyncReadExt: AsyncRead + Unpin {
     /// For reading rsync's variable length integers… quite an odd format.
@@ -157,6 +484,229 @@ pub trait RsyncReadExt: AsyncRead + Unpin {
             v as i64
         })
     }
+
+    /// Protocol 30+ varint. The number of leading ones of the first byte tells how many bytes
+    /// follow, and the remaining bits of it are the most significant ones.
+    async fn read_varint(&mut self) -> Result<i32> {
+        let ch = self.read_u8().await?;
+        let extra = int_byte_extra(ch);
+        let mut b = [0u8; 5];
+        if extra > 0 {
+            if extra >= b.len() {
+                bail!("overflow in read_varint");
+            }
+            self.read_exact(&mut b[..extra]).await?;
+            b[extra] = ch & ((1u8 << (8 - extra)) - 1);
+        } else {
+            b[0] = ch;
+        }
+        Ok(i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// Like `read_varint`, but at least `min_bytes` bytes are always sent.
+    async fn read_varlong(&mut self, min_bytes: usize) -> Result<i64> {
+        let mut b2 = [0u8; 8];
+        self.read_exact(&mut b2[..min_bytes]).await?;
+        let mut b = [0u8; 9];
+        b[..min_bytes - 1].copy_from_slice(&b2[1..min_bytes]);
+        let extra = int_byte_extra(b2[0]);
+        if extra > 0 {
+            if min_bytes + extra > b.len() {
+                bail!("overflow in read_varlong");
+            }
+            self.read_exact(&mut b[min_bytes - 1..min_bytes - 1 + extra])
+                .await?;
+            b[min_bytes + extra - 1] = b2[0] & ((1u8 << (8 - extra)) - 1);
+        } else {
+            b[min_bytes - 1] = b2[0];
+        }
+        Ok(i64::from_le_bytes(b[..8].try_into().expect("8 bytes")))
+    }
+
+    /// A string prefixed with a one or two byte length.
+    async fn read_vstring(&mut self) -> Result<Vec<u8>> {
+        let mut len = self.read_u8().await? as usize;
+        if len & 0x80 != 0 {
+            len = (len & 0x7f) << 8 | self.read_u8().await? as usize;
+        }
+        let mut buf = vec![0; len];
+        self.read_exact(&mut buf).await?;
+        Ok(buf)
+    }
+
+    async fn read_varint30(&mut self, protocol: i32) -> Result<i32> {
+        if protocol < 30 {
+            Ok(self.read_i32_le().await?)
+        } else {
+            self.read_varint().await
+        }
+    }
+
+    async fn read_varlong30(&mut self, protocol: i32, min_bytes: usize) -> Result<i64> {
+        if protocol < 30 {
+            self.read_rsync_long().await
+        } else {
+            self.read_varlong(min_bytes).await
+        }
+    }
 }
 
 impl<T: AsyncRead + Unpin> RsyncReadExt for T {}
+
+#[async_trait::async_trait]
+pub trait RsyncWriteExt: AsyncWrite + Unpin {
+    async fn write_rsync_long(&mut self, v: i64) -> Result<()> {
+        if (0..=i32::MAX as i64).contains(&v) {
+            self.write_i32_le(v as i32).await?;
+        } else {
+            self.write_i32_le(-1).await?;
+            self.write_i64_le(v).await?;
+        }
+        Ok(())
+    }
+
+    /// See `RsyncReadExt::read_varint`.
+    async fn write_varint(&mut self, v: i32) -> Result<()> {
+        self.write_varlong(v as u32 as i64, 1).await
+    }
+
+    /// See `RsyncReadExt::read_varlong`.
+    async fn write_varlong(&mut self, v: i64, min_bytes: usize) -> Result<()> {
+        let mut b = [0u8; 9];
+        b[1..].copy_from_slice(&v.to_le_bytes());
+        let mut cnt = 8;
+        while cnt > min_bytes && b[cnt] == 0 {
+            cnt -= 1;
+        }
+        let bit = 1u8 << (7 + min_bytes - cnt);
+        if b[cnt] >= bit {
+            cnt += 1;
+            b[0] = !(bit - 1);
+        } else if cnt > min_bytes {
+            b[0] = b[cnt] | !(bit * 2 - 1);
+        } else {
+            b[0] = b[cnt];
+        }
+        self.write_all(&b[..cnt]).await?;
+        Ok(())
+    }
+
+    /// See `RsyncReadExt::read_vstring`.
+    async fn write_vstring(&mut self, s: &[u8]) -> Result<()> {
+        if s.len() > 0x7fff {
+            bail!("vstring too long");
+        } else if s.len() > 0x7f {
+            self.write_u8((s.len() >> 8) as u8 | 0x80).await?;
+        }
+        self.write_u8(s.len() as u8).await?;
+        self.write_all(s).await?;
+        Ok(())
+    }
+
+    async fn write_varint30(&mut self, protocol: i32, v: i32) -> Result<()> {
+        if protocol < 30 {
+            self.write_i32_le(v).await?;
+            Ok(())
+        } else {
+            self.write_varint(v).await
+        }
+    }
+
+    async fn write_varlong30(&mut self, protocol: i32, v: i64, min_bytes: usize) -> Result<()> {
+        if protocol < 30 {
+            self.write_rsync_long(v).await
+        } else {
+            self.write_varlong(v, min_bytes).await
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> RsyncWriteExt for T {}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, BufReader};
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use super::*;
+
+    fn frame(code: MsgCode, payload: &[u8]) -> Vec<u8> {
+        let len = payload.len() as u32;
+        let mut frame = len.to_le_bytes()[..3].to_vec();
+        frame.push(MPLEX_BASE + code as u8);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[tokio::test]
+    async fn demultiplex() {
+        let (mut peer, rx) = duplex(4096);
+        let stream = [
+            frame(MsgCode::Data, b"hel"),
+            frame(MsgCode::Info, b"sending incremental file list\n"),
+            frame(MsgCode::IoError, &1i32.to_le_bytes()),
+            frame(MsgCode::Data, b""),
+            frame(MsgCode::Redo, &7i32.to_le_bytes()),
+            frame(MsgCode::Data, b"lo"),
+            frame(MsgCode::IoError, &2i32.to_le_bytes()),
+        ]
+        .concat();
+        peer.write_all(&stream).await.unwrap();
+        drop(peer);
+
+        let (sink, mut messages) = unbounded_channel();
+        let mut rx = EnvelopeRead::new(BufReader::new(rx), true);
+        rx.set_sink(sink);
+        let mut data = vec![];
+        rx.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"hello");
+        assert_eq!(rx.io_error(), 3);
+
+        drop(rx);
+        let mut received = vec![];
+        while let Some(message) = messages.recv().await {
+            received.push(message);
+        }
+        assert_eq!(
+            received,
+            [
+                Message::Info(String::from("sending incremental file list")),
+                Message::IoError(1),
+                Message::Redo(7),
+                Message::IoError(2),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn demultiplex_errors() {
+        async fn read(stream: &[u8]) -> std::io::Result<Vec<u8>> {
+            let (mut peer, rx) = duplex(4096);
+            peer.write_all(stream).await?;
+            drop(peer);
+            let mut data = vec![];
+            EnvelopeRead::new(BufReader::new(rx), true)
+                .read_to_end(&mut data)
+                .await?;
+            Ok(data)
+        }
+
+        let exit = [
+            frame(MsgCode::Data, b"x"),
+            frame(MsgCode::ErrorExit, &23i32.to_le_bytes()),
+            frame(MsgCode::Data, b"y"),
+        ]
+        .concat();
+        let err = read(&exit).await.unwrap_err();
+        assert_eq!(err.to_string(), "Remote exited with code 23");
+
+        let err = read(&frame(MsgCode::Data, b"truncated")[..8])
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Abort during data frame");
+        let err = read(&[0, 0, 0, MPLEX_BASE + 50]).await.unwrap_err();
+        assert_eq!(err.to_string(), "Unknown message tag 57");
+        let err = read(&frame(MsgCode::Redo, b"ab")).await.unwrap_err();
+        assert_eq!(err.to_string(), "Redo message of invalid length 2");
+    }
+}
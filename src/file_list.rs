@@ -1,23 +1,46 @@
 use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Debug, Formatter};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::hash::Hash;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use eyre::{bail, Result};
-use tokio::io::AsyncReadExt;
-use tracing::debug;
+use eyre::{bail, eyre, Context, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, warn};
 
-use crate::envelope::RsyncReadExt;
+use crate::acls::{get_acls, Acl, AclLists};
+use crate::envelope::{MsgCode, RsyncReadExt, RsyncWriteExt};
+use crate::filter::FilterList;
+use crate::opts::UnsafeLinks;
+use crate::uid_list::{IdNames, IdOptions};
+use crate::xattrs::{get_xattrs, Xattr};
 use crate::EnvelopedConn;
 
-const XMIT_TOP_DIR: u8 = 1 << 0;
-const XMIT_SAME_MODE: u8 = 1 << 1;
-const XMIT_EXTENDED_FLAGS: u8 = 1 << 2;
-const XMIT_SAME_RDEV_PRE28: u8 = XMIT_EXTENDED_FLAGS; /* Only in protocols < 28 */
-const XMIT_SAME_UID: u8 = 1 << 3;
-const XMIT_SAME_GID: u8 = 1 << 4;
-const XMIT_SAME_NAME: u8 = 1 << 5;
-const XMIT_LONG_NAME: u8 = 1 << 6;
-const XMIT_SAME_TIME: u8 = 1 << 7;
+const XMIT_TOP_DIR: u32 = 1 << 0;
+const XMIT_SAME_MODE: u32 = 1 << 1;
+const XMIT_EXTENDED_FLAGS: u32 = 1 << 2; /* Protocols 28 - now */
+const XMIT_SAME_RDEV_PRE28: u32 = XMIT_EXTENDED_FLAGS; /* Only in protocols < 28 */
+const XMIT_SAME_UID: u32 = 1 << 3;
+const XMIT_SAME_GID: u32 = 1 << 4;
+const XMIT_SAME_NAME: u32 = 1 << 5;
+const XMIT_LONG_NAME: u32 = 1 << 6;
+const XMIT_SAME_TIME: u32 = 1 << 7;
+const XMIT_SAME_RDEV_MAJOR: u32 = 1 << 8; /* protocols 28 - now (devices only) */
+#[allow(dead_code)]
+const XMIT_NO_CONTENT_DIR: u32 = 1 << 8; /* protocols 30 - now (dirs only) */
+const XMIT_HLINKED: u32 = 1 << 9; /* protocols 28 - now (non-dirs) */
+const XMIT_SAME_DEV_PRE30: u32 = 1 << 10; /* protocols 28 - 29  */
+const XMIT_USER_NAME_FOLLOWS: u32 = 1 << 10; /* protocols 30 - now */
+const XMIT_RDEV_MINOR_8_PRE30: u32 = 1 << 11; /* protocols 28 - 29 */
+const XMIT_GROUP_NAME_FOLLOWS: u32 = 1 << 11; /* protocols 30 - now */
+const XMIT_HLINK_FIRST: u32 = 1 << 12; /* protocols 30 - now (HLINKED files only) */
+const XMIT_IO_ERROR_ENDLIST: u32 = 1 << 12; /* protocols 31 - now (w/XMIT_EXTENDED_FLAGS) */
+const XMIT_MOD_NSEC: u32 = 1 << 13; /* protocols 31 - now */
 
 #[derive(Clone)]
 pub struct FileEntry {
@@ -26,15 +49,157 @@ pub struct FileEntry {
     pub len: u64,
     pub modify_time: SystemTime,
     pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Device number of block and character devices.
+    pub rdev: u64,
+    /// Device and inode of hard linked files, equal within a group. Received ones may be made up.
+    pub dev_ino: Option<(u64, u64)>,
     // maybe PathBuf?
     pub link_target: Option<Vec<u8>>,
+    /// `-A`, what the mode doesn't tell. Never for symlinks.
+    pub acl: Acl,
+    /// Directories only.
+    pub default_acl: Acl,
+    /// `-X`, sorted by name.
+    pub xattrs: Vec<Xattr>,
     pub idx: i32,
 }
 
+/// What the file list carries besides names, sizes, mtimes, modes and symlinks, which both sides
+/// must agree on.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct ListOptions {
+    pub ids: IdOptions,
+    /// `-l`, symlinks and their targets.
+    pub links: bool,
+    /// `--devices`, block and character devices.
+    pub devices: bool,
+    /// `--specials`, FIFOs and sockets.
+    pub specials: bool,
+    /// `-H`, hard linked files carry their identity.
+    pub hard_links: bool,
+    /// `-X`, entries are followed by their xattrs. ACLs go with the ids.
+    pub xattrs: bool,
+}
+
+/// What entries are sent relative to, besides the previous one.
+#[derive(Debug, Default)]
+struct ListState {
+    /// Only changes of the device number are sent.
+    rdev: u64,
+    /// Same for the device of hard linked files, before protocol 30.
+    dev: u64,
+    /// Since protocol 30, followers refer to the position of the first of their group instead.
+    hlink_leaders: HashMap<(u64, u64), usize>,
+    acls: AclLists,
+    xattrs: IndexedValues<Vec<Xattr>>,
+}
+
+/// ACLs and xattr lists already in the file list, repeats are sent as their index.
+#[derive(Debug)]
+pub struct IndexedValues<T> {
+    /// The sender's.
+    index: HashMap<T, usize>,
+    /// The receiver's.
+    values: Vec<T>,
+}
+
+impl<T> Default for IndexedValues<T> {
+    fn default() -> Self {
+        Self {
+            index: HashMap::new(),
+            values: vec![],
+        }
+    }
+}
+
+impl<T: Clone + Eq + Hash> IndexedValues<T> {
+    /// The index of `value` if it was sent before, otherwise it gets the next one.
+    pub fn find_or_insert(&mut self, value: &T) -> Option<usize> {
+        let next = self.index.len();
+        match self.index.entry(value.clone()) {
+            Entry::Occupied(ndx) => Some(*ndx.get()),
+            Entry::Vacant(ndx) => {
+                ndx.insert(next);
+                None
+            }
+        }
+    }
+
+    pub fn get(&self, ndx: usize) -> Option<&T> {
+        self.values.get(ndx)
+    }
+
+    pub fn push(&mut self, value: T) {
+        self.values.push(value);
+    }
+}
+
+impl ListOptions {
+    /// ACLs and xattrs can't be sent before protocol 30.
+    pub fn check_protocol(&self, protocol: i32) -> Result<()> {
+        if protocol < 30 && (self.ids.acls || self.xattrs) {
+            bail!("ACLs and xattrs require protocol 30 or higher");
+        }
+        Ok(())
+    }
+
+    /// Whether entries of `mode` are sent at all, besides directories, regular files and symlinks.
+    pub fn includes(&self, mode: u32) -> bool {
+        if is_device(mode) {
+            self.devices
+        } else {
+            self.specials && (unix_mode::is_fifo(mode) || unix_mode::is_socket(mode))
+        }
+    }
+
+    /// Whether an entry of `mode` has a device number.
+    fn sends_rdev(&self, mode: u32, protocol: i32) -> bool {
+        is_device(mode) && self.devices || protocol < 31 && self.includes(mode) && !is_device(mode)
+    }
+}
+
+pub fn is_device(mode: u32) -> bool {
+    unix_mode::is_block_device(mode) || unix_mode::is_char_device(mode)
+}
+
+/// Split a Linux `dev_t` into major and minor, as glibc does.
+fn split_rdev(rdev: u64) -> (u32, u32) {
+    let major = (rdev >> 8) & 0xfff | (rdev >> 32) & !0xfff;
+    let minor = rdev & 0xff | (rdev >> 12) & !0xff;
+    (major as u32, minor as u32)
+}
+
+fn make_rdev(major: u32, minor: u32) -> u64 {
+    let (major, minor) = (major as u64, minor as u64);
+    (major & 0xfff) << 8 | (major & !0xfff) << 32 | minor & 0xff | (minor & !0xff) << 12
+}
+
 impl FileEntry {
     pub fn name_lossy(&self) -> Cow<'_, str> {
         String::from_utf8_lossy(&self.name)
     }
+
+    /// An entry with just a name and a mode, dated at the epoch.
+    #[cfg(test)]
+    pub fn bare(name: &str, mode: u32) -> Self {
+        Self {
+            name: name.as_bytes().to_vec(),
+            len: 0,
+            modify_time: UNIX_EPOCH,
+            mode,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            dev_ino: None,
+            link_target: None,
+            acl: Acl::default(),
+            default_acl: Acl::default(),
+            xattrs: vec![],
+            idx: 0,
+        }
+    }
 }
 
 impl Debug for FileEntry {
@@ -44,65 +209,376 @@ impl Debug for FileEntry {
             .field("len", &self.len)
             .field("modify_time", &self.modify_time)
             .field("mode", &self.mode)
+            .field("uid", &self.uid)
+            .field("gid", &self.gid)
+            .field("rdev", &self.rdev)
+            .field("dev_ino", &self.dev_ino)
             .field(
                 "link_target",
                 &(self
                     .link_target
                     .as_ref()
-                    .map(|s| String::from_utf8_lossy(&s))),
+                    .map(|s| String::from_utf8_lossy(s))),
             )
+            .field("acl", &self.acl)
+            .field("default_acl", &self.default_acl)
+            .field("xattrs", &self.xattrs)
             .field("idx", &self.idx)
             .finish()
     }
 }
 
-impl<'a> EnvelopedConn<'a> {
-    pub async fn recv_file_list(&mut self) -> Result<Vec<FileEntry>> {
+impl<R: AsyncRead + Unpin + Send, W: AsyncWrite + Unpin + Send> EnvelopedConn<R, W> {
+    /// Receive the file list, sorted the same way as the sender does. Also returns the sender's
+    /// names for the ids in it, and the io error flag sent by the server.
+    pub async fn recv_file_list(
+        &mut self,
+        options: ListOptions,
+    ) -> Result<(Vec<FileEntry>, IdNames, i32)> {
+        let protocol = self.protocol.version;
         let mut list = vec![];
+        let mut names = IdNames::default();
+        let mut io_errors = 0;
 
         let mut name_scratch = Vec::new();
+        let mut state = ListState::default();
         loop {
-            let b = self.rx.read_u8().await?;
-            if b == 0 {
-                break;
-            }
+            let flags = if self.protocol.varint_flist_flags() {
+                let flags = self.rx.read_varint().await? as u32;
+                if flags == 0 {
+                    io_errors |= self.rx.read_varint().await?;
+                    break;
+                }
+                flags
+            } else {
+                let mut flags = self.rx.read_u8().await? as u32;
+                if flags == 0 {
+                    break;
+                }
+                if protocol >= 28 && flags & XMIT_EXTENDED_FLAGS != 0 {
+                    flags |= (self.rx.read_u8().await? as u32) << 8;
+                }
+                if flags == XMIT_EXTENDED_FLAGS | XMIT_IO_ERROR_ENDLIST {
+                    if !self.protocol.safe_flist() {
+                        bail!("unexpected io error marker in file list");
+                    }
+                    io_errors |= self.rx.read_varint().await?;
+                    break;
+                }
+                flags
+            };
 
-            let entry = self
-                .recv_file_entry(b, &mut name_scratch, list.last())
+            let mut entry = self
+                .recv_file_entry(
+                    flags,
+                    &mut name_scratch,
+                    &list,
+                    options,
+                    &mut names,
+                    &mut state,
+                )
                 .await?;
+            if options.ids.acls && !unix_mode::is_symlink(entry.mode) {
+                self.recv_acls(&mut entry, &mut state.acls, &mut names)
+                    .await?;
+            }
+            if options.xattrs {
+                entry.xattrs = self.recv_xattrs(&mut state.xattrs).await?;
+            }
             debug!(?entry, "recv file entry");
             list.push(entry);
         }
 
-        list.sort_unstable_by(|x, y| x.name.cmp(&y.name));
-        list.dedup_by(|x, y| x.name == y.name);
+        self.recv_id_lists(options.ids, &mut names).await?;
+        if protocol < 30 {
+            io_errors |= self.rx.read_i32_le().await?;
+        }
+
+        sort_file_list(&mut list, protocol);
+        check_no_links_in_paths(&list)?;
+
+        Ok((list, names, io_errors))
+    }
+
+    /// Send a file list built by `scan_file_list`, followed by the names of its ids and the io
+    /// error flag.
+    pub async fn send_file_list(
+        &mut self,
+        list: &[FileEntry],
+        io_errors: i32,
+        options: ListOptions,
+    ) -> Result<()> {
+        let protocol = self.protocol.version;
 
-        // Now we mark their idx
-        for (idx, entry) in list.iter_mut().enumerate() {
-            entry.idx = i32::try_from(idx).expect("file list too long");
+        let mut prev = None;
+        let mut state = ListState::default();
+        for (pos, entry) in list.iter().enumerate() {
+            debug!(?entry, "send file entry");
+            self.send_file_entry(entry, pos, prev, options, &mut state)
+                .await?;
+            if options.ids.acls && !unix_mode::is_symlink(entry.mode) {
+                self.send_acls(entry, &mut state.acls).await?;
+            }
+            if options.xattrs {
+                self.send_xattrs(&entry.xattrs, &mut state.xattrs).await?;
+            }
+            prev = Some(entry);
         }
 
-        Ok(list)
+        if self.protocol.varint_flist_flags() {
+            self.tx.write_varint(0).await?;
+            self.tx.write_varint(io_errors).await?;
+        } else if io_errors != 0 && self.protocol.safe_flist() {
+            let flags = (XMIT_EXTENDED_FLAGS | XMIT_IO_ERROR_ENDLIST) as u16;
+            self.tx.write_u16_le(flags).await?;
+            self.tx.write_varint(io_errors).await?;
+        } else {
+            if io_errors != 0 && protocol >= 30 {
+                // No room for them at the end of the list, tell the client out of band.
+                self.tx.write_msg_int(MsgCode::IoError, io_errors).await?;
+            }
+            self.tx.write_u8(0).await?;
+        }
+
+        self.send_id_lists(list, options.ids).await?;
+        if protocol < 30 {
+            self.tx.write_i32_le(io_errors).await?;
+        }
+        self.tx.flush().await?;
+        Ok(())
+    }
+
+    /// The counterpart of `recv_file_entry`. `pos` is the position of `entry` in the list.
+    async fn send_file_entry(
+        &mut self,
+        entry: &FileEntry,
+        pos: usize,
+        prev: Option<&FileEntry>,
+        options: ListOptions,
+        state: &mut ListState,
+    ) -> Result<()> {
+        let protocol = self.protocol.version;
+        let is_dir = unix_mode::is_dir(entry.mode);
+        let (secs, nsecs) = unix_time(entry.modify_time);
+
+        let mut flags = 0;
+        if entry.name == b"." {
+            flags |= XMIT_TOP_DIR;
+        }
+        if prev.map(|prev| prev.mode) == Some(entry.mode) {
+            flags |= XMIT_SAME_MODE;
+        }
+        if prev.map(|prev| unix_time(prev.modify_time).0) == Some(secs) {
+            flags |= XMIT_SAME_TIME;
+        }
+        if options.ids.owner && prev.map(|prev| prev.uid) == Some(entry.uid) {
+            flags |= XMIT_SAME_UID;
+        }
+        if options.ids.group && prev.map(|prev| prev.gid) == Some(entry.gid) {
+            flags |= XMIT_SAME_GID;
+        }
+        let send_rdev = options.sends_rdev(entry.mode, protocol);
+        // Specials carry a dummy device number.
+        let rdev = if is_device(entry.mode) { entry.rdev } else { 0 };
+        if send_rdev {
+            let (major, minor) = split_rdev(rdev);
+            if protocol < 28 {
+                if rdev == state.rdev {
+                    flags |= XMIT_SAME_RDEV_PRE28;
+                }
+            } else {
+                if major == split_rdev(state.rdev).0 {
+                    flags |= XMIT_SAME_RDEV_MAJOR;
+                }
+                if protocol < 30 && minor <= 0xff {
+                    flags |= XMIT_RDEV_MINOR_8_PRE30;
+                }
+            }
+        }
+        if protocol >= 31 && nsecs != 0 {
+            flags |= XMIT_MOD_NSEC;
+        }
+        // Only the first of a hard link group is sent in full since protocol 30.
+        let dev_ino = entry.dev_ino.filter(|_| {
+            options.hard_links
+                && if protocol < 28 {
+                    unix_mode::is_file(entry.mode)
+                } else {
+                    !is_dir
+                }
+        });
+        let mut leader = None;
+        if let Some(dev_ino) = dev_ino {
+            flags |= XMIT_HLINKED;
+            if protocol >= 30 {
+                match state.hlink_leaders.entry(dev_ino) {
+                    Entry::Occupied(first) => leader = Some(*first.get()),
+                    Entry::Vacant(first) => {
+                        first.insert(pos);
+                        flags |= XMIT_HLINK_FIRST;
+                    }
+                }
+            } else if protocol >= 28 && dev_ino.0 == state.dev {
+                flags |= XMIT_SAME_DEV_PRE30;
+            }
+        }
+
+        // Names share their prefix with the previous one, up to 255 bytes.
+        let inherit_name_len = prev.map_or(0, |prev| {
+            prev.name
+                .iter()
+                .zip(&entry.name)
+                .take(255)
+                .take_while(|(x, y)| x == y)
+                .count()
+        });
+        let name_len = entry.name.len() - inherit_name_len;
+        if inherit_name_len > 0 {
+            flags |= XMIT_SAME_NAME;
+        }
+        if name_len > 255 {
+            flags |= XMIT_LONG_NAME;
+        }
+
+        // A zero flag byte would end the list.
+        if self.protocol.varint_flist_flags() {
+            self.tx
+                .write_varint(if flags == 0 {
+                    XMIT_EXTENDED_FLAGS
+                } else {
+                    flags
+                } as i32)
+                .await?;
+        } else if protocol >= 28 {
+            if flags == 0 && !is_dir {
+                flags |= XMIT_TOP_DIR;
+            }
+            if flags & 0xff00 != 0 || flags == 0 {
+                flags |= XMIT_EXTENDED_FLAGS;
+                self.tx.write_u16_le(flags as u16).await?;
+            } else {
+                self.tx.write_u8(flags as u8).await?;
+            }
+        } else {
+            if flags & 0xff == 0 {
+                flags |= if is_dir { XMIT_LONG_NAME } else { XMIT_TOP_DIR };
+            }
+            self.tx.write_u8(flags as u8).await?;
+        }
+
+        if flags & XMIT_SAME_NAME != 0 {
+            self.tx.write_u8(inherit_name_len as u8).await?;
+        }
+        if flags & XMIT_LONG_NAME != 0 {
+            self.tx.write_varint30(protocol, name_len as i32).await?;
+        } else {
+            self.tx.write_u8(name_len as u8).await?;
+        }
+        self.tx.write_all(&entry.name[inherit_name_len..]).await?;
+        if let Some(leader) = leader {
+            // The rest is the leader's. Like rsync, its device still counts for the next one.
+            if send_rdev && is_device(entry.mode) {
+                state.rdev = rdev;
+            }
+            self.tx.write_varint(leader as i32).await?;
+            return Ok(());
+        }
+
+        self.tx
+            .write_varlong30(protocol, entry.len as i64, 3)
+            .await?;
+        if flags & XMIT_SAME_TIME == 0 {
+            if protocol >= 30 {
+                self.tx.write_varlong(secs, 4).await?;
+            } else {
+                self.tx.write_u32_le(secs as u32).await?;
+            }
+        }
+        if flags & XMIT_MOD_NSEC != 0 {
+            self.tx.write_varint(nsecs as i32).await?;
+        }
+        if flags & XMIT_SAME_MODE == 0 {
+            self.tx.write_u32_le(entry.mode).await?;
+        }
+        // Names are sent in the id lists instead of with the entries.
+        if options.ids.owner && flags & XMIT_SAME_UID == 0 {
+            self.send_id(entry.uid).await?;
+        }
+        if options.ids.group && flags & XMIT_SAME_GID == 0 {
+            self.send_id(entry.gid).await?;
+        }
+        if send_rdev {
+            let (major, minor) = split_rdev(rdev);
+            if protocol < 28 {
+                if flags & XMIT_SAME_RDEV_PRE28 == 0 {
+                    self.tx.write_u32_le(rdev as u32).await?;
+                }
+            } else {
+                if flags & XMIT_SAME_RDEV_MAJOR == 0 {
+                    self.tx.write_varint30(protocol, major as i32).await?;
+                }
+                if protocol >= 30 {
+                    self.tx.write_varint(minor as i32).await?;
+                } else if flags & XMIT_RDEV_MINOR_8_PRE30 != 0 {
+                    self.tx.write_u8(minor as u8).await?;
+                } else {
+                    self.tx.write_u32_le(minor).await?;
+                }
+            }
+            state.rdev = rdev;
+        } else if protocol < 28 {
+            state.rdev = 0;
+        }
+
+        if let Some(target) = &entry.link_target {
+            self.tx
+                .write_varint30(protocol, target.len() as i32)
+                .await?;
+            self.tx.write_all(target).await?;
+        }
+
+        if let Some((dev, ino)) = dev_ino.filter(|_| protocol < 30) {
+            if flags & XMIT_SAME_DEV_PRE30 == 0 {
+                self.tx.write_rsync_long(dev as i64).await?;
+            }
+            self.tx.write_rsync_long(ino as i64).await?;
+            state.dev = dev;
+        }
+
+        Ok(())
+    }
+
+    async fn send_id(&mut self, id: u32) -> Result<()> {
+        if self.protocol.version >= 30 {
+            self.tx.write_varint(id as i32).await
+        } else {
+            self.tx.write_u32_le(id).await?;
+            Ok(())
+        }
     }
 
     async fn recv_file_entry(
         &mut self,
-        flags: u8,
+        flags: u32,
         name_scratch: &mut Vec<u8>,
-        prev: Option<&FileEntry>,
+        list: &[FileEntry],
+        options: ListOptions,
+        names: &mut IdNames,
+        state: &mut ListState,
     ) -> Result<FileEntry> {
         let same_name = flags & XMIT_SAME_NAME != 0;
         let long_name = flags & XMIT_LONG_NAME != 0;
         let same_time = flags & XMIT_SAME_TIME != 0;
         let same_mode = flags & XMIT_SAME_MODE != 0;
 
+        let protocol = self.protocol.version;
         let inherit_name_len = if same_name {
             self.rx.read_u8().await?
         } else {
             0
         };
         let name_len = if long_name {
-            self.rx.read_u32_le().await?
+            self.rx.read_varint30(protocol).await? as u32
         } else {
             self.rx.read_u8().await? as u32
         };
@@ -120,21 +596,46 @@ impl<'a> EnvelopedConn<'a> {
         self.rx
             .read_exact(&mut name_scratch[inherit_name_len as usize..])
             .await?;
-        // TODO this only works on unix
-        // TODO: does rsync’s clean_fname() and sanitize_path() combination do
-        // anything more than Go’s filepath.Clean()?
         let name = name_scratch.clone();
+        check_name(&name)?;
+
+        if protocol >= 30 && flags & XMIT_HLINKED != 0 && flags & XMIT_HLINK_FIRST == 0 {
+            // A hard link follower, everything else is its leader's.
+            let leader = self.rx.read_varint().await?;
+            let Some(leader) = usize::try_from(leader).ok().and_then(|pos| list.get(pos)) else {
+                bail!("hard link leader {} out of range", leader);
+            };
+            if options.devices && is_device(leader.mode) {
+                state.rdev = leader.rdev;
+            }
+            return Ok(FileEntry {
+                name,
+                ..leader.clone()
+            });
+        }
+        let prev = list.last();
 
-        let len = self.rx.read_rsync_long().await? as u64;
+        let len = self.rx.read_varlong30(protocol, 3).await? as u64;
 
-        let modify_time = if same_time {
-            prev.expect("prev must exist").modify_time
+        // Like rsync, the seconds may be the previous entry's but the nanoseconds never are.
+        let secs = if same_time {
+            let Some(prev) = prev else {
+                bail!("same time flag on the first file");
+            };
+            unix_time(prev.modify_time).0
+        } else if protocol >= 30 {
+            self.rx.read_varlong(4).await?
         } else {
             // To avoid Y2038 problem, newer versions of rsync daemon treat mtime as u32 when
-            // speaking protocol version 27.
-            let secs = self.rx.read_u32_le().await?;
-            SystemTime::UNIX_EPOCH + std::time::Duration::new(secs as u64, 0)
+            // speaking protocol version < 30.
+            self.rx.read_u32_le().await?.into()
         };
+        let nsecs = if flags & XMIT_MOD_NSEC != 0 {
+            self.rx.read_varint().await? as u32
+        } else {
+            0
+        };
+        let modify_time = from_unix_time(secs, nsecs)?;
 
         let mode = if same_mode {
             prev.expect("prev must exist").mode
@@ -144,13 +645,65 @@ impl<'a> EnvelopedConn<'a> {
 
         let is_link = unix_mode::is_symlink(mode);
 
-        // Preserve uid is disabled
-        // Preserve gid is disabled
-        // Preserve dev and special are disabled
+        let uid = if !options.ids.owner {
+            0
+        } else if flags & XMIT_SAME_UID != 0 {
+            prev.map_or(0, |prev| prev.uid)
+        } else {
+            let uid = self.recv_id().await?;
+            if protocol >= 30 && flags & XMIT_USER_NAME_FOLLOWS != 0 {
+                let name = self.recv_id_name().await?;
+                names.users.insert(uid, name);
+            }
+            uid
+        };
+        let gid = if !options.ids.group {
+            0
+        } else if flags & XMIT_SAME_GID != 0 {
+            prev.map_or(0, |prev| prev.gid)
+        } else {
+            let gid = self.recv_id().await?;
+            if protocol >= 30 && flags & XMIT_GROUP_NAME_FOLLOWS != 0 {
+                let name = self.recv_id_name().await?;
+                names.groups.insert(gid, name);
+            }
+            gid
+        };
+
+        let rdev = if options.sends_rdev(mode, protocol) {
+            let rdev = if protocol < 28 {
+                if flags & XMIT_SAME_RDEV_PRE28 != 0 {
+                    state.rdev
+                } else {
+                    self.rx.read_u32_le().await? as u64
+                }
+            } else {
+                let major = if flags & XMIT_SAME_RDEV_MAJOR != 0 {
+                    split_rdev(state.rdev).0
+                } else {
+                    self.rx.read_varint30(protocol).await? as u32
+                };
+                let minor = if protocol >= 30 {
+                    self.rx.read_varint().await? as u32
+                } else if flags & XMIT_RDEV_MINOR_8_PRE30 != 0 {
+                    self.rx.read_u8().await? as u32
+                } else {
+                    self.rx.read_u32_le().await?
+                };
+                make_rdev(major, minor)
+            };
+            state.rdev = rdev;
+            rdev
+        } else {
+            if protocol < 28 {
+                state.rdev = 0;
+            }
+            0
+        };
 
         // Preserve links
-        let link_target = if is_link {
-            let len = self.rx.read_u32_le().await?;
+        let link_target = if is_link && options.links {
+            let len = self.rx.read_varint30(protocol).await?;
             let mut buf = vec![0u8; len as usize];
             self.rx.read_exact(&mut buf).await?;
             // TODO this only works on unix
@@ -159,22 +712,709 @@ impl<'a> EnvelopedConn<'a> {
             None
         };
 
+        // Before protocol 28 every regular file carries its device and inode.
+        let hlinked = if protocol < 28 {
+            options.hard_links && unix_mode::is_file(mode)
+        } else {
+            flags & XMIT_HLINKED != 0
+        };
+        let dev_ino = if !hlinked {
+            None
+        } else if protocol < 30 {
+            let dev = if flags & XMIT_SAME_DEV_PRE30 != 0 {
+                state.dev
+            } else {
+                self.rx.read_rsync_long().await? as u64
+            };
+            let ino = self.rx.read_rsync_long().await? as u64;
+            state.dev = dev;
+            Some((dev, ino))
+        } else {
+            // A group leader, followers refer to its position.
+            Some((0, list.len() as u64))
+        };
+
         Ok(FileEntry {
             name,
             len,
             modify_time,
             mode,
+            uid,
+            gid,
+            rdev,
+            dev_ino,
             link_target,
+            acl: Acl::default(),
+            default_acl: Acl::default(),
+            xattrs: vec![],
             idx: i32::MAX, // to be filled later
         })
     }
+
+    async fn recv_id(&mut self) -> Result<u32> {
+        if self.protocol.version >= 30 {
+            Ok(self.rx.read_varint().await? as u32)
+        } else {
+            Ok(self.rx.read_u32_le().await?)
+        }
+    }
+}
+
+/// Walk `root` and build the list of files to send, sorted and indexed. Names are relative to
+/// `root`, which itself is sent as `.`. Excluded directories aren't descended into.
+pub async fn scan_file_list(
+    root: &Path,
+    protocol: i32,
+    filter: &mut FilterList,
+    unsafe_links: UnsafeLinks,
+    options: ListOptions,
+) -> Result<Vec<FileEntry>> {
+    let mut list = vec![];
+    scan_tree(
+        root,
+        PathBuf::from("."),
+        true,
+        filter,
+        unsafe_links,
+        options,
+        &mut list,
+    )
+    .await?;
+    sort_file_list(&mut list, protocol);
+    if protocol >= 28 {
+        forget_lone_links(&mut list);
+    }
+    Ok(list)
+}
+
+/// Build the list of files to send for `--files-from` names relative to `root`. Their parent
+/// directories are sent too, like rsync's implied dirs, and directories are only descended into
+/// with `recurse`. Missing names are skipped.
+pub async fn scan_files_from(
+    root: &Path,
+    names: &[PathBuf],
+    recurse: bool,
+    protocol: i32,
+    filter: &mut FilterList,
+    unsafe_links: UnsafeLinks,
+    options: ListOptions,
+) -> Result<Vec<FileEntry>> {
+    let mut list = vec![];
+    let mut implied_dirs = HashSet::new();
+    filter.enter_dir(b".", root).await?;
+    for name in names {
+        let path = root.join(name);
+        if let Err(e) = tokio::fs::symlink_metadata(&path).await {
+            warn!(?path, "can't stat: {}", e);
+            continue;
+        }
+
+        let mut parents: Vec<_> = name
+            .ancestors()
+            .skip(1)
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .collect();
+        parents.reverse();
+        for dir in parents {
+            if implied_dirs.insert(dir.to_path_buf()) {
+                scan_tree(
+                    root,
+                    dir.to_path_buf(),
+                    false,
+                    filter,
+                    unsafe_links,
+                    options,
+                    &mut list,
+                )
+                .await?;
+            }
+        }
+        scan_tree(
+            root,
+            name.clone(),
+            recurse,
+            filter,
+            unsafe_links,
+            options,
+            &mut list,
+        )
+        .await?;
+    }
+
+    sort_file_list(&mut list, protocol);
+    if protocol >= 28 {
+        forget_lone_links(&mut list);
+    }
+    Ok(list)
+}
+
+/// Add `start` below `root` to `list`, and with `recurse` everything inside it.
+async fn scan_tree(
+    root: &Path,
+    start: PathBuf,
+    recurse: bool,
+    filter: &mut FilterList,
+    unsafe_links: UnsafeLinks,
+    options: ListOptions,
+    list: &mut Vec<FileEntry>,
+) -> Result<()> {
+    let mut pending = vec![start];
+    while let Some(name) = pending.pop() {
+        let path = root.join(&name);
+        let mut meta = tokio::fs::symlink_metadata(&path)
+            .await
+            .with_context(|| format!("can't stat {}", path.display()))?;
+        if meta.file_type().is_symlink() && unsafe_links == UnsafeLinks::Copy {
+            // TODO unix only
+            let target = tokio::fs::read_link(&path).await?;
+            if is_unsafe_symlink(target.as_os_str().as_bytes(), name.as_os_str().as_bytes()) {
+                // Send what it points to instead.
+                match tokio::fs::metadata(&path).await {
+                    Ok(target_meta) => meta = target_meta,
+                    Err(e) => {
+                        warn!(?path, "can't follow unsafe symlink: {}", e);
+                        continue;
+                    }
+                }
+            }
+        }
+        // TODO unix only
+        if filter.is_excluded(name.as_os_str().as_bytes(), meta.is_dir()) {
+            debug!(?path, "excluded");
+            continue;
+        }
+
+        let link_target = if meta.file_type().is_symlink() && options.links {
+            // TODO unix only
+            Some(
+                tokio::fs::read_link(&path)
+                    .await?
+                    .into_os_string()
+                    .into_vec(),
+            )
+        } else if meta.is_dir() {
+            // TODO unix only
+            filter.enter_dir(name.as_os_str().as_bytes(), root).await?;
+            if recurse {
+                let mut dir = tokio::fs::read_dir(&path).await?;
+                while let Some(child) = dir.next_entry().await? {
+                    if name == Path::new(".") {
+                        pending.push(PathBuf::from(child.file_name()));
+                    } else {
+                        pending.push(name.join(child.file_name()));
+                    }
+                }
+            }
+            None
+        } else if meta.is_file() || options.includes(meta.mode()) {
+            None
+        } else {
+            debug!(?path, "skip non-regular file");
+            continue;
+        };
+
+        let (acl, default_acl) = if options.ids.acls && !meta.file_type().is_symlink() {
+            get_acls(&path, meta.mode())?
+        } else {
+            Default::default()
+        };
+        let xattrs = if options.xattrs {
+            get_xattrs(&path, filter.xattrs())?
+        } else {
+            vec![]
+        };
+        list.push(FileEntry {
+            // TODO unix only
+            name: name.as_os_str().as_bytes().to_vec(),
+            len: meta.len(),
+            modify_time: meta.modified()?,
+            mode: meta.mode(),
+            uid: meta.uid(),
+            gid: meta.gid(),
+            rdev: meta.rdev(),
+            dev_ino: (options.hard_links && !meta.is_dir()).then(|| (meta.dev(), meta.ino())),
+            link_target,
+            acl,
+            default_acl,
+            xattrs,
+            idx: i32::MAX, // to be filled later
+        });
+    }
+    Ok(())
+}
+
+/// Since protocol 28 only files linked to others are marked, before that every regular file.
+fn forget_lone_links(list: &mut [FileEntry]) {
+    let mut links = HashMap::new();
+    for dev_ino in list.iter().filter_map(|entry| entry.dev_ino) {
+        *links.entry(dev_ino).or_insert(0) += 1;
+    }
+    for entry in list {
+        if entry.dev_ino.is_some_and(|dev_ino| links[&dev_ino] < 2) {
+            entry.dev_ino = None;
+        }
+    }
+}
+
+/// Whether symlink `name`, relative to the transfer root, points outside of it, like rsync's
+/// `unsafe_symlink`. Absolute and empty targets are unsafe.
+pub fn is_unsafe_symlink(target: &[u8], name: &[u8]) -> bool {
+    if target.is_empty() || target.starts_with(b"/") {
+        return true;
+    }
+
+    // Directories between the root and the link.
+    let mut depth = name.iter().filter(|c| **c == b'/').count();
+    for component in target.split(|c| *c == b'/') {
+        match component {
+            b".." => match depth.checked_sub(1) {
+                Some(up) => depth = up,
+                None => return true,
+            },
+            b"" | b"." => {}
+            _ => depth += 1,
+        }
+    }
+    false
+}
+
+/// Reject received names that could escape the transfer root: absolute ones and ones with `..`
+/// components, which rsync's `clean_fname` and `sanitize_path` take care of.
+fn check_name(name: &[u8]) -> Result<()> {
+    if name.starts_with(b"/") || name.split(|&c| c == b'/').any(|part| part == b"..") {
+        bail!("unsafe file name {}", String::from_utf8_lossy(name));
+    }
+    Ok(())
+}
+
+/// Reject entries below a symlink in the same list, which would be created wherever it points.
+fn check_no_links_in_paths(list: &[FileEntry]) -> Result<()> {
+    let links: HashSet<&[u8]> = list
+        .iter()
+        .filter(|entry| entry.link_target.is_some())
+        .map(|entry| entry.name.as_slice())
+        .collect();
+    if links.is_empty() {
+        return Ok(());
+    }
+    for entry in list {
+        let parents = entry
+            .name
+            .iter()
+            .enumerate()
+            .filter(|(_, &c)| c == b'/')
+            .map(|(i, _)| &entry.name[..i]);
+        for parent in parents {
+            if links.contains(parent) {
+                bail!(
+                    "{} is below the symlink {}",
+                    entry.name_lossy(),
+                    String::from_utf8_lossy(parent)
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Sort, dedup and index the file list the same way on both sides.
+fn sort_file_list(list: &mut Vec<FileEntry>, protocol: i32) {
+    list.sort_unstable_by(|x, y| f_name_cmp(x, y, protocol));
+    list.dedup_by(|x, y| x.name == y.name);
+
+    // Now we mark their idx
+    for (idx, entry) in list.iter_mut().enumerate() {
+        entry.idx = i32::try_from(idx).expect("file list too long");
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum NameType {
+    Item,
+    Path,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum NameState {
+    Dir,
+    Slash,
+    Base,
+    Trailing,
+}
+
+/// Walks a file name the way rsync's `f_name_cmp` does: dirname, slash, basename, and a trailing
+/// slash for directories since protocol 29.
+struct NameCursor<'a> {
+    #[allow(dead_code)]
+    dirname: &'a [u8],
+    basename: &'a [u8],
+    is_dir: bool,
+    t_path: NameType,
+    typ: NameType,
+    state: NameState,
+    cur: &'a [u8],
+}
+
+impl<'a> NameCursor<'a> {
+    fn new(entry: &'a FileEntry, protocol: i32) -> Self {
+        let (dirname, basename) = match entry.name.iter().rposition(|c| *c == b'/') {
+            Some(pos) => (&entry.name[..pos], &entry.name[pos + 1..]),
+            None => (&entry.name[..0], &entry.name[..]),
+        };
+        let t_path = if protocol >= 29 {
+            NameType::Path
+        } else {
+            NameType::Item
+        };
+        let mut cursor = Self {
+            dirname,
+            basename,
+            is_dir: unix_mode::is_dir(entry.mode),
+            t_path,
+            typ: t_path,
+            state: NameState::Dir,
+            cur: dirname,
+        };
+        if dirname.is_empty() {
+            cursor.enter_basename();
+        }
+        cursor
+    }
+
+    fn enter_basename(&mut self) {
+        self.typ = if self.is_dir {
+            self.t_path
+        } else {
+            NameType::Item
+        };
+        if self.typ == NameType::Path && self.basename == b"." {
+            self.typ = NameType::Item;
+            self.state = NameState::Trailing;
+            self.cur = b"";
+        } else {
+            self.state = NameState::Base;
+            self.cur = self.basename;
+        }
+    }
+
+    /// Move on to the next segment once `cur` is exhausted.
+    fn advance(&mut self) {
+        match self.state {
+            NameState::Dir => {
+                self.state = NameState::Slash;
+                self.cur = b"/";
+            }
+            NameState::Slash => self.enter_basename(),
+            NameState::Base if self.typ == NameType::Path => {
+                self.state = NameState::Trailing;
+                self.cur = b"/";
+            }
+            NameState::Base | NameState::Trailing => {
+                self.state = NameState::Trailing;
+                self.typ = NameType::Item;
+            }
+        }
+    }
+}
+
+/// Sort order of the file list, must match the sender's or indices won't line up.
+///
+/// Since protocol 29, files sort before directories on the same level.
+pub fn f_name_cmp(x: &FileEntry, y: &FileEntry, protocol: i32) -> Ordering {
+    fn path_last(typ: NameType) -> Ordering {
+        if typ == NameType::Path {
+            Ordering::Greater
+        } else {
+            Ordering::Less
+        }
+    }
+
+    let mut c1 = NameCursor::new(x, protocol);
+    let mut c2 = NameCursor::new(y, protocol);
+    if c1.typ != c2.typ {
+        return path_last(c1.typ);
+    }
+
+    loop {
+        if c1.cur.is_empty() {
+            c1.advance();
+            if !c2.cur.is_empty() && c1.typ != c2.typ {
+                return path_last(c1.typ);
+            }
+        }
+        if c2.cur.is_empty() {
+            c2.advance();
+            if !c1.cur.is_empty() && c1.typ != c2.typ {
+                return path_last(c1.typ);
+            }
+        }
+
+        let b1 = c1.cur.first().copied().unwrap_or(0);
+        let b2 = c2.cur.first().copied().unwrap_or(0);
+        match b1.cmp(&b2) {
+            // Both names are exhausted.
+            Ordering::Equal if b1 == 0 => return Ordering::Equal,
+            Ordering::Equal => {
+                c1.cur = &c1.cur[1..];
+                c2.cur = &c2.cur[1..];
+            }
+            ord => return ord,
+        }
+    }
 }
 
 pub fn mod_time_eq(x: SystemTime, y: SystemTime) -> bool {
-    x.duration_since(UNIX_EPOCH)
-        .expect("time before unix epoch")
-        .as_secs()
-        == y.duration_since(UNIX_EPOCH)
-            .expect("time before unix epoch")
-            .as_secs()
+    unix_time(x).0 == unix_time(y).0
+}
+
+/// Seconds and nanoseconds since the epoch, as in `struct timespec`: the seconds are rounded down
+/// for times before 1970 too.
+fn unix_time(time: SystemTime) -> (i64, u32) {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => (d.as_secs() as i64, d.subsec_nanos()),
+        Err(e) => {
+            let d = e.duration();
+            match d.subsec_nanos() {
+                0 => (-(d.as_secs() as i64), 0),
+                nsecs => (-(d.as_secs() as i64) - 1, 1_000_000_000 - nsecs),
+            }
+        }
+    }
+}
+
+/// The counterpart of `unix_time`.
+fn from_unix_time(secs: i64, nsecs: u32) -> Result<SystemTime> {
+    let time = if nsecs >= 1_000_000_000 {
+        None
+    } else if secs >= 0 {
+        UNIX_EPOCH.checked_add(Duration::new(secs as u64, nsecs))
+    } else {
+        UNIX_EPOCH
+            .checked_sub(Duration::from_secs(secs.unsigned_abs()))
+            .and_then(|time| time.checked_add(Duration::from_nanos(nsecs.into())))
+    };
+    time.ok_or_else(|| eyre!("invalid modification time {}.{:09}", secs, nsecs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsafe_symlinks() {
+        let unsafe_link =
+            |target: &str, name: &str| is_unsafe_symlink(target.as_bytes(), name.as_bytes());
+        assert!(unsafe_link("/etc/passwd", "link"));
+        assert!(unsafe_link("", "link"));
+        assert!(unsafe_link("..", "link"));
+        assert!(unsafe_link("../x", "link"));
+        assert!(unsafe_link("../../x", "d/link"));
+        assert!(unsafe_link("a/../..", "link"));
+        assert!(unsafe_link("a/../../../x", "d/link"));
+        // Leaving and coming back in is unsafe too.
+        assert!(unsafe_link("../d", "link"));
+
+        assert!(!unsafe_link("x", "link"));
+        assert!(!unsafe_link("./x/", "link"));
+        assert!(!unsafe_link("../x", "d/link"));
+        assert!(!unsafe_link("a/../x", "link"));
+        assert!(!unsafe_link("a//b/../../x", "link"));
+        assert!(!unsafe_link("../../x", "d/e/link"));
+    }
+
+    #[test]
+    fn rdev_split() {
+        for (major, minor) in [
+            (0, 0),
+            (8, 1),
+            (0xfff, 0xff),
+            (0x1000, 0x100),
+            (259, 0x12345),
+        ] {
+            let rdev = make_rdev(major, minor);
+            assert_eq!(rdev, libc::makedev(major, minor));
+            assert_eq!(split_rdev(rdev), (major, minor));
+        }
+        let rdev = make_rdev(u32::MAX, u32::MAX);
+        assert_eq!(rdev, u64::MAX);
+        assert_eq!(split_rdev(rdev), (u32::MAX, u32::MAX));
+    }
+
+    /// Send `list` and read it back.
+    async fn round_trip(list: &[FileEntry], version: i32, options: ListOptions) -> Vec<FileEntry> {
+        let mut conn = EnvelopedConn::loopback(version);
+        conn.send_file_list(list, 0, options).await.unwrap();
+        let (received, _, io_errors) = conn.recv_file_list(options).await.unwrap();
+        assert_eq!(io_errors, 0, "{}", version);
+        received
+    }
+
+    #[tokio::test]
+    async fn rdev_round_trip() {
+        let device = |name, mode, major, minor| {
+            let mut entry = FileEntry::bare(name, mode);
+            entry.rdev = make_rdev(major, minor);
+            entry
+        };
+        let list = [
+            device("a", 0o020644, 1, 3),
+            // Same device before 28, same major since.
+            device("b", 0o020644, 1, 3),
+            // Minor too large for a byte before 30.
+            device("c", 0o060644, 1, 0x1234),
+            device("d", 0o060644, 259, 5),
+            // Specials carry a dummy device before 31, which is remembered as well.
+            FileEntry::bare("e", 0o010644),
+            device("f", 0o020644, 259, 5),
+            // Regular files don't touch the device before 28 either.
+            FileEntry::bare("g", 0o100644),
+            device("h", 0o020644, 259, 5),
+            // Only the low 32 bits of the device make it before 28.
+            device("i", 0o020644, 0x1000, 0x100000),
+        ];
+        let options = ListOptions {
+            devices: true,
+            specials: true,
+            ..ListOptions::default()
+        };
+        for version in 27..=31 {
+            let list = if version < 28 {
+                &list[..list.len() - 1]
+            } else {
+                &list
+            };
+            let received = round_trip(list, version, options).await;
+            let rdevs = |list: &[FileEntry]| -> Vec<_> {
+                list.iter()
+                    .map(|entry| (entry.name.clone(), entry.mode, entry.rdev))
+                    .collect()
+            };
+            assert_eq!(rdevs(&received), rdevs(list), "{}", version);
+        }
+    }
+
+    /// The first entry of each entry's hard link group, by name.
+    fn leaders(list: &[FileEntry]) -> Vec<Option<&[u8]>> {
+        list.iter()
+            .map(|entry| {
+                let dev_ino = entry.dev_ino?;
+                let leader = list.iter().find(|other| other.dev_ino == Some(dev_ino))?;
+                Some(leader.name.as_slice())
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn hard_link_round_trip() {
+        let file = |name, dev_ino| {
+            let mut entry = FileEntry::bare(name, 0o100644);
+            entry.dev_ino = dev_ino;
+            entry
+        };
+        let list = [
+            file("a", Some((1, 10))),
+            file("b", Some((1, 11))),
+            file("c", Some((1, 10))),
+            file("d", None),
+            file("e", Some((2, 10))),
+            file("f", Some((1, 11))),
+            file("g", Some((2, 10))),
+        ];
+        let options = ListOptions {
+            hard_links: true,
+            ..ListOptions::default()
+        };
+        for version in 27..=31 {
+            let mut list = list.clone();
+            if version < 28 {
+                // Every regular file carries one.
+                list[3].dev_ino = Some((3, 10));
+            }
+            let received = round_trip(&list, version, options).await;
+            assert_eq!(leaders(&received), leaders(&list), "{}", version);
+            let dev_inos: Vec<_> = received.iter().map(|entry| entry.dev_ino).collect();
+            if version < 30 {
+                // Sent as they are, the device only when it changes since 28.
+                let sent: Vec<_> = list.iter().map(|entry| entry.dev_ino).collect();
+                assert_eq!(dev_inos, sent, "{}", version);
+            } else {
+                // Followers only carry the index of their leader.
+                let positions = [Some((0, 0)), Some((0, 1)), Some((0, 0)), None];
+                assert_eq!(dev_inos[..4], positions, "{}", version);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn hard_linked_devices() {
+        let device = |name, major, minor, dev_ino| {
+            let mut entry = FileEntry::bare(name, 0o020644);
+            entry.rdev = make_rdev(major, minor);
+            entry.dev_ino = dev_ino;
+            entry
+        };
+        let list = [
+            device("a", 7, 1, Some((1, 10))),
+            device("b", 5, 1, None),
+            device("c", 7, 1, Some((1, 10))),
+            device("d", 7, 2, None),
+        ];
+        let options = ListOptions {
+            devices: true,
+            hard_links: true,
+            ..ListOptions::default()
+        };
+
+        // What rsync sends: the follower's device counts for the major of the next one.
+        let mut rsync = vec![];
+        let extended = |flags| (XMIT_EXTENDED_FLAGS | flags) as u16;
+        let same = XMIT_SAME_MODE | XMIT_SAME_TIME;
+        rsync
+            .write_u16_le(extended(XMIT_HLINKED | XMIT_HLINK_FIRST))
+            .await
+            .unwrap();
+        rsync.write_all(b"\x01a").await.unwrap();
+        rsync.write_varlong(0, 3).await.unwrap();
+        rsync.write_varlong(0, 4).await.unwrap();
+        rsync.write_u32_le(0o020644).await.unwrap();
+        rsync.write_all(&[7, 1]).await.unwrap();
+        rsync.write_u8(same as u8).await.unwrap();
+        rsync.write_all(b"\x01b").await.unwrap();
+        rsync.write_varlong(0, 3).await.unwrap();
+        rsync.write_all(&[5, 1]).await.unwrap();
+        rsync
+            .write_u16_le(extended(same | XMIT_HLINKED))
+            .await
+            .unwrap();
+        rsync.write_all(b"\x01c").await.unwrap();
+        rsync.write_varint(0).await.unwrap();
+        rsync
+            .write_u16_le(extended(same | XMIT_SAME_RDEV_MAJOR))
+            .await
+            .unwrap();
+        rsync.write_all(b"\x01d").await.unwrap();
+        rsync.write_varlong(0, 3).await.unwrap();
+        rsync.write_all(&[2]).await.unwrap();
+        // The end of the list.
+        rsync.write_u8(0).await.unwrap();
+
+        let mut conn = EnvelopedConn::loopback(31);
+        conn.send_file_list(&list, 0, options).await.unwrap();
+        let EnvelopedConn { tx, mut rx, .. } = conn;
+        drop(tx);
+        let mut sent = vec![];
+        rx.read_to_end(&mut sent).await.unwrap();
+        assert_eq!(sent, rsync);
+
+        let mut conn = EnvelopedConn::loopback(31);
+        conn.tx.write_all(&rsync).await.unwrap();
+        conn.tx.flush().await.unwrap();
+        let (received, _, _) = conn.recv_file_list(options).await.unwrap();
+        let rdevs: Vec<_> = received
+            .iter()
+            .map(|entry| split_rdev(entry.rdev))
+            .collect();
+        assert_eq!(rdevs, [(7, 1), (5, 1), (7, 1), (7, 2)]);
+        assert_eq!(leaders(&received), leaders(&list));
+    }
 }
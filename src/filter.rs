@@ -1,45 +1,1247 @@
-use std::ffi::OsString;
-use std::os::unix::ffi::OsStrExt;
+//! Filter rules in rsync's syntax, see the FILTER RULES section of rsync(1).
 
-use eyre::Result;
-use tokio::io::AsyncWriteExt;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::future::Future;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
+use std::pin::Pin;
 
-use crate::Conn;
+use eyre::{bail, ensure, eyre, Context, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::warn;
+
+use crate::file_list::FileEntry;
+use crate::EnvelopedConn;
 
 const EXCLUSION_LIST_END: i32 = 0;
 
-#[derive(Debug)]
-pub enum Rule {
-    Exclude(OsString),
-    Include(OsString),
+/// `hide`, `show`, `protect` and `risk` are parsed the way rsync does, into excludes and
+/// includes limited to one side of the transfer.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RuleKind {
+    Exclude,
+    Include,
+    /// Read more rules from a file when the rule is parsed.
+    Merge,
+    /// Read more rules from a file in every directory of the transfer.
+    DirMerge,
+    /// Drop all rules before this one.
+    Clear,
 }
 
-impl Rule {
-    fn to_command(&self) -> OsString {
+impl RuleKind {
+    fn prefix(self) -> u8 {
+        match self {
+            RuleKind::Exclude => b'-',
+            RuleKind::Include => b'+',
+            RuleKind::Merge => b'.',
+            RuleKind::DirMerge => b':',
+            RuleKind::Clear => b'!',
+        }
+    }
+
+    fn valid_modifiers(self) -> &'static [u8] {
         match self {
-            Rule::Exclude(path) => {
-                let mut cmd = OsString::from("-");
-                cmd.push(path);
-                cmd
+            RuleKind::Exclude | RuleKind::Include => b"/!Cxsrp",
+            RuleKind::Merge | RuleKind::DirMerge => b"-+Cenwsrp",
+            RuleKind::Clear => b"",
+        }
+    }
+}
+
+/// Rule kind and side modifiers of a short or long rule name.
+fn parse_name(name: &[u8]) -> Option<(RuleKind, bool, bool)> {
+    let parsed = match name {
+        b"-" | b"exclude" => (RuleKind::Exclude, false, false),
+        b"+" | b"include" => (RuleKind::Include, false, false),
+        b"." | b"merge" => (RuleKind::Merge, false, false),
+        b":" | b"dir-merge" => (RuleKind::DirMerge, false, false),
+        b"!" | b"clear" => (RuleKind::Clear, false, false),
+        b"H" | b"hide" => (RuleKind::Exclude, true, false),
+        b"S" | b"show" => (RuleKind::Include, true, false),
+        b"P" | b"protect" => (RuleKind::Exclude, false, true),
+        b"R" | b"risk" => (RuleKind::Include, false, true),
+        _ => return None,
+    };
+    Some(parsed)
+}
+
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Modifiers {
+    /// `/`: match against the absolute path of the file.
+    pub absolute: bool,
+    /// `!`: the rule applies when the pattern doesn't match.
+    pub negate: bool,
+    /// `C`: CVS ignore rules. Without a pattern, `-C` stands for the default CVS excludes and
+    /// `:C` for `.cvsignore` files.
+    pub cvs: bool,
+    /// `s`: only affects the sending side.
+    pub sender: bool,
+    /// `r`: only affects the receiving side.
+    pub receiver: bool,
+    /// `p`: ignored in directories that are being deleted.
+    pub perishable: bool,
+    /// `x`: matches xattr names instead of files.
+    pub xattr: bool,
+    /// `+` or `-`: merged files contain bare include or exclude patterns.
+    pub merge_kind: Option<RuleKind>,
+    /// `e`: exclude the merge file itself.
+    pub exclude_self: bool,
+    /// `n`: merged rules aren't inherited by subdirectories.
+    pub no_inherit: bool,
+    /// `w`: merged files are split on whitespace instead of lines.
+    pub word_split: bool,
+}
+
+impl Modifiers {
+    fn set(&mut self, modifier: u8) {
+        match modifier {
+            b'/' => self.absolute = true,
+            b'!' => self.negate = true,
+            b'C' => self.cvs = true,
+            b's' => self.sender = true,
+            b'r' => self.receiver = true,
+            b'p' => self.perishable = true,
+            b'x' => self.xattr = true,
+            b'-' => self.merge_kind = Some(RuleKind::Exclude),
+            b'+' => self.merge_kind = Some(RuleKind::Include),
+            b'e' => self.exclude_self = true,
+            b'n' => self.no_inherit = true,
+            b'w' => self.word_split = true,
+            _ => unreachable!("modifier validated by caller"),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let flags = [
+            (self.absolute, b'/'),
+            (self.negate, b'!'),
+            (self.cvs, b'C'),
+            (self.no_inherit, b'n'),
+            (self.word_split, b'w'),
+            (self.exclude_self, b'e'),
+            (self.merge_kind == Some(RuleKind::Exclude), b'-'),
+            (self.merge_kind == Some(RuleKind::Include), b'+'),
+            (self.xattr, b'x'),
+            (self.sender, b's'),
+            (self.receiver, b'r'),
+            (self.perishable, b'p'),
+        ];
+        flags
+            .into_iter()
+            .filter_map(|(set, c)| set.then_some(c))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Rule {
+    pub kind: RuleKind,
+    pub modifiers: Modifiers,
+    /// Kept verbatim: a leading `/` anchors the pattern to the transfer root, a trailing `/`
+    /// only matches directories, and `*`, `**`, `?` and `[...]` are wildcards. The file to read
+    /// for merge rules, empty for `clear`.
+    pub pattern: OsString,
+}
+
+impl Rule {
+    /// Parse a rule as given to `--filter`, e.g. `- *.o`, `-/ /tmp`, `dir-merge,n- .ignore`,
+    /// `P /backup/` or `!`. The pattern follows a single space or underscore.
+    pub fn parse(text: &[u8]) -> Result<Self> {
+        let lossy = || String::from_utf8_lossy(text);
+
+        // Long names are words, optionally followed by `,` and modifiers.
+        let word_end = text
+            .iter()
+            .position(|c| matches!(c, b' ' | b'_' | b','))
+            .unwrap_or(text.len());
+        let (kind, sender, receiver, rest) = match parse_name(&text[..word_end]) {
+            Some((kind, sender, receiver)) if word_end > 1 => {
+                let rest = &text[word_end..];
+                (
+                    kind,
+                    sender,
+                    receiver,
+                    rest.strip_prefix(b",").unwrap_or(rest),
+                )
             }
-            Rule::Include(path) => {
-                let mut cmd = OsString::from("+");
-                cmd.push(path);
-                cmd
+            _ => {
+                let (kind, sender, receiver) = text
+                    .first()
+                    .and_then(|c| parse_name(std::slice::from_ref(c)))
+                    .ok_or_else(|| eyre!("unknown filter rule: {}", lossy()))?;
+                (kind, sender, receiver, &text[1..])
             }
+        };
+
+        let mods_end = rest
+            .iter()
+            .position(|c| matches!(c, b' ' | b'_'))
+            .unwrap_or(rest.len());
+        let mut modifiers = Modifiers {
+            sender,
+            receiver,
+            ..Modifiers::default()
+        };
+        for &modifier in &rest[..mods_end] {
+            ensure!(
+                kind.valid_modifiers().contains(&modifier),
+                "invalid modifier '{}' in filter rule: {}",
+                modifier as char,
+                lossy()
+            );
+            modifier_conflicts(&modifiers, modifier).map_err(|e| eyre!("{}: {}", e, lossy()))?;
+            modifiers.set(modifier);
+        }
+        let pattern = rest.get(mods_end + 1..).unwrap_or_default();
+
+        match kind {
+            RuleKind::Clear => ensure!(
+                pattern.is_empty(),
+                "clear rule takes no pattern: {}",
+                lossy()
+            ),
+            _ => ensure!(
+                !pattern.is_empty() || modifiers.cvs,
+                "filter rule has no pattern: {}",
+                lossy()
+            ),
+        }
+
+        Ok(Rule {
+            kind,
+            modifiers,
+            pattern: OsString::from_vec(pattern.to_vec()),
+        })
+    }
+
+    /// Parse a rule sent by a peer speaking `protocol`. Before protocol 29 only the `+ ` and
+    /// `- ` prefixes exist, anything else is an exclude pattern.
+    pub fn parse_wire(cmd: &[u8], protocol: i32) -> Result<Self> {
+        if protocol >= 29 {
+            return Self::parse(cmd);
+        }
+
+        let (kind, pattern) = match cmd {
+            [b'+', b' ', pattern @ ..] => (RuleKind::Include, pattern),
+            [b'-', b' ', pattern @ ..] => (RuleKind::Exclude, pattern),
+            [b'!'] => (RuleKind::Clear, &[][..]),
+            pattern => (RuleKind::Exclude, pattern),
+        };
+        Ok(Rule {
+            kind,
+            modifiers: Modifiers::default(),
+            pattern: OsString::from_vec(pattern.to_vec()),
+        })
+    }
+
+    /// The short form of the rule, `Rule::parse` reads it back unchanged.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut cmd = vec![self.kind.prefix()];
+        cmd.extend(self.modifiers.to_bytes());
+        if !self.pattern.is_empty() {
+            cmd.push(b' ');
+            cmd.extend_from_slice(self.pattern.as_bytes());
         }
+        cmd
+    }
+
+    /// The rule as sent to a peer speaking `protocol`. The side modifiers are dropped for old
+    /// peers, callers only send rules that apply to the peer's side.
+    pub fn to_wire(&self, protocol: i32) -> Result<Vec<u8>> {
+        if protocol >= 29 {
+            return Ok(self.to_bytes());
+        }
+
+        let sides_only = Modifiers {
+            sender: self.modifiers.sender,
+            receiver: self.modifiers.receiver,
+            ..Modifiers::default()
+        };
+        ensure!(
+            matches!(self.kind, RuleKind::Include | RuleKind::Exclude)
+                && self.modifiers == sides_only,
+            "filter rule is too modern for protocol {}: {}",
+            protocol,
+            self
+        );
+        let mut cmd = vec![self.kind.prefix(), b' '];
+        cmd.extend_from_slice(self.pattern.as_bytes());
+        Ok(cmd)
+    }
+
+    /// Whether the rule affects the sending side of the transfer.
+    pub fn applies_to_sender(&self) -> bool {
+        self.modifiers.sender || !self.modifiers.receiver
+    }
+
+    /// Whether the rule affects the receiving side of the transfer, i.e. deletions.
+    pub fn applies_to_receiver(&self) -> bool {
+        self.modifiers.receiver || !self.modifiers.sender
     }
 }
 
-impl<'a> Conn<'a> {
-    pub async fn send_filter_rules(&mut self, rules: &[Rule]) -> Result<()> {
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&String::from_utf8_lossy(&self.to_bytes()))
+    }
+}
+
+fn modifier_conflicts(modifiers: &Modifiers, modifier: u8) -> Result<()> {
+    match modifier {
+        b'-' | b'+' if modifiers.merge_kind.is_some() => bail!("both '+' and '-' modifiers"),
+        b'C' if modifiers.merge_kind.is_some() => bail!("'C' modifier with '+' or '-'"),
+        b'-' | b'+' if modifiers.cvs => bail!("'C' modifier with '+' or '-'"),
+        _ => Ok(()),
+    }
+}
+
+/// Patterns of rsync's `-C` without a pattern, whitespace separated as in rsync.
+const DEFAULT_CVS_EXCLUDES: &str = "RCS SCCS CVS CVS.adm RCSLOG cvslog.* tags TAGS .make.state \
+    .nse_depinfo *~ #* .#* ,* _$* *$ *.old *.bak *.BAK *.orig *.rej .del-* *.a *.olb *.o *.obj \
+    *.so *.exe *.Z *.elc *.ln core .svn/ .git/ .hg/ .bzr/";
+
+/// Which side of the transfer a filter list is evaluated for.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Side {
+    /// Decides what gets into the file list.
+    Sender,
+    /// Decides what may be deleted.
+    Receiver,
+}
+
+impl Side {
+    /// Whether `rule` filters files on this side.
+    fn applies(self, rule: &Rule) -> bool {
+        let applies = match self {
+            Side::Sender => rule.applies_to_sender(),
+            Side::Receiver => rule.applies_to_receiver(),
+        };
+        applies && !rule.modifiers.xattr
+    }
+}
+
+/// A rule pattern, split up the way rsync does before matching.
+#[derive(Debug)]
+struct Matcher {
+    include: bool,
+    negate: bool,
+    absolute: bool,
+    /// Leading `/`, stripped from `pattern`.
+    anchored: bool,
+    /// Trailing `/`, stripped from `pattern`.
+    dir_only: bool,
+    /// Patterns with a `/` (anchored included) or `**` are matched against the whole path, others
+    /// against the last component only.
+    full_path: bool,
+    pattern: Vec<u8>,
+}
+
+impl Matcher {
+    fn new(include: bool, modifiers: &Modifiers, pattern: &[u8]) -> Self {
+        let (anchored, pattern) = match pattern.strip_prefix(b"/") {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        let (dir_only, pattern) = match pattern.strip_suffix(b"/") {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        Matcher {
+            include,
+            negate: modifiers.negate,
+            absolute: modifiers.absolute,
+            anchored,
+            dir_only,
+            full_path: anchored
+                || pattern.contains(&b'/')
+                || pattern.windows(2).any(|w| w == b"**"),
+            pattern: pattern.to_vec(),
+        }
+    }
+
+    fn matches(&self, name: &[u8], abs_name: &[u8], is_dir: bool) -> bool {
+        // Like rsync, `-! */` excludes everything but directories.
+        if self.dir_only && !is_dir {
+            return self.negate;
+        }
+
+        let name = if self.absolute { abs_name } else { name };
+        let matched = if !self.full_path {
+            let base = name.rsplit(|c| *c == b'/').next().unwrap_or(name);
+            wildmatch(&self.pattern, base)
+        } else if self.anchored || self.absolute {
+            wildmatch(&self.pattern, name)
+        } else {
+            // Unanchored paths may match after any slash.
+            wildmatch(&self.pattern, name)
+                || name
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, c)| **c == b'/')
+                    .any(|(i, _)| wildmatch(&self.pattern, &name[i + 1..]))
+        };
+        matched != self.negate
+    }
+}
+
+/// Shell-style matching as in rsync's wildmatch: `*` and `?` stop at slashes, `**` doesn't, and
+/// a trailing `/***` also matches the directory itself.
+pub fn wildmatch(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        b"/***" if text.is_empty() => true,
+        [b'*', b'*', rest @ ..] => {
+            let rest = rest.strip_prefix(b"*").unwrap_or(rest);
+            (0..=text.len()).any(|i| wildmatch(rest, &text[i..]))
+        }
+        [b'*', rest @ ..] => {
+            let segment = text.iter().position(|c| *c == b'/').unwrap_or(text.len());
+            (0..=segment).any(|i| wildmatch(rest, &text[i..]))
+        }
+        [b'?', rest @ ..] => match text {
+            [c, text @ ..] if *c != b'/' => wildmatch(rest, text),
+            _ => false,
+        },
+        [b'[', class @ ..] => match (text, match_class(class, text.first().copied())) {
+            ([_, text @ ..], Some((true, rest))) => wildmatch(rest, text),
+            // An unterminated class is a literal `[`.
+            ([b'[', text @ ..], None) => wildmatch(class, text),
+            _ => false,
+        },
+        [b'\\', c, rest @ ..] | [c, rest @ ..] => match text {
+            [t, text @ ..] if t == c => wildmatch(rest, text),
+            _ => false,
+        },
+    }
+}
+
+/// Match `c` against a `[...]` class, `class` starting after the `[`. Returns whether it matched
+/// and the pattern after the `]`, or `None` if the class isn't terminated.
+fn match_class(class: &[u8], c: Option<u8>) -> Option<(bool, &[u8])> {
+    let (negated, mut class) = match class {
+        [b'!' | b'^', class @ ..] => (true, class),
+        _ => (false, class),
+    };
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let (lo, rest) = match class {
+            [] => return None,
+            [b']', rest @ ..] if !first => {
+                let matched = c.is_some_and(|c| c != b'/' && matched != negated);
+                return Some((matched, rest));
+            }
+            [b'\\', lo, rest @ ..] | [lo, rest @ ..] => (*lo, rest),
+        };
+        first = false;
+        let (hi, rest) = match rest {
+            [b'-', b'\\', hi, rest @ ..] => (*hi, rest),
+            [b'-', hi, rest @ ..] if *hi != b']' => (*hi, rest),
+            _ => (lo, rest),
+        };
+        matched |= c.is_some_and(|c| (lo..=hi).contains(&c));
+        class = rest;
+    }
+}
+
+/// Matchers for an include or exclude rule, `-C` stands for many.
+fn rule_matchers(rule: &Rule) -> Vec<Matcher> {
+    let include = rule.kind == RuleKind::Include;
+    if rule.pattern.is_empty() {
+        DEFAULT_CVS_EXCLUDES
+            .split_whitespace()
+            .map(|pattern| Matcher::new(include, &Modifiers::default(), pattern.as_bytes()))
+            .collect()
+    } else {
+        vec![Matcher::new(
+            include,
+            &rule.modifiers,
+            rule.pattern.as_bytes(),
+        )]
+    }
+}
+
+/// The parent directory of a name relative to the transfer root, `.` for top level names.
+fn parent_dir(name: &[u8]) -> &[u8] {
+    name.iter()
+        .rposition(|c| *c == b'/')
+        .map_or(b".", |i| &name[..i])
+}
+
+/// A `dir-merge` rule, standing for the rules read from its file in each directory.
+#[derive(Debug)]
+struct DirMerge {
+    file_name: OsString,
+    modifiers: Modifiers,
+    /// Rules read so far, keyed by directory name relative to the transfer root.
+    layers: HashMap<Vec<u8>, Layer>,
+}
+
+#[derive(Debug)]
+struct Layer {
+    matchers: Vec<Matcher>,
+    /// The file has a `clear` rule, the parent directories' files don't apply.
+    clear: bool,
+}
+
+impl DirMerge {
+    /// The first rule matching `name`, looking at the file in its directory first, then the ones
+    /// above it. Patterns are relative to the directory of the file they're read from.
+    fn find(&self, name: &[u8], abs_name: &[u8], is_dir: bool) -> Option<&Matcher> {
+        let parent = parent_dir(name);
+        let mut dir = parent;
+        loop {
+            if let Some(layer) = self.layers.get(dir) {
+                if !self.modifiers.no_inherit || dir == parent {
+                    let rel_name = if dir == b"." {
+                        name
+                    } else {
+                        &name[dir.len() + 1..]
+                    };
+                    let matcher = layer
+                        .matchers
+                        .iter()
+                        .find(|matcher| matcher.matches(rel_name, abs_name, is_dir));
+                    if matcher.is_some() {
+                        return matcher;
+                    }
+                }
+                if layer.clear {
+                    return None;
+                }
+            }
+            if dir == b"." {
+                return None;
+            }
+            dir = parent_dir(dir);
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Slot {
+    Matcher(Matcher),
+    DirMerge(DirMerge),
+}
+
+/// Rules ready to be matched against paths, in order.
+#[derive(Debug)]
+pub struct FilterList {
+    slots: Vec<Slot>,
+    side: Side,
+    /// Prefix of names for rules with the `/` modifier.
+    root: Vec<u8>,
+    xattrs: XattrFilter,
+}
+
+impl FilterList {
+    /// Compile the rules affecting `side` of a transfer rooted at `root`. Rules before a `clear`
+    /// are dropped, xattr rules go to `xattrs`. `dir-merge` files are read by `enter_dir`.
+    pub fn new(rules: &[Rule], side: Side, root: &Path) -> Self {
+        let mut slots = vec![];
+        for rule in rules.iter().filter(|rule| side.applies(rule)) {
+            match rule.kind {
+                RuleKind::Include | RuleKind::Exclude => {
+                    slots.extend(rule_matchers(rule).into_iter().map(Slot::Matcher));
+                }
+                RuleKind::Clear => slots.clear(),
+                RuleKind::Merge => {
+                    // Merge rules should have gone through `expand_merge_rules`.
+                    warn!("merge rule not applied: {}", rule);
+                }
+                RuleKind::DirMerge => {
+                    let mut modifiers = rule.modifiers.clone();
+                    let mut file_name = rule.pattern.clone();
+                    if file_name.is_empty() {
+                        // `:C`, rsync doesn't inherit these either.
+                        file_name = OsString::from(".cvsignore");
+                        modifiers.no_inherit = true;
+                    }
+                    slots.push(Slot::DirMerge(DirMerge {
+                        file_name,
+                        modifiers,
+                        layers: HashMap::new(),
+                    }));
+                }
+            }
+        }
+
+        // Anchored patterns lose their leading slash, so the root does too.
+        // TODO unix only
+        let root = std::path::absolute(root).unwrap_or_else(|_| root.to_path_buf());
+        let root = root.as_os_str().as_bytes();
+        let mut root = root.strip_prefix(b"/").unwrap_or(root).to_vec();
+        if !root.is_empty() && !root.ends_with(b"/") {
+            root.push(b'/');
+        }
+        FilterList {
+            slots,
+            side,
+            root,
+            xattrs: XattrFilter::new(rules),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    pub fn xattrs(&self) -> &XattrFilter {
+        &self.xattrs
+    }
+
+    /// Read the `dir-merge` files in `dir`, a directory relative to the transfer root found
+    /// under `base`. Must be called before checking names inside `dir`.
+    pub async fn enter_dir(&mut self, dir: &[u8], base: &Path) -> Result<()> {
+        let side = self.side;
+        for slot in &mut self.slots {
+            let Slot::DirMerge(merge) = slot else {
+                continue;
+            };
+
+            // TODO unix only
+            let path = base.join(OsStr::from_bytes(dir)).join(&merge.file_name);
+            match tokio::fs::symlink_metadata(&path).await {
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            }
+            let rules = load_merge_file(&path, &merge.modifiers, false).await?;
+
+            let mut layer = Layer {
+                matchers: vec![],
+                clear: false,
+            };
+            for rule in rules.iter().filter(|rule| side.applies(rule)) {
+                match rule.kind {
+                    RuleKind::Include | RuleKind::Exclude => {
+                        layer.matchers.extend(rule_matchers(rule));
+                    }
+                    RuleKind::Clear => {
+                        layer.matchers.clear();
+                        layer.clear = true;
+                    }
+                    RuleKind::Merge | RuleKind::DirMerge => {
+                        // TODO nested merge files
+                        warn!(?path, "merge rule not applied: {}", rule);
+                    }
+                }
+            }
+            merge.layers.insert(dir.to_vec(), layer);
+        }
+        Ok(())
+    }
+
+    /// Whether `name`, relative to the transfer root, is excluded by the first matching rule.
+    /// The contents of an excluded directory aren't checked here, see `retain`.
+    pub fn is_excluded(&self, name: &[u8], is_dir: bool) -> bool {
+        // The transfer root itself is never filtered.
+        if name == b"." {
+            return false;
+        }
+
+        let mut abs_name = self.root.clone();
+        abs_name.extend_from_slice(name);
+        self.slots
+            .iter()
+            .find_map(|slot| match slot {
+                Slot::Matcher(matcher) => {
+                    matcher.matches(name, &abs_name, is_dir).then_some(matcher)
+                }
+                Slot::DirMerge(merge) => merge.find(name, &abs_name, is_dir),
+            })
+            .is_some_and(|matcher| !matcher.include)
+    }
+
+    /// Drop excluded entries from a sorted file list, along with everything inside excluded
+    /// directories. `dir-merge` files are read from the directories under `base` as the list is
+    /// walked. Indices are left alone, they still refer to the peer's list.
+    pub async fn retain(&mut self, list: &mut Vec<FileEntry>, base: &Path) -> Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+
+        let mut excluded_dirs = HashSet::new();
+        let mut kept = Vec::with_capacity(list.len());
+        for entry in list.drain(..) {
+            let inside_excluded = entry
+                .name
+                .iter()
+                .enumerate()
+                .filter(|(_, c)| **c == b'/')
+                .any(|(i, _)| excluded_dirs.contains(&entry.name[..i]));
+            if inside_excluded {
+                continue;
+            }
+
+            let is_dir = unix_mode::is_dir(entry.mode);
+            if self.is_excluded(&entry.name, is_dir) {
+                if is_dir {
+                    excluded_dirs.insert(entry.name);
+                }
+                continue;
+            }
+            if is_dir {
+                self.enter_dir(&entry.name, base).await?;
+            }
+            kept.push(entry);
+        }
+        *list = kept;
+        Ok(())
+    }
+}
+
+/// The `x` rules, matched against xattr names instead of files. They apply to both sides.
+#[derive(Debug, Default)]
+pub struct XattrFilter {
+    matchers: Vec<Matcher>,
+}
+
+impl XattrFilter {
+    /// Like `FilterList::new`, rules before a `clear` are dropped.
+    pub fn new(rules: &[Rule]) -> Self {
+        let mut matchers = vec![];
+        for rule in rules {
+            match rule.kind {
+                RuleKind::Include | RuleKind::Exclude if rule.modifiers.xattr => {
+                    matchers.extend(rule_matchers(rule));
+                }
+                RuleKind::Clear => matchers.clear(),
+                _ => (),
+            }
+        }
+        Self { matchers }
+    }
+
+    /// Whether xattr `name` is excluded by the first matching rule.
+    pub fn is_excluded(&self, name: &[u8]) -> bool {
+        self.matchers
+            .iter()
+            .find(|matcher| matcher.matches(name, name, false))
+            .is_some_and(|matcher| !matcher.include)
+    }
+}
+
+/// How lines of a filter file are turned into rules.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum FileFormat {
+    /// Full filter rules, as in `--filter=merge`.
+    Rules,
+    /// Bare patterns of one kind, or `+ `/`- ` prefixed ones, as in `--exclude-from`.
+    Patterns(RuleKind),
+    /// Whitespace separated excludes, as in `.cvsignore`.
+    Cvs,
+}
+
+/// Merge files may merge other files, up to this depth.
+const MAX_MERGE_DEPTH: usize = 16;
+
+/// Read `--exclude-from` patterns. Lines may also start with `+ ` or `- `. `-` reads stdin.
+pub async fn load_exclude_from(path: &Path, from0: bool) -> Result<Vec<Rule>> {
+    let data = read_filter_file(path).await?;
+    parse_filter_file(
+        &data,
+        path,
+        FileFormat::Patterns(RuleKind::Exclude),
+        from0,
+        false,
+    )
+}
+
+/// Read `--include-from` patterns. Lines may also start with `+ ` or `- `. `-` reads stdin.
+pub async fn load_include_from(path: &Path, from0: bool) -> Result<Vec<Rule>> {
+    let data = read_filter_file(path).await?;
+    parse_filter_file(
+        &data,
+        path,
+        FileFormat::Patterns(RuleKind::Include),
+        from0,
+        false,
+    )
+}
+
+/// Replace `merge` rules with the rules in their files, recursively. Other rules, `dir-merge`
+/// ones included, are kept in place.
+pub async fn expand_merge_rules(rules: &[Rule], from0: bool) -> Result<Vec<Rule>> {
+    expand_merge_rules_at(rules, from0, 0).await
+}
+
+fn expand_merge_rules_at(
+    rules: &[Rule],
+    from0: bool,
+    depth: usize,
+) -> Pin<Box<dyn Future<Output = Result<Vec<Rule>>> + Send + '_>> {
+    Box::pin(async move {
+        let mut expanded = vec![];
         for rule in rules {
-            let cmd = rule.to_command();
+            if rule.kind != RuleKind::Merge {
+                expanded.push(rule.clone());
+                continue;
+            }
+            ensure!(
+                depth < MAX_MERGE_DEPTH,
+                "merge files nested too deep: {}",
+                rule
+            );
+
+            let path = Path::new(&rule.pattern);
+            let merged = load_merge_file(path, &rule.modifiers, from0).await?;
+            expanded.extend(expand_merge_rules_at(&merged, from0, depth + 1).await?);
+        }
+        Ok(expanded)
+    })
+}
+
+/// Read the rules of a `merge` or `dir-merge` file, applying the rule's modifiers to them.
+async fn load_merge_file(path: &Path, modifiers: &Modifiers, from0: bool) -> Result<Vec<Rule>> {
+    let format = if modifiers.cvs {
+        FileFormat::Cvs
+    } else if let Some(kind) = modifiers.merge_kind {
+        FileFormat::Patterns(kind)
+    } else {
+        FileFormat::Rules
+    };
+    let data = read_filter_file(path).await?;
+    let mut rules = parse_filter_file(&data, path, format, from0, modifiers.word_split)?;
+    for rule in rules.iter_mut().filter(|rule| rule.kind != RuleKind::Clear) {
+        rule.modifiers.sender |= modifiers.sender;
+        rule.modifiers.receiver |= modifiers.receiver;
+        rule.modifiers.perishable |= modifiers.perishable;
+    }
+
+    if modifiers.exclude_self {
+        let name = path.file_name().unwrap_or(path.as_os_str());
+        rules.push(Rule {
+            kind: RuleKind::Exclude,
+            modifiers: Modifiers {
+                sender: modifiers.sender,
+                receiver: modifiers.receiver,
+                ..Modifiers::default()
+            },
+            pattern: name.to_os_string(),
+        });
+    }
+    Ok(rules)
+}
+
+async fn read_filter_file(path: &Path) -> Result<Vec<u8>> {
+    let mut data = vec![];
+    if path == Path::new("-") {
+        tokio::io::stdin().read_to_end(&mut data).await?;
+    } else {
+        data = tokio::fs::read(path)
+            .await
+            .with_context(|| format!("can't read filter file {}", path.display()))?;
+    }
+    Ok(data)
+}
+
+/// Read a `--files-from` list. Names end with `\n` or `\r`, or `\0` with `from0`. Blank lines
+/// are skipped. `-` reads stdin.
+pub async fn load_files_from(path: &Path, from0: bool) -> Result<Vec<Vec<u8>>> {
+    let data = read_filter_file(path).await?;
+    let names = data
+        .split(|c| {
+            if from0 {
+                *c == 0
+            } else {
+                matches!(c, b'\n' | b'\r')
+            }
+        })
+        .filter(|name| !name.is_empty())
+        .map(<[u8]>::to_vec)
+        .collect();
+    Ok(names)
+}
+
+/// Lines end with `\n`, optionally preceded by `\r`, or `\0` with `from0`. Blank lines are
+/// skipped, so are lines starting with `#` or `;` unless the file is split into words.
+fn parse_filter_file(
+    data: &[u8],
+    path: &Path,
+    format: FileFormat,
+    from0: bool,
+    word_split: bool,
+) -> Result<Vec<Rule>> {
+    let word_split = word_split || format == FileFormat::Cvs;
+    let mut rules = vec![];
+    let eol = if from0 { b'\0' } else { b'\n' };
+    let lines = data.split(|c| *c == eol).map(|line| match line {
+        [line @ .., b'\r'] if !from0 => line,
+        line => line,
+    });
+    for (lineno, line) in lines.enumerate() {
+        let words: Vec<&[u8]> = if word_split {
+            line.split(u8::is_ascii_whitespace)
+                .filter(|word| !word.is_empty())
+                .collect()
+        } else if line.is_empty() || line.starts_with(b"#") || line.starts_with(b";") {
+            continue;
+        } else {
+            vec![line]
+        };
+
+        for word in words {
+            let rule = parse_filter_line(word, format).with_context(|| {
+                format!("in filter file {} line {}", path.display(), lineno + 1)
+            })?;
+            rules.push(rule);
+        }
+    }
+    Ok(rules)
+}
+
+fn parse_filter_line(line: &[u8], format: FileFormat) -> Result<Rule> {
+    let bare = |kind, pattern: &[u8]| Rule {
+        kind,
+        modifiers: Modifiers::default(),
+        pattern: OsString::from_vec(pattern.to_vec()),
+    };
+    match (format, line) {
+        (FileFormat::Rules, line) => Rule::parse(line),
+        (_, b"!") => Ok(bare(RuleKind::Clear, b"")),
+        (FileFormat::Patterns(_), [b'+', b' ', pattern @ ..]) => {
+            Ok(bare(RuleKind::Include, pattern))
+        }
+        (FileFormat::Patterns(_), [b'-', b' ', pattern @ ..]) => {
+            Ok(bare(RuleKind::Exclude, pattern))
+        }
+        (FileFormat::Patterns(kind), pattern) => Ok(bare(kind, pattern)),
+        (FileFormat::Cvs, pattern) => Ok(bare(RuleKind::Exclude, pattern)),
+    }
+}
+
+impl<R: AsyncRead + Unpin + Send, W: AsyncWrite + Unpin + Send> EnvelopedConn<R, W> {
+    /// Send the rules affecting the sending side to the server.
+    pub async fn send_filter_rules(&mut self, rules: &[Rule]) -> Result<()> {
+        for rule in rules.iter().filter(|rule| rule.applies_to_sender()) {
+            if rule.kind == RuleKind::Merge {
+                // The file is ours, `expand_merge_rules` should have replaced the rule.
+                warn!("merge rule not sent: {}", rule);
+                continue;
+            }
+            let cmd = rule.to_wire(self.protocol.version)?;
             self.tx.write_i32_le(cmd.len() as i32).await?;
-            // TODO unix only
-            self.tx.write_all(cmd.as_bytes()).await?;
+            self.tx.write_all(&cmd).await?;
         }
         self.tx.write_i32_le(EXCLUSION_LIST_END).await?;
+        self.tx.flush().await?;
         Ok(())
     }
+
+    /// Server side of `send_filter_rules`. Rules we don't understand are skipped.
+    pub async fn recv_filter_rules(&mut self) -> Result<Vec<Rule>> {
+        const MAX_RULE_LEN: i32 = 4096;
+
+        let mut rules = vec![];
+        loop {
+            let len = self.rx.read_i32_le().await?;
+            if len == EXCLUSION_LIST_END {
+                break;
+            }
+            ensure!(
+                (1..=MAX_RULE_LEN).contains(&len),
+                "invalid filter rule length {}",
+                len
+            );
+
+            let mut cmd = vec![0; len as usize];
+            self.rx.read_exact(&mut cmd).await?;
+            match Rule::parse_wire(&cmd, self.protocol.version) {
+                Ok(rule) => rules.push(rule),
+                Err(e) => warn!("unsupported filter rule: {}", e),
+            }
+        }
+        Ok(rules)
+    }
+
+    /// Send `--files-from=-` names, after the filter rules. Each ends with a NUL, and an empty one
+    /// ends the list, so the server must be told `--from0`.
+    pub async fn send_files_from(&mut self, names: &[Vec<u8>]) -> Result<()> {
+        for name in names {
+            ensure!(
+                !name.contains(&0),
+                "file name contains NUL: {}",
+                String::from_utf8_lossy(name)
+            );
+            self.tx.write_all(name).await?;
+            self.tx.write_u8(0).await?;
+        }
+        self.tx.write_u8(0).await?;
+        self.tx.flush().await?;
+        Ok(())
+    }
+
+    /// Server side of `send_files_from`. Without `from0`, names end with a newline.
+    pub async fn recv_files_from(&mut self, from0: bool) -> Result<Vec<Vec<u8>>> {
+        const MAX_NAME_LEN: usize = 4096;
+
+        let mut names = vec![];
+        let mut name = vec![];
+        loop {
+            let c = self.rx.read_u8().await?;
+            let end_of_name = if from0 {
+                c == 0
+            } else {
+                matches!(c, b'\n' | b'\r')
+            };
+            if !end_of_name {
+                ensure!(name.len() < MAX_NAME_LEN, "files-from name too long");
+                name.push(c);
+            } else if name.is_empty() {
+                break;
+            } else {
+                names.push(std::mem::take(&mut name));
+            }
+        }
+        Ok(names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rule_round_trip() {
+        let cases = [
+            ("- *.o", "- *.o"),
+            ("exclude *.o", "- *.o"),
+            ("include,/ /srv", "+/ /srv"),
+            ("-! */", "-! */"),
+            ("- a b", "- a b"),
+            ("hide secret", "-s secret"),
+            ("P /backup/", "-r /backup/"),
+            ("Rx user.*", "+xr user.*"),
+            ("dir-merge,n- .ignore", ":n- .ignore"),
+            ("merge,w+ .include", ".w+ .include"),
+            (".e_rules", ".e rules"),
+            (":C", ":C"),
+            ("-C", "-C"),
+            ("clear", "!"),
+        ];
+        for (text, short) in cases {
+            let rule = Rule::parse(text.as_bytes()).unwrap();
+            assert_eq!(rule.to_bytes(), short.as_bytes(), "{}", text);
+            assert_eq!(Rule::parse(&rule.to_bytes()).unwrap(), rule, "{}", text);
+        }
+    }
+
+    /// Whether `name` is excluded by `rules` on the sending side of a transfer rooted at
+    /// `/srv/data`.
+    fn excluded(rules: &[&str], name: &str, is_dir: bool) -> bool {
+        let rules: Vec<_> = rules
+            .iter()
+            .map(|rule| Rule::parse(rule.as_bytes()).unwrap())
+            .collect();
+        FilterList::new(&rules, Side::Sender, Path::new("/srv/data"))
+            .is_excluded(name.as_bytes(), is_dir)
+    }
+
+    #[test]
+    fn wildcards_and_slashes() {
+        assert!(excluded(&["- a/*/c"], "a/b/c", false));
+        assert!(!excluded(&["- a/*/c"], "a/b/x/c", false));
+        assert!(excluded(&["- a/**/c"], "a/b/c", false));
+        assert!(excluded(&["- a/**/c"], "a/b/x/c", false));
+        assert!(!excluded(&["- a/**/c"], "a/c", false));
+        // Without a slash only the last component is matched.
+        assert!(excluded(&["- *.o"], "x/y.o", false));
+        assert!(!excluded(&["- /*.o"], "x/y.o", false));
+        assert!(excluded(&["- /**.o"], "x/y.o", false));
+        assert!(excluded(&["- ?.c"], "d/a.c", false));
+    }
+
+    #[test]
+    fn trailing_triple_star() {
+        assert!(excluded(&["- /a/***"], "a", true));
+        assert!(excluded(&["- /a/***"], "a/b", false));
+        assert!(excluded(&["- /a/***"], "a/b/c", false));
+        assert!(!excluded(&["- /a/***"], "ab", true));
+        // `/**` needs something below the directory.
+        assert!(!excluded(&["- /a/**"], "a", true));
+    }
+
+    #[test]
+    fn anchoring() {
+        assert!(excluded(&["- /foo"], "foo", false));
+        assert!(!excluded(&["- /foo"], "x/foo", false));
+        assert!(excluded(&["- foo"], "x/foo", false));
+        // Unanchored paths match at any directory boundary.
+        assert!(excluded(&["- x/foo"], "x/foo", false));
+        assert!(excluded(&["- x/foo"], "y/x/foo", false));
+        assert!(!excluded(&["- x/foo"], "yx/foo", false));
+    }
+
+    #[test]
+    fn dir_only_and_negation() {
+        assert!(excluded(&["- build/"], "build", true));
+        assert!(!excluded(&["- build/"], "build", false));
+        assert!(excluded(&["- build/"], "src/build", true));
+        assert!(excluded(&["-! *.txt"], "a.c", false));
+        assert!(!excluded(&["-! *.txt"], "a.txt", false));
+        // Everything but directories.
+        assert!(excluded(&["-! */"], "a", false));
+        assert!(!excluded(&["-! */"], "a", true));
+        assert!(excluded(&["-! /a/"], "b", true));
+        assert!(!excluded(&["-! /a/"], "a", true));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(excluded(&["- [!a]*"], "bx", false));
+        assert!(!excluded(&["- [!a]*"], "ax", false));
+        assert!(excluded(&["- [^a]*"], "bx", false));
+        assert!(excluded(&["- []x]"], "]", false));
+        assert!(excluded(&["- []x]"], "x", false));
+        assert!(!excluded(&["- []x]"], "y", false));
+        assert!(excluded(&["- [a-c]1"], "b1", false));
+        assert!(!excluded(&["- [a-c]1"], "d1", false));
+        // Classes never match a slash.
+        assert!(!excluded(&["- /a[!x]b"], "a/b", false));
+    }
+
+    #[test]
+    fn absolute_modifier() {
+        assert!(excluded(&["-/ /srv/data/*.tmp"], "x.tmp", false));
+        assert!(!excluded(&["-/ /srv/data/*.tmp"], "d/x.tmp", false));
+        assert!(excluded(&["-/ /srv/**.tmp"], "d/x.tmp", false));
+        assert!(!excluded(&["-/ /other/*.tmp"], "x.tmp", false));
+        // Without it, the pattern is anchored at the transfer root.
+        assert!(!excluded(&["- /srv/data/*.tmp"], "x.tmp", false));
+    }
+
+    #[test]
+    fn first_match_wins() {
+        assert!(!excluded(&["+ keep.o", "- *.o"], "keep.o", false));
+        assert!(excluded(&["+ keep.o", "- *.o"], "x.o", false));
+        assert!(excluded(&["- *.o", "+ keep.o"], "keep.o", false));
+        assert!(!excluded(&["- *.o", "!", "+ x"], "a.o", false));
+        // Includes only matter before a matching exclude.
+        assert!(!excluded(&["+ */", "+ *.c", "- *"], "src", true));
+        assert!(excluded(&["+ */", "+ *.c", "- *"], "src/a.h", false));
+        assert!(!excluded(&["+ */", "+ *.c", "- *"], "src/a.c", false));
+        // The transfer root is never filtered.
+        assert!(!excluded(&["- *"], ".", true));
+    }
+
+    #[test]
+    fn xattr_rules() {
+        let filter = |rules: &[&str]| {
+            let rules: Vec<_> = rules
+                .iter()
+                .map(|rule| Rule::parse(rule.as_bytes()).unwrap())
+                .collect();
+            XattrFilter::new(&rules)
+        };
+        let xattrs = filter(&["-x user.secret*", "+x user.keep", "- *", "-x user.*"]);
+        assert!(xattrs.is_excluded(b"user.secret.key"));
+        assert!(!xattrs.is_excluded(b"user.keep"));
+        assert!(xattrs.is_excluded(b"user.other"));
+        // Only `x` rules apply.
+        assert!(!xattrs.is_excluded(b"security.selinux"));
+
+        let xattrs = filter(&["-x user.*", "!", "+x user.a", "-x *"]);
+        assert!(!xattrs.is_excluded(b"user.a"));
+        assert!(xattrs.is_excluded(b"user.b"));
+        assert!(!filter(&[]).is_excluded(b"user.b"));
+    }
+
+    fn load(data: &[u8], format: FileFormat, from0: bool) -> Result<Vec<String>> {
+        let rules = parse_filter_file(data, Path::new("list"), format, from0, false)?;
+        Ok(rules.iter().map(ToString::to_string).collect())
+    }
+
+    #[test]
+    fn pattern_files() {
+        let data = b"# comment\n; comment\n\n*.o\r\n+ keep.o\n- /tmp/\n+core";
+        assert_eq!(
+            load(data, FileFormat::Patterns(RuleKind::Exclude), false).unwrap(),
+            ["- *.o", "+ keep.o", "- /tmp/", "- +core"]
+        );
+        assert_eq!(
+            load(data, FileFormat::Patterns(RuleKind::Include), false).unwrap(),
+            ["+ *.o", "+ keep.o", "- /tmp/", "+ +core"]
+        );
+        assert_eq!(
+            load(
+                b"a b\0+ c\r\0\0# comment\0",
+                FileFormat::Patterns(RuleKind::Exclude),
+                true
+            )
+            .unwrap(),
+            ["- a b", "+ c\r"]
+        );
+    }
+
+    #[test]
+    fn rule_files() {
+        let data = b"- *.o\r\n\r\n# comment\r\nmerge,- .excludes\r\n";
+        assert_eq!(
+            load(data, FileFormat::Rules, false).unwrap(),
+            ["- *.o", ".- .excludes"]
+        );
+
+        let err = load(b"- *.o\r\n\r\nbogus x\r\n", FileFormat::Rules, false).unwrap_err();
+        assert_eq!(err.to_string(), "in filter file list line 3");
+        let err = load(b"- a\0\0- b\0bogus x", FileFormat::Rules, true).unwrap_err();
+        assert_eq!(err.to_string(), "in filter file list line 4");
+    }
+
+    /// A tree with `.rules` files at the top and in `sub`, the empty one in `sub/clear` drops
+    /// the others.
+    async fn dir_merge(rule: &str) -> FilterList {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(root.path().join("sub/clear")).unwrap();
+        std::fs::write(root.path().join(".rules"), "- *.o\n- /top\n").unwrap();
+        std::fs::write(root.path().join("sub/.rules"), "+ keep.o\n- /x\n").unwrap();
+        std::fs::write(root.path().join("sub/clear/.rules"), "!\n").unwrap();
+
+        let rules = [Rule::parse(rule.as_bytes()).unwrap()];
+        let mut filters = FilterList::new(&rules, Side::Sender, Path::new("/srv/data"));
+        for dir in [".", "sub", "sub/clear"] {
+            filters
+                .enter_dir(dir.as_bytes(), root.path())
+                .await
+                .unwrap();
+        }
+        filters
+    }
+
+    #[tokio::test]
+    async fn dir_merge_files() {
+        let filters = dir_merge(": .rules").await;
+        assert!(filters.is_excluded(b"a.o", false));
+        assert!(filters.is_excluded(b"top", false));
+        // Patterns are relative to the directory of their file, which is searched first.
+        assert!(filters.is_excluded(b"sub/x", false));
+        assert!(!filters.is_excluded(b"x", false));
+        assert!(!filters.is_excluded(b"sub/top", false));
+        assert!(!filters.is_excluded(b"sub/keep.o", false));
+        assert!(filters.is_excluded(b"sub/a.o", false));
+        assert!(!filters.is_excluded(b"sub/clear/a.o", false));
+        // The merge files themselves are sent.
+        assert!(!filters.is_excluded(b".rules", false));
+        assert!(!filters.is_excluded(b"sub/.rules", false));
+    }
+
+    #[tokio::test]
+    async fn dir_merge_modifiers() {
+        let filters = dir_merge(":n .rules").await;
+        assert!(filters.is_excluded(b"a.o", false));
+        assert!(filters.is_excluded(b"sub/x", false));
+        assert!(!filters.is_excluded(b"sub/a.o", false));
+        assert!(!filters.is_excluded(b"sub/clear/x", false));
+
+        let filters = dir_merge(":e .rules").await;
+        assert!(filters.is_excluded(b".rules", false));
+        assert!(filters.is_excluded(b"sub/.rules", false));
+        assert!(filters.is_excluded(b"sub/a.o", false));
+        // The file in `sub/clear` only has its own exclusion left.
+        assert!(filters.is_excluded(b"sub/clear/.rules", false));
+        assert!(!filters.is_excluded(b"sub/clear/a.o", false));
+    }
 }
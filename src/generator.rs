@@ -1,82 +1,165 @@
 use std::cmp::min;
-use std::ffi::OsStr;
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::fs::Permissions;
+use std::future::Future;
 use std::ops::{Deref, DerefMut};
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::fs::MetadataExt;
-use std::path::Path;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 
-use eyre::Result;
+use eyre::{Context, Result};
+use filetime::FileTime;
 use tokio::fs;
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::tcp::WriteHalf;
-use tracing::{debug, info};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, info, warn};
 
+use crate::acls::set_acls;
 use crate::chksum::{checksum_1, checksum_2, SumHead};
-use crate::file_list::{mod_time_eq, FileEntry};
-use crate::opts::Opts;
+use crate::delete::{DeleteMode, Deleter};
+use crate::envelope::EnvelopeWrite;
+use crate::file_list::{is_device, is_unsafe_symlink, mod_time_eq, FileEntry};
+use crate::filter::XattrFilter;
+use crate::hlink::find_leaders;
+use crate::ndx::{NdxState, ITEM_REPORT_XATTR, ITEM_TRANSFER, NDX_DONE};
+use crate::opts::{Opts, UnsafeLinks};
+use crate::protocol::Protocol;
+use crate::uid_list::{am_root, set_owner};
+use crate::xattrs::{missing_values, send_xattr_request, set_xattrs};
 
-pub struct Generator<'a>(pub WriteHalf<'a>);
+/// File type bits of a mode.
+const S_IFMT: u32 = 0o170000;
 
-impl<'a> Deref for Generator<'a> {
-    type Target = WriteHalf<'a>;
+pub struct Generator<W: AsyncWrite + Unpin + Send> {
+    pub tx: EnvelopeWrite<W>,
+    pub protocol: Protocol,
+    pub ndx: NdxState,
+}
+
+impl<W: AsyncWrite + Unpin + Send> Deref for Generator<W> {
+    type Target = EnvelopeWrite<W>;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.tx
     }
 }
 
-impl<'a> DerefMut for Generator<'a> {
+impl<W: AsyncWrite + Unpin + Send> DerefMut for Generator<W> {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.tx
     }
 }
 
-impl<'a> Generator<'a> {
+impl<W: AsyncWrite + Unpin + Send> Generator<W> {
+    pub fn new(tx: EnvelopeWrite<W>, protocol: Protocol) -> Self {
+        Self {
+            tx,
+            protocol,
+            ndx: NdxState::default(),
+        }
+    }
+
     pub async fn generate_task(
         &mut self,
         seed: i32,
         opts: &Opts,
         file_list: &[FileEntry],
+        mut deleter: Option<&mut Deleter>,
     ) -> Result<()> {
+        let leaders = if opts.hard_links {
+            find_leaders(file_list)
+        } else {
+            HashMap::new()
+        };
+        let xattr_filter = XattrFilter::new(&opts.filters);
         for entry in file_list {
-            self.recv_generator(seed, opts, entry).await?;
+            if leaders.contains_key(&entry.idx) {
+                debug!(name = ?entry.name_lossy(), "hard link, linked after the transfer");
+            } else {
+                self.recv_generator(seed, opts, entry, &xattr_filter)
+                    .await?;
+            }
+
+            if let Some(deleter) = deleter.as_deref_mut() {
+                let during = matches!(deleter.mode(), DeleteMode::During | DeleteMode::Delay);
+                if during && unix_mode::is_dir(entry.mode) {
+                    deleter.delete_in_dir(&entry.name).await?;
+                }
+            }
         }
 
         info!("generate file phase 1");
-        self.write_i32_le(-1).await?;
+        self.write_ndx(NDX_DONE).await?;
 
         // TODO phase 2: re-do failed files
         info!("generate file phase 2");
-        self.write_i32_le(-1).await?;
+        self.write_ndx(NDX_DONE).await?;
+
+        if self.protocol.max_phase() > 1 {
+            // TODO phase 3: delayed updates
+            info!("generate file phase 3");
+            self.write_ndx(NDX_DONE).await?;
+        }
+        self.flush().await?;
 
         info!("generator finish");
         Ok(())
     }
-    async fn recv_generator(&mut self, seed: i32, opts: &Opts, entry: &FileEntry) -> Result<()> {
+    async fn recv_generator(
+        &mut self,
+        seed: i32,
+        opts: &Opts,
+        entry: &FileEntry,
+        xattr_filter: &XattrFilter,
+    ) -> Result<()> {
         let filename = Path::new(OsStr::from_bytes(&entry.name));
         // TODO s3 impl: merge s3 file index and local partial index, compare to s3, and generate missing files.
 
         // NOTE the following impl doesn't consider
         // 1. expect file, but dir exists
-        // 2. permission incorrect
-        // 3. soft links & hardlinks
-        // 4. non regular files
+        if unix_mode::is_symlink(entry.mode) {
+            return match &entry.link_target {
+                Some(target) if opts.links => recv_symlink(opts, filename, target, entry).await,
+                _ => {
+                    debug!(?filename, "skip symlink");
+                    Ok(())
+                }
+            };
+        }
         if unix_mode::is_dir(entry.mode) {
             debug!(?filename, "create dir");
-            fs::create_dir_all(opts.dest.join(filename)).await?;
-            return Ok(());
+            // `create_dir_all` can't create `dest/.` if dest doesn't exist yet.
+            let path = clean_path::clean(opts.dest.join(filename));
+            // Whatever it points to isn't ours to fill, the destination itself excepted.
+            let is_link = fs::symlink_metadata(&path)
+                .await
+                .is_ok_and(|meta| meta.file_type().is_symlink());
+            if is_link && entry.name != b"." {
+                debug!(?filename, "replace symlink with dir");
+                fs::remove_file(&path).await?;
+            }
+            fs::create_dir_all(&path).await?;
+            if opts.perms_of(entry.mode).is_some() {
+                // Its mode may not let us fill it, `touch_up_dirs` sets the real one.
+                let mode = fs::metadata(&path).await?.mode() & 0o7777;
+                if mode & 0o700 != 0o700 {
+                    fs::set_permissions(&path, Permissions::from_mode(mode | 0o700)).await?;
+                }
+            }
+            // Its ACLs go with its mode in `touch_up_dirs`.
+            return self.sync_xattrs(opts, entry, &path, xattr_filter).await;
         }
 
-        // TODO we skip all non-regular files
+        // Only sent with `--devices` and `--specials`.
         if !unix_mode::is_file(entry.mode) {
-            return Ok(());
+            return recv_special(opts, filename, entry).await;
         }
 
         // check if skip file
-        let meta = tokio::fs::metadata(opts.dest.join(filename))
+        let meta = tokio::fs::symlink_metadata(opts.dest.join(filename))
             .await
-            .map(|m| Some(m))
+            .map(Some)
             .or_else(|e| {
                 if e.kind() == std::io::ErrorKind::NotFound {
                     Ok(None)
@@ -85,30 +168,99 @@ impl<'a> Generator<'a> {
                 }
             })?;
         if let Some(meta) = meta {
-            if meta.size() == entry.len && mod_time_eq(meta.modified()?, entry.modify_time) {
-                return Ok(());
+            if meta.file_type().is_symlink() {
+                // The receiver would write through it.
+                debug!(?filename, "replace symlink with file");
+                fs::remove_file(opts.dest.join(filename)).await?;
+            } else if meta.size() == entry.len && mod_time_eq(meta.modified()?, entry.modify_time) {
+                let path = opts.dest.join(filename);
+                set_owner(&path, opts, entry)?;
+                if let Some(mode) = opts.perms_of(entry.mode) {
+                    if meta.mode() & 0o7777 != mode {
+                        debug!(?filename, "fix permissions");
+                        fs::set_permissions(&path, Permissions::from_mode(mode)).await?;
+                    }
+                    if opts.acls {
+                        set_acls(&path, entry, mode)?;
+                    }
+                }
+                return self.sync_xattrs(opts, entry, &path, xattr_filter).await;
             }
         }
 
-        if let Some(f) = File::open(opts.dest.join(filename)).await.ok() {
+        // Long xattr values the basis file doesn't have come with the data.
+        let missing = if opts.xattrs {
+            missing_values(&opts.dest.join(filename), entry, xattr_filter)?
+        } else {
+            vec![]
+        };
+        if let Ok(f) = File::open(opts.dest.join(filename)).await {
             info!(?filename, idx = entry.idx, "requesting partial file");
             // incremental mode
-            self.write_i32_le(entry.idx).await?;
-            self.generate_and_send_sums(seed, f).await?;
+            self.write_transfer_request(entry.idx, &missing).await?;
+            self.generate_and_send_sums(seed, f, opts.block_size)
+                .await?;
         } else {
             info!(?filename, idx = entry.idx, "requesting full file");
             // full mode
-            self.write_i32_le(entry.idx).await?;
-            SumHead::default().write_to(&mut self.0).await?;
+            self.write_transfer_request(entry.idx, &missing).await?;
+            SumHead::default().write_to(&mut self.tx).await?;
         }
 
         Ok(())
     }
-    async fn generate_and_send_sums(&mut self, seed: i32, mut file: File) -> Result<()> {
+    /// Give an entry that isn't transferred its xattrs. Long values we don't have are asked for,
+    /// the receiver sets them once they arrive.
+    async fn sync_xattrs(
+        &mut self,
+        opts: &Opts,
+        entry: &FileEntry,
+        path: &Path,
+        xattr_filter: &XattrFilter,
+    ) -> Result<()> {
+        if !opts.xattrs {
+            return Ok(());
+        }
+        let missing = missing_values(path, entry, xattr_filter)?;
+        if missing.is_empty() {
+            return set_xattrs(path, entry, &HashMap::new(), path, xattr_filter);
+        }
+        debug!(name = ?entry.name_lossy(), ?missing, "requesting xattr values");
+        self.write_ndx_and_attrs(entry.idx, ITEM_REPORT_XATTR)
+            .await?;
+        send_xattr_request(&mut self.tx, &missing).await
+    }
+    /// Ask for a file, and for the xattr values in `missing`.
+    async fn write_transfer_request(&mut self, ndx: i32, missing: &[u32]) -> Result<()> {
+        if missing.is_empty() {
+            return self.write_ndx_and_attrs(ndx, ITEM_TRANSFER).await;
+        }
+        self.write_ndx_and_attrs(ndx, ITEM_TRANSFER | ITEM_REPORT_XATTR)
+            .await?;
+        send_xattr_request(&mut self.tx, missing).await
+    }
+    async fn write_ndx(&mut self, ndx: i32) -> Result<()> {
+        self.ndx
+            .write_ndx(&mut self.tx, self.protocol.version, ndx)
+            .await
+    }
+    async fn write_ndx_and_attrs(&mut self, ndx: i32, iflags: u16) -> Result<()> {
+        self.write_ndx(ndx).await?;
+        if self.protocol.version >= 29 {
+            self.write_u16_le(iflags).await?;
+        }
+        Ok(())
+    }
+    async fn generate_and_send_sums(
+        &mut self,
+        seed: i32,
+        mut file: File,
+        block_size: Option<u32>,
+    ) -> Result<()> {
         // TODO unix only
         let file_len = file.metadata().await?.size();
-        let sum_head = SumHead::sum_sizes_sqroot(file_len);
-        sum_head.write_to(&mut self.0).await?;
+        let sum_head = SumHead::sum_sizes_sqroot(file_len, block_size, &self.protocol)?;
+        sum_head.write_to(&mut self.tx).await?;
 
         let mut buf = vec![0u8; sum_head.block_len as usize];
         let mut remaining = file_len;
@@ -119,9 +271,11 @@ impl<'a> Generator<'a> {
             file.read_exact(buf_slice).await?;
 
             let sum1 = checksum_1(buf_slice);
-            let sum2 = checksum_2(seed, buf_slice);
+            let sum2 = checksum_2(seed, buf_slice, &self.protocol);
             self.write_i32_le(sum1 as i32).await?;
-            self.write_all(&sum2).await?;
+            // Only the first `checksum_len` bytes are sent.
+            self.write_all(&sum2[..sum_head.checksum_len as usize])
+                .await?;
 
             remaining -= n1 as u64;
         }
@@ -129,3 +283,159 @@ impl<'a> Generator<'a> {
         Ok(())
     }
 }
+
+/// Give directories their mtimes and permissions once everything inside them is done, deepest
+/// first, so neither writing their contents nor read-only directories get in the way.
+pub async fn touch_up_dirs(opts: &Opts, file_list: &[FileEntry]) -> Result<()> {
+    for entry in file_list.iter().rev() {
+        if !unix_mode::is_dir(entry.mode) {
+            continue;
+        }
+        // TODO unix only
+        let path = clean_path::clean(opts.dest.join(OsStr::from_bytes(&entry.name)));
+        let meta = match fs::metadata(&path).await {
+            Ok(meta) => meta,
+            // Not created, e.g. excluded or in the way of something.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if opts.times && !mod_time_eq(meta.modified()?, entry.modify_time) {
+            filetime::set_file_mtime(&path, FileTime::from_system_time(entry.modify_time))?;
+        }
+        set_owner(&path, opts, entry)?;
+        if let Some(mode) = opts.perms_of(entry.mode) {
+            fs::set_permissions(&path, Permissions::from_mode(mode)).await?;
+            if opts.acls {
+                set_acls(&path, entry, mode)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Point symlink `filename` at `target`, replacing whatever non-directory is there, and give it
+/// the mtime and owner of `entry`.
+async fn recv_symlink(
+    opts: &Opts,
+    filename: &Path,
+    target: &[u8],
+    entry: &FileEntry,
+) -> Result<()> {
+    // TODO unix only
+    if opts.unsafe_links == UnsafeLinks::Skip
+        && is_unsafe_symlink(target, filename.as_os_str().as_bytes())
+    {
+        info!(?filename, "ignoring unsafe symlink");
+        return Ok(());
+    }
+
+    let path = opts.dest.join(filename);
+    let target = Path::new(OsStr::from_bytes(target));
+    match fs::symlink_metadata(&path).await {
+        Ok(meta) if meta.file_type().is_symlink() && fs::read_link(&path).await? == target => {
+            if !opts.times || mod_time_eq(meta.modified()?, entry.modify_time) {
+                return set_owner(&path, opts, entry);
+            }
+        }
+        Ok(meta) if meta.is_dir() => {
+            warn!(?filename, "directory in the way of symlink, skipping");
+            return Ok(());
+        }
+        Ok(_) => {
+            debug!(?filename, ?target, "replace symlink");
+            replace_atomically(&path, |tmp_path| async move {
+                Ok(fs::symlink(target, tmp_path).await?)
+            })
+            .await?;
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            debug!(?filename, ?target, "create symlink");
+            fs::symlink(target, &path).await?;
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    if opts.times {
+        // lutimes, the atime doesn't matter.
+        let modify_time = FileTime::from_system_time(entry.modify_time);
+        filetime::set_symlink_file_times(&path, modify_time, modify_time)?;
+    }
+    set_owner(&path, opts, entry)
+}
+
+/// Create the device, FIFO or socket `filename` like `entry`, replacing whatever non-directory is
+/// there. Devices need root.
+async fn recv_special(opts: &Opts, filename: &Path, entry: &FileEntry) -> Result<()> {
+    if is_device(entry.mode) && !am_root() {
+        warn!(?filename, "skipping device, not running as root");
+        return Ok(());
+    }
+
+    // TODO unix only
+    let path = opts.dest.join(filename);
+    match fs::symlink_metadata(&path).await {
+        Ok(meta) if meta.mode() & S_IFMT == entry.mode & S_IFMT && meta.rdev() == entry.rdev => (),
+        Ok(meta) if meta.is_dir() => {
+            warn!(?filename, "directory in the way of special file, skipping");
+            return Ok(());
+        }
+        Ok(_) => {
+            debug!(?filename, "replace special file");
+            replace_atomically(&path, |tmp_path| async move { mknod(&tmp_path, entry) }).await?;
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            debug!(?filename, "create special file");
+            mknod(&path, entry)?;
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    if opts.times {
+        filetime::set_file_mtime(&path, FileTime::from_system_time(entry.modify_time))?;
+    }
+    set_owner(&path, opts, entry)?;
+    if let Some(mode) = opts.perms_of(entry.mode) {
+        fs::set_permissions(&path, Permissions::from_mode(mode)).await?;
+    }
+    Ok(())
+}
+
+/// Replace the non-directory at `path` in one step, so it never goes missing: `create` makes the
+/// new entry under a temporary name next to it, which is then renamed over it.
+pub async fn replace_atomically<F, Fut>(path: &Path, create: F) -> Result<()>
+where
+    F: FnOnce(PathBuf) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let mut tmp_name = OsStr::new(".~tmp~").to_os_string();
+    tmp_name.push(path.file_name().unwrap_or_default());
+    let tmp_path = path.with_file_name(tmp_name);
+    let _ = fs::remove_file(&tmp_path).await;
+    create(tmp_path.clone()).await?;
+    fs::rename(&tmp_path, path).await?;
+    Ok(())
+}
+
+/// `mknod`, or `mkfifo` for FIFOs. The mode is subject to the umask.
+fn mknod(path: &Path, entry: &FileEntry) -> Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let perms = (entry.mode & 0o7777) as libc::mode_t;
+    // SAFETY: `c_path` is a valid C string.
+    let ret = unsafe {
+        if unix_mode::is_fifo(entry.mode) {
+            libc::mkfifo(c_path.as_ptr(), perms)
+        } else {
+            let file_type = (entry.mode & S_IFMT) as libc::mode_t;
+            libc::mknod(
+                c_path.as_ptr(),
+                file_type | perms,
+                entry.rdev as libc::dev_t,
+            )
+        }
+    };
+    if ret != 0 {
+        let e = std::io::Error::last_os_error();
+        return Err(e).wrap_err_with(|| format!("can't create {}", path.display()));
+    }
+    Ok(())
+}
@@ -0,0 +1,83 @@
+//! Hard links, `-H`: files sharing a device and inode are transferred once, as rsync's hlink.c.
+//! The first of each group in the file list is its leader, the others are linked to it once it
+//! has been received.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+
+use eyre::Result;
+use tokio::fs;
+use tracing::{debug, warn};
+
+use crate::file_list::FileEntry;
+use crate::generator::replace_atomically;
+use crate::opts::Opts;
+
+/// The leader of every follower in `list`, by idx.
+pub fn find_leaders(list: &[FileEntry]) -> HashMap<i32, &FileEntry> {
+    let mut firsts = HashMap::new();
+    let mut leaders = HashMap::new();
+    for entry in list {
+        let Some(dev_ino) = entry.dev_ino.filter(|_| !unix_mode::is_dir(entry.mode)) else {
+            continue;
+        };
+        match firsts.entry(dev_ino) {
+            Entry::Occupied(first) => {
+                leaders.insert(entry.idx, *first.get());
+            }
+            Entry::Vacant(first) => {
+                first.insert(entry);
+            }
+        }
+    }
+    leaders
+}
+
+/// Link every follower to its leader after the transfer, replacing whatever non-directory is in
+/// the way. Followers already linked to it are left alone.
+pub async fn link_followers(opts: &Opts, list: &[FileEntry]) -> Result<()> {
+    if !opts.hard_links {
+        return Ok(());
+    }
+    let leaders = find_leaders(list);
+    for entry in list {
+        let Some(leader) = leaders.get(&entry.idx) else {
+            continue;
+        };
+        // TODO unix only
+        let filename = OsStr::from_bytes(&entry.name);
+        let path = opts.dest.join(filename);
+        let leader_path = opts.dest.join(OsStr::from_bytes(&leader.name));
+        let leader_meta = match fs::symlink_metadata(&leader_path).await {
+            Ok(meta) => meta,
+            // Not received, e.g. an unsafe symlink or a device without root.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                warn!(?filename, "hard link target missing, skipping");
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        match fs::symlink_metadata(&path).await {
+            Ok(meta) if meta.dev() == leader_meta.dev() && meta.ino() == leader_meta.ino() => (),
+            Ok(meta) if meta.is_dir() => {
+                warn!(?filename, "directory in the way of hard link, skipping");
+            }
+            Ok(_) => {
+                debug!(?filename, leader = ?leader.name_lossy(), "replace with hard link");
+                replace_atomically(&path, |tmp_path| async {
+                    Ok(fs::hard_link(&leader_path, tmp_path).await?)
+                })
+                .await?;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!(?filename, leader = ?leader.name_lossy(), "create hard link");
+                fs::hard_link(&leader_path, &path).await?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
@@ -1,43 +1,89 @@
-use std::ffi::OsString;
-use std::path::PathBuf;
+use std::path::Path;
 
-use eyre::{bail, eyre, Context, Result};
+use eyre::{bail, ensure, eyre, Context, Result};
 use scan_fmt::scan_fmt;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
-use tokio::net::tcp::{ReadHalf, WriteHalf};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::io::{ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
 use tracing::{debug, info, instrument, warn};
 use url::Url;
 
-use crate::envelope::{EnvelopeRead, RsyncReadExt};
-use crate::filter::Rule;
-use crate::generator::Generator;
-use crate::opts::Opts;
+use crate::auth::{AuthDigest, AuthError, Credentials, AUTH_DIGESTS};
+use crate::chksum::{StrongHash, CHECKSUM_LIST};
+use crate::cli::Location;
+use crate::delete::{DeleteMode, Deleter};
+use crate::envelope::{EnvelopeRead, EnvelopeWrite, RsyncReadExt, RsyncWriteExt};
+use crate::file_list::scan_file_list;
+use crate::filter::{FilterList, Side};
+use crate::generator::{touch_up_dirs, Generator};
+use crate::hlink::link_followers;
+use crate::ndx::NDX_DONE;
+use crate::opts::{Opts, UnsafeLinks};
+use crate::protocol::{Protocol, CLIENT_INFO, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
 use crate::recv::Receiver;
+use crate::rsh::RemoteShell;
+use crate::sender::Sender;
 
+mod acls;
+mod auth;
 mod chksum;
+mod chmod;
+mod cli;
+mod daemon;
+mod delete;
 mod envelope;
 mod file_list;
 mod filter;
 mod generator;
+mod hlink;
+mod module_list;
+mod ndx;
 mod opts;
+mod protocol;
 mod recv;
+mod rsh;
+mod sender;
 mod uid_list;
+mod xattrs;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    println!("Hello, world!");
     tracing_subscriber::fmt::init();
 
-    let opts = Opts {
-        dest: PathBuf::from("./dest"),
-        filters: vec![Rule::Exclude(OsString::from("*.pyc"))],
+    let args = cli::Args::parse(std::env::args().skip(1)).await?;
+    let mut opts = args.opts;
+    opts.filters = filter::expand_merge_rules(&opts.filters, false).await?;
+    let shell = |host: String| RemoteShell {
+        rsh: args.rsh.clone(),
+        host: Some(host),
+        rsync_path: args.rsync_path.clone(),
     };
-
-    let url = Url::parse("rsync://127.0.0.1/pysjtu/")?;
-    // let url = Url::parse("rsync://mirrors.kernel.org/debian-cd/")?;
-    // let url = Url::parse("rsync://rsync.deepin.com/deepin/")?;
-    start_socket_client(url, &opts).await?;
+    match (args.src, args.dest) {
+        (Location::Daemon(url), _) => start_socket_client(url, &opts).await?,
+        (Location::Shell { host, path }, _) => {
+            rsh::start_rsh_client(&shell(host), &path, &opts).await?
+        }
+        (Location::Local(src), Some(Location::Daemon(url))) => {
+            start_socket_sender(url, &src, &opts).await?
+        }
+        (Location::Local(src), Some(Location::Shell { host, path })) => {
+            rsh::start_rsh_sender(&shell(host), &src, &path, &opts).await?
+        }
+        // Rejected by `Args::parse`.
+        (Location::Local(_), _) => unreachable!("local source without remote destination"),
+    }
+    // let config = daemon::DaemonConfig {
+    //     modules: vec![daemon::Module {
+    //         name: String::from("pysjtu"),
+    //         path: PathBuf::from("/srv/pysjtu"),
+    //         comment: String::from("pysjtu mirror"),
+    //         read_only: true,
+    //         incoming_chmod: None,
+    //         outgoing_chmod: None,
+    //     }],
+    //     motd: None,
+    // };
+    // daemon::start_daemon("0.0.0.0:873", config).await?;
 
     Ok(())
 }
@@ -45,109 +91,457 @@ async fn main() -> Result<()> {
 async fn start_socket_client(url: Url, opts: &Opts) -> Result<()> {
     let port = url.port().unwrap_or(873);
     let path = url.path().trim_start_matches('/');
-    let module = path.split('/').next().unwrap_or("must have module");
+    let module = path.split('/').next().unwrap_or_default();
 
     let mut stream = TcpStream::connect(format!("{}:{}", url.host_str().expect("has host"), port))
         .await
         .expect("connect success");
 
-    let mut conn = Conn::new(&mut stream);
-    conn.start_inband_exchange(module, path).await?;
+    let (rx, tx) = stream.split();
+    let mut conn = Conn::new(rx, tx);
+    if module.is_empty() {
+        let list = conn.list_modules().await?;
+        for line in list.motd {
+            println!("{}", line);
+        }
+        for module in list.modules {
+            println!("{:<15}\t{}", module.name, module.comment);
+        }
+        return Ok(());
+    }
+    conn.start_inband_exchange(module, path, url.username(), opts, Role::Receiver)
+        .await?;
+
+    run_transfer(conn, opts).await
+}
+
+/// Push the contents of `src` to the daemon module path in `url`.
+async fn start_socket_sender(url: Url, src: &Path, opts: &Opts) -> Result<()> {
+    let port = url.port().unwrap_or(873);
+    let path = url.path().trim_start_matches('/');
+    let module = path.split('/').next().unwrap_or_default();
+    ensure!(!module.is_empty(), "no module to upload to");
 
-    let (seed, mut enveloped_conn) = conn.handshake_done(&opts.filters).await?;
-    let file_list = enveloped_conn.recv_file_list().await?;
+    let mut stream = TcpStream::connect(format!("{}:{}", url.host_str().expect("has host"), port))
+        .await
+        .expect("connect success");
+
+    let (rx, tx) = stream.split();
+    let mut conn = Conn::new(rx, tx);
+    conn.start_inband_exchange(module, path, url.username(), opts, Role::Sender)
+        .await?;
+
+    run_upload(conn, src, opts).await
+}
+
+/// Pull files once the server has been told what to send.
+async fn run_transfer<R: AsyncRead + Unpin + Send, W: AsyncWrite + Unpin + Send>(
+    conn: Conn<R, W>,
+    opts: &Opts,
+) -> Result<()> {
+    let (seed, mut enveloped_conn) = conn.handshake_done(opts.checksum_choice).await?;
+    opts.list_options()
+        .check_protocol(enveloped_conn.protocol.version)?;
+    if let Some(sink) = &opts.messages {
+        enveloped_conn.rx.set_sink(sink.clone());
+    }
+    enveloped_conn.send_filter_rules(&opts.filters).await?;
+    if let Some(path) = &opts.files_from {
+        let names = filter::load_files_from(path, opts.from0).await?;
+        info!(names = names.len(), "files from");
+        enveloped_conn.send_files_from(&names).await?;
+    }
+    let protocol = enveloped_conn.protocol;
+    let (mut file_list, id_names, io_errors) =
+        enveloped_conn.recv_file_list(opts.list_options()).await?;
+    // Older servers report them out of band.
+    let io_errors = io_errors | enveloped_conn.rx.io_error();
+    uid_list::map_ids(
+        &mut file_list,
+        &id_names,
+        opts.list_options().ids,
+        opts.usermap.as_ref(),
+        opts.groupmap.as_ref(),
+    );
+    // Servers may not honour every rule we sent, and our own dir-merge files apply too.
+    FilterList::new(&opts.filters, Side::Sender, &opts.dest)
+        .retain(&mut file_list, &opts.dest)
+        .await?;
     info!(files = file_list.len(), "file list");
 
-    // enveloped_conn.consume_uid_mapping().await?;
-    let io_errors = enveloped_conn.rx.read_i32_le().await?;
     if io_errors != 0 {
         warn!("server reported IO errors: {}", io_errors);
     }
 
-    let mut generator = Generator(enveloped_conn.tx);
-    let mut receiver = Receiver(enveloped_conn.rx);
+    let mut deleter = match opts.delete {
+        // Like rsync, don't delete what the server may just have failed to list.
+        Some(_) if io_errors != 0 => {
+            warn!("IO error encountered -- skipping file deletion");
+            None
+        }
+        Some(_) if opts.files_from.is_some() => {
+            warn!("no directory contents are sent with files-from, skipping file deletion");
+            None
+        }
+        Some(mode) => Some(Deleter::new(mode, opts, &file_list)),
+        None => None,
+    };
+    if let Some(deleter) = &mut deleter {
+        if deleter.mode() == DeleteMode::Before {
+            deleter.delete_all(&file_list).await?;
+        }
+    }
+
+    let mut generator = Generator::new(enveloped_conn.tx, protocol);
+    let mut receiver = Receiver::new(enveloped_conn.rx, protocol);
     // Do not receiver on generator error?
     tokio::try_join!(
-        generator.generate_task(seed, opts, &file_list),
+        generator.generate_task(seed, opts, &file_list, deleter.as_mut()),
         receiver.recv_task(seed, opts, &file_list),
     )?;
 
-    let Generator(mut tx) = generator;
-    let Receiver(mut rx) = receiver;
-
-    let read = rx.read_rsync_long().await?;
-    let written = rx.read_rsync_long().await?;
-    let size = rx.read_rsync_long().await?;
+    if let Some(deleter) = &mut deleter {
+        deleter.finish(&file_list, receiver.rx.io_error()).await?;
+    }
+    link_followers(opts, &file_list).await?;
+    touch_up_dirs(opts, &file_list).await?;
+
+    let Generator {
+        mut tx, mut ndx, ..
+    } = generator;
+    let Receiver {
+        mut rx,
+        ndx: mut rx_ndx,
+        ..
+    } = receiver;
+
+    let version = protocol.version;
+    let read = rx.read_varlong30(version, 3).await?;
+    let written = rx.read_varlong30(version, 3).await?;
+    let size = rx.read_varlong30(version, 3).await?;
+    if version >= 29 {
+        let flist_buildtime = rx.read_varlong30(version, 3).await?;
+        let flist_xfertime = rx.read_varlong30(version, 3).await?;
+        debug!(flist_buildtime, flist_xfertime, "file list stats");
+    }
 
     info!(read, written, size, "transfer stats");
 
-    tx.write_i32_le(-1).await?;
+    // Goodbye. Since protocol 31 the server echoes it and waits for another one.
+    ndx.write_ndx(&mut tx, version, NDX_DONE).await?;
+    if version >= 31 {
+        tx.flush().await?;
+        let done = rx_ndx.read_ndx(&mut rx, version).await?;
+        ensure!(done == NDX_DONE, "invalid packet at end of run");
+        ndx.write_ndx(&mut tx, version, NDX_DONE).await?;
+    }
+    tx.shutdown().await?;
+
+    Ok(())
+}
+
+/// Push files once the server has been told where to put them.
+async fn run_upload<R: AsyncRead + Unpin + Send, W: AsyncWrite + Unpin + Send>(
+    conn: Conn<R, W>,
+    src: &Path,
+    opts: &Opts,
+) -> Result<()> {
+    // The receiver only asks for our filter rules when deleting, and we don't support that yet.
+    let (seed, mut enveloped_conn) = conn.handshake_done(opts.checksum_choice).await?;
+    opts.list_options()
+        .check_protocol(enveloped_conn.protocol.version)?;
+    if let Some(sink) = &opts.messages {
+        enveloped_conn.rx.set_sink(sink.clone());
+    }
+    let protocol = enveloped_conn.protocol;
+
+    let mut filter = FilterList::new(&opts.filters, Side::Sender, src);
+    let mut file_list = scan_file_list(
+        src,
+        protocol.version,
+        &mut filter,
+        opts.unsafe_links,
+        opts.list_options(),
+    )
+    .await?;
+    if let Some(chmod) = &opts.chmod {
+        // Like rsync, the sender tweaks the modes it sends.
+        chmod.apply_to_list(&mut file_list);
+    }
+    info!(files = file_list.len(), "file list");
+    enveloped_conn
+        .send_file_list(&file_list, 0, opts.list_options())
+        .await?;
+
+    let mut sender = Sender::new(enveloped_conn.rx, enveloped_conn.tx, protocol);
+    sender.send_task(seed, src, &file_list).await?;
+
+    let Sender {
+        mut rx,
+        mut tx,
+        mut rx_ndx,
+        mut tx_ndx,
+        ..
+    } = sender;
+
+    // The receiving server keeps its stats to itself, and only says goodbye. Since protocol 31 we
+    // echo it and wait for another one.
+    let version = protocol.version;
+    let done = rx_ndx.read_ndx(&mut rx, version).await?;
+    ensure!(done == NDX_DONE, "invalid packet at end of run");
+    if version >= 31 {
+        tx_ndx.write_ndx(&mut tx, version, NDX_DONE).await?;
+        tx.flush().await?;
+        let done = rx_ndx.read_ndx(&mut rx, version).await?;
+        ensure!(done == NDX_DONE, "invalid packet at end of run");
+    }
     tx.shutdown().await?;
 
     Ok(())
 }
 
+/// Which side of the transfer we are. The server takes the other one.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Role {
+    Sender,
+    Receiver,
+}
+
+/// Arguments for the server side, either sent in-band to a daemon or passed to `rsync` on a remote
+/// shell.
+fn server_options(protocol: i32, path: &str, role: Role, opts: &Opts) -> Vec<String> {
+    // TODO daemon args, hardcoded for now. Need to modify file_list parse code if changed.
+    // -l preserve_links -t preserve_times -r recursive -p perms -o owner -g group -H hard links
+    // -A acls -X xattrs
+    let files_from = role == Role::Receiver && opts.files_from.is_some();
+    let mut flags = String::from("-");
+    if opts.links {
+        flags.push('l');
+    }
+    if opts.times {
+        flags.push('t');
+    }
+    if opts.perms || opts.acls {
+        flags.push('p');
+    }
+    if opts.owner {
+        flags.push('o');
+    }
+    if opts.group {
+        flags.push('g');
+    }
+    // -D is both.
+    if opts.devices && opts.specials {
+        flags.push('D');
+    }
+    if opts.hard_links {
+        flags.push('H');
+    }
+    if opts.acls {
+        flags.push('A');
+    }
+    if opts.xattrs {
+        flags.push('X');
+    }
+    if files_from {
+        // Like rsync, listed paths are kept whole (-R relative) and listed directories are sent
+        // without their contents (-d dirs).
+        flags.push_str("Rd");
+    } else {
+        flags.push('r');
+    }
+    if protocol >= 30 {
+        // Capabilities, see `CLIENT_INFO`.
+        flags.push('e');
+        flags.push_str(CLIENT_INFO);
+    }
+
+    let mut options = vec![String::from("--server")];
+    if role == Role::Receiver {
+        options.push(String::from("--sender"));
+    }
+    options.push(flags);
+    if files_from {
+        // We send the list after the filter rules, NUL terminated.
+        options.push(String::from("--files-from=-"));
+        options.push(String::from("--from0"));
+    }
+    // Unsafe links are resolved by the sender and ignored by the receiver, whichever is remote.
+    match (role, opts.unsafe_links) {
+        (Role::Receiver, UnsafeLinks::Copy) => options.push(String::from("--copy-unsafe-links")),
+        (Role::Sender, UnsafeLinks::Skip) => options.push(String::from("--safe-links")),
+        _ => (),
+    }
+    if opts.numeric_ids {
+        options.push(String::from("--numeric-ids"));
+    }
+    if opts.devices != opts.specials {
+        options.push(String::from(if opts.devices {
+            "--devices"
+        } else {
+            "--specials"
+        }));
+    }
+    // Ids are mapped by whoever receives.
+    if role == Role::Sender {
+        if let Some(usermap) = &opts.usermap {
+            options.push(format!("--usermap={}", usermap));
+        }
+        if let Some(groupmap) = &opts.groupmap {
+            options.push(format!("--groupmap={}", groupmap));
+        }
+        // So are block sums generated.
+        if let Some(block_size) = opts.block_size {
+            options.push(format!("--block-size={}", block_size));
+        }
+    }
+    if let Some(checksum) = opts.checksum_choice {
+        options.push(format!("--checksum-choice={}", checksum.name()));
+    }
+    options.push(String::from("."));
+    if !path.is_empty() {
+        options.push(path.to_string());
+    }
+    options
+}
+
 #[derive(Debug)]
-struct EnvelopedConn<'a> {
-    tx: WriteHalf<'a>,
-    rx: EnvelopeRead<BufReader<ReadHalf<'a>>>,
+struct EnvelopedConn<R: AsyncRead + Unpin + Send, W: AsyncWrite + Unpin + Send> {
+    tx: EnvelopeWrite<W>,
+    rx: EnvelopeRead<BufReader<R>>,
+    protocol: Protocol,
 }
 
+#[cfg(test)]
+impl EnvelopedConn<tokio::io::DuplexStream, tokio::io::DuplexStream> {
+    /// A connection reading back what it writes, for wire format round trips.
+    fn loopback(version: i32) -> Self {
+        let (tx, rx) = tokio::io::duplex(1 << 20);
+        Self {
+            tx: EnvelopeWrite::new(tx, false),
+            rx: EnvelopeRead::new(BufReader::new(rx), false),
+            protocol: Protocol::new(version),
+        }
+    }
+}
+
+/// Parse an `@RSYNCD: <version>[.<sub>] [digests...]` greeting. Newer daemons list their auth
+/// digests after the version.
+fn parse_greeting(greeting: &str) -> Result<(i32, Vec<String>)> {
+    let protocol_header = greeting
+        .trim()
+        .strip_prefix("@RSYNCD: ")
+        .ok_or_else(|| eyre!("invalid greeting"))?;
+
+    let mut header_fields = protocol_header.split_whitespace();
+    let version = header_fields.next().unwrap_or_default();
+    let digests = header_fields.map(ToString::to_string).collect();
+
+    let protocol = scan_fmt!(version, "{}.{}", i32, i32)
+        .map(|(protocol, _sub)| protocol)
+        .or_else(|_| scan_fmt!(version, "{}", i32))
+        .context("invalid greeting: no version")?;
+    Ok((protocol, digests))
+}
+
+/// A connection to an rsync daemon over any transport, e.g. a TCP or Unix socket, a TLS stream,
+/// or an in-memory duplex pipe.
 #[derive(Debug)]
-struct Conn<'a> {
-    tx: WriteHalf<'a>,
-    rx: BufReader<ReadHalf<'a>>,
+struct Conn<R: AsyncRead + Unpin + Send, W: AsyncWrite + Unpin + Send> {
+    tx: W,
+    rx: BufReader<R>,
+    /// Negotiated protocol version, valid after the inband exchange.
+    protocol: i32,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Conn<ReadHalf<S>, WriteHalf<S>> {
+    /// Use a bidirectional stream as transport.
+    #[allow(dead_code)]
+    fn from_stream(stream: S) -> Self {
+        let (rx, tx) = tokio::io::split(stream);
+        Self::new(rx, tx)
+    }
 }
 
-impl<'a> Conn<'a> {
-    fn new(stream: &'a mut TcpStream) -> Self {
-        let (rx, tx) = stream.split();
+impl<R: AsyncRead + Unpin + Send, W: AsyncWrite + Unpin + Send> Conn<R, W> {
+    fn new(rx: R, tx: W) -> Self {
         Self {
             tx,
             rx: BufReader::with_capacity(256 * 1024, rx),
+            protocol: PROTOCOL_VERSION,
         }
     }
 
     // TODO all readlines in this module are not safe (no length limit). Can use .take(?).
-    #[instrument(skip(self))]
-    async fn start_inband_exchange(&mut self, module: &str, path: &str) -> Result<()> {
-        info!("start inband exchange");
-
-        self.tx.write_all(b"@RSYNCD: 27.0\n").await?;
+    /// Send our greeting and parse the daemon's. Returns the auth digests offered by the daemon.
+    async fn exchange_versions(&mut self) -> Result<Vec<String>> {
+        self.tx
+            .write_all(format!("@RSYNCD: {}.0 {}\n", PROTOCOL_VERSION, AUTH_DIGESTS).as_bytes())
+            .await?;
 
         let mut greeting = String::new();
         self.rx.read_line(&mut greeting).await?;
         info!(greeting, "greeting");
 
-        let protocol_header = greeting
-            .trim()
-            .strip_prefix("@RSYNCD: ")
-            .ok_or_else(|| eyre!("invalid greeting"))?
-            .to_string();
-
-        let remote_protocol = scan_fmt!(&protocol_header, "{}.{}", i32, i32)
-            .map(|(protocol, _sub)| protocol)
-            .or_else(|_| scan_fmt!(&protocol_header, "{}", i32))
-            .context("invalid greeting: no server version")?;
-
-        if remote_protocol < 27 {
+        let (remote_protocol, daemon_digests) = parse_greeting(&greeting)?;
+        if remote_protocol < MIN_PROTOCOL_VERSION {
             bail!("Server version too old: {}", remote_protocol);
         }
+        self.protocol = remote_protocol.min(PROTOCOL_VERSION);
+
+        info!(
+            remote_protocol,
+            local_protocol = PROTOCOL_VERSION,
+            protocol = self.protocol,
+            "Client Protocol"
+        );
 
-        info!(remote_protocol, local_protocol = 27, "Client Protocol");
+        Ok(daemon_digests)
+    }
+
+    #[instrument(skip(self, opts))]
+    async fn start_inband_exchange(
+        &mut self,
+        module: &str,
+        path: &str,
+        url_user: &str,
+        opts: &Opts,
+        role: Role,
+    ) -> Result<()> {
+        info!("start inband exchange");
+
+        let daemon_digests = self.exchange_versions().await?;
         self.tx
             .write_all(format!("{}\n", module).as_bytes())
             .await?;
 
         // MOTD
+        let mut authenticated = false;
         loop {
             let mut line = String::new();
-            self.rx.read_line(&mut line).await?;
+            if self.rx.read_line(&mut line).await? == 0 {
+                bail!("connection closed during inband exchange");
+            }
 
-            if line.starts_with("@ERROR") {
-                bail!("server error: {}", line);
-            } else if line.starts_with("@RSYNCD: AUTHREQD ") {
-                bail!("server requires authentication");
+            if let Some(msg) = line.strip_prefix("@ERROR") {
+                let msg = msg.trim_start_matches(':').trim();
+                if authenticated && msg.starts_with("auth failed") {
+                    return Err(AuthError::Rejected {
+                        module: module.to_string(),
+                        msg: msg.to_string(),
+                    }
+                    .into());
+                }
+                bail!("server error: {}", msg);
+            } else if let Some(challenge) = line.strip_prefix("@RSYNCD: AUTHREQD ") {
+                let credentials = Credentials::resolve(url_user, module, opts)?;
+                info!(user = credentials.user, "authenticating");
+                let digest = AuthDigest::negotiate(self.protocol, &daemon_digests)?;
+                let response = credentials.response(challenge.trim_end(), digest);
+                self.tx
+                    .write_all(format!("{}\n", response).as_bytes())
+                    .await?;
+                authenticated = true;
             } else if line.starts_with("@RSYNCD: OK") {
                 break;
             } else {
@@ -155,37 +549,106 @@ impl<'a> Conn<'a> {
             }
         }
 
-        // TODO daemon args, hardcoded for now. Need to modify file_list parse code if changed.
-        // TODO preserve_hard_link is commented out in go rsync, why?
-        // -l preserve_links -t preserve_times -r recursive -p perms
-        const SERVER_OPTIONS: [&str; 4] = ["--server", "--sender", "-ltpr", "."];
-        for opt in SERVER_OPTIONS {
+        // Since protocol 30 arguments are null terminated, so they may contain newlines.
+        let eol = if self.protocol >= 30 { b'\0' } else { b'\n' };
+        for opt in server_options(self.protocol, path, role, opts) {
             debug!(opt, "server option");
-            self.tx.write_all(format!("{}\n", opt).as_bytes()).await?;
-        }
-        if path != "" {
-            debug!(path, "server option");
-            self.tx.write_all(format!("{}\n", path).as_bytes()).await?;
+            self.tx.write_all(opt.as_bytes()).await?;
+            self.tx.write_u8(eol).await?;
         }
-        self.tx.write_all(b"\n").await?;
+        self.tx.write_u8(eol).await?;
         debug!("options done");
 
         Ok(())
     }
 
     #[instrument(skip(self))]
-    async fn handshake_done(mut self, rules: &[Rule]) -> Result<(i32, EnvelopedConn<'a>)> {
+    async fn handshake_done(
+        mut self,
+        checksum_choice: Option<StrongHash>,
+    ) -> Result<(i32, EnvelopedConn<R, W>)> {
+        let mut protocol = Protocol::new(self.protocol);
+        if protocol.version >= 30 {
+            protocol.compat_flags = self.rx.read_varint().await? as u32;
+            debug!(compat_flags = protocol.compat_flags);
+        }
+        // With `--checksum-choice` the server was told already. Otherwise both sides send their
+        // list before reading the other's.
+        protocol.checksum = match checksum_choice {
+            Some(checksum) => checksum,
+            None if protocol.negotiated_strings() => {
+                self.tx.write_vstring(CHECKSUM_LIST.as_bytes()).await?;
+                self.tx.flush().await?;
+                let server_list = self.rx.read_vstring().await?;
+                StrongHash::negotiate(CHECKSUM_LIST.as_bytes(), &server_list)?
+            }
+            None => protocol.checksum,
+        };
+        debug!(checksum = protocol.checksum.name());
+
         let seed = self.rx.read_i32_le().await?;
         debug!(seed);
 
-        self.send_filter_rules(rules).await?;
+        // Since protocol 30 our side is multiplexed too.
+        let conn = EnvelopedConn {
+            tx: EnvelopeWrite::new(self.tx, protocol.version >= 30),
+            rx: EnvelopeRead::new(self.rx, true),
+            protocol,
+        };
+
+        Ok((seed, conn))
+    }
+}
 
-        Ok((
-            seed,
-            EnvelopedConn {
-                tx: self.tx,
-                rx: EnvelopeRead::new(self.rx),
-            },
-        ))
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use tokio::io::duplex;
+
+    use super::*;
+    use crate::chmod::Chmod;
+    use crate::daemon::{DaemonConfig, Module};
+
+    #[tokio::test]
+    async fn pull_over_duplex() {
+        let src = tempfile::tempdir().unwrap();
+        let dest = tempfile::tempdir().unwrap();
+        std::fs::create_dir(src.path().join("dir")).unwrap();
+        std::fs::write(src.path().join("dir/file"), b"hello").unwrap();
+        let config = DaemonConfig {
+            modules: vec![Module {
+                name: String::from("m"),
+                path: src.path().to_path_buf(),
+                comment: String::new(),
+                read_only: true,
+                incoming_chmod: None,
+                outgoing_chmod: Some(Chmod::parse("F640").unwrap()),
+            }],
+            motd: None,
+        };
+        let opts = Opts {
+            dest: dest.path().to_path_buf(),
+            perms: true,
+            ..Opts::default()
+        };
+
+        let (client, server) = duplex(64 * 1024);
+        let server = Conn::from_stream(server).serve(&config);
+        let client = async {
+            let mut conn = Conn::from_stream(client);
+            conn.start_inband_exchange("m", "m/", "", &opts, Role::Receiver)
+                .await?;
+            run_transfer(conn, &opts).await
+        };
+        let (served, pulled) = tokio::join!(server, client);
+        served.unwrap();
+        pulled.unwrap();
+        assert_eq!(
+            std::fs::read(dest.path().join("dir/file")).unwrap(),
+            b"hello"
+        );
+        let meta = std::fs::metadata(dest.path().join("dir/file")).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o640);
     }
 }
@@ -0,0 +1,121 @@
+//! Module listing, requested by sending an empty module name.
+
+use eyre::{bail, Result};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt};
+use tracing::info;
+
+use crate::Conn;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ModuleInfo {
+    pub name: String,
+    pub comment: String,
+}
+
+/// What the daemon replies to an empty module name.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ModuleList {
+    /// Message of the day, one entry per line.
+    pub motd: Vec<String>,
+    pub modules: Vec<ModuleInfo>,
+}
+
+impl<R: AsyncRead + Unpin + Send, W: AsyncWrite + Unpin + Send> Conn<R, W> {
+    /// List the modules the daemon advertises. The connection can't be used afterwards.
+    pub async fn list_modules(&mut self) -> Result<ModuleList> {
+        info!("list modules");
+
+        self.exchange_versions().await?;
+        self.tx.write_all(b"\n").await?;
+
+        let mut list = ModuleList::default();
+        loop {
+            let mut line = String::new();
+            if self.rx.read_line(&mut line).await? == 0 {
+                // Daemons before protocol 25 just close the connection.
+                break;
+            }
+
+            let line = line.trim_end_matches(['\r', '\n']);
+            if let Some(msg) = line.strip_prefix("@ERROR") {
+                bail!("server error: {}", msg.trim_start_matches(':').trim());
+            } else if line.starts_with("@RSYNCD: EXIT") {
+                break;
+            } else if let Some((name, comment)) = line.split_once('\t') {
+                // Names are padded to 15 chars.
+                list.modules.push(ModuleInfo {
+                    name: name.trim_end().to_string(),
+                    comment: comment.to_string(),
+                });
+            } else {
+                list.motd.push(line.to_string());
+            }
+        }
+
+        Ok(list)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, split};
+
+    use super::*;
+
+    /// Lists the modules of a daemon that replies with `reply` and then hangs up.
+    async fn list(reply: &str) -> Result<ModuleList> {
+        let (client, mut server) = duplex(4096);
+        server.write_all(reply.as_bytes()).await?;
+        server.shutdown().await?;
+        let (rx, tx) = split(client);
+        Conn::new(rx, tx).list_modules().await
+    }
+
+    fn module(name: &str, comment: &str) -> ModuleInfo {
+        ModuleInfo {
+            name: name.to_string(),
+            comment: comment.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn modules_and_motd() {
+        let list = list(concat!(
+            "@RSYNCD: 31.0\n",
+            "Welcome to the mirror\n",
+            "\n",
+            "pysjtu         \tPyPI mirror\n",
+            "empty          \t\n",
+            "debian         \tDebian\r\n",
+            "@RSYNCD: EXIT\n",
+            "late           \tafter exit\n",
+        ))
+        .await
+        .unwrap();
+        assert_eq!(list.motd, ["Welcome to the mirror", ""]);
+        assert_eq!(
+            list.modules,
+            [
+                module("pysjtu", "PyPI mirror"),
+                module("empty", ""),
+                module("debian", "Debian")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn hang_up_without_exit() {
+        let list = list("@RSYNCD: 29.0\nftp            \tFTP area\n")
+            .await
+            .unwrap();
+        assert_eq!(list.modules, [module("ftp", "FTP area")]);
+    }
+
+    #[tokio::test]
+    async fn server_error() {
+        let err = list("@RSYNCD: 31.0\n@ERROR: listing denied\n")
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "server error: listing denied");
+    }
+}
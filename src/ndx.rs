@@ -0,0 +1,123 @@
+//! File index encoding.
+//!
+//! Before protocol 30 a file index is a plain i32. Since then it's delta encoded against the
+//! previous index sent in the same direction, so each side keeps its own state.
+
+use eyre::Result;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Marks the end of a phase.
+pub const NDX_DONE: i32 = -1;
+
+// Item flags following the index since protocol 29.
+pub const ITEM_REPORT_XATTR: u16 = 1 << 8;
+pub const ITEM_BASIS_TYPE_FOLLOWS: u16 = 1 << 11;
+pub const ITEM_XNAME_FOLLOWS: u16 = 1 << 12;
+pub const ITEM_TRANSFER: u16 = 1 << 15;
+
+#[derive(Debug, Copy, Clone)]
+pub struct NdxState {
+    prev_positive: i32,
+    prev_negative: i32,
+}
+
+impl Default for NdxState {
+    fn default() -> Self {
+        Self {
+            prev_positive: -1,
+            prev_negative: 1,
+        }
+    }
+}
+
+impl NdxState {
+    pub async fn read_ndx<R: AsyncReadExt + Unpin>(
+        &mut self,
+        rx: &mut R,
+        protocol: i32,
+    ) -> Result<i32> {
+        if protocol < 30 {
+            return Ok(rx.read_i32_le().await?);
+        }
+
+        let mut b = rx.read_u8().await?;
+        let negative = if b == 0xff {
+            b = rx.read_u8().await?;
+            true
+        } else if b == 0 {
+            return Ok(NDX_DONE);
+        } else {
+            false
+        };
+        let prev = if negative {
+            &mut self.prev_negative
+        } else {
+            &mut self.prev_positive
+        };
+
+        let num = if b == 0xfe {
+            let b0 = rx.read_u8().await?;
+            let b1 = rx.read_u8().await?;
+            if b0 & 0x80 != 0 {
+                let b2 = rx.read_u8().await?;
+                let b3 = rx.read_u8().await?;
+                i32::from_le_bytes([b1, b2, b3, b0 & !0x80])
+            } else {
+                ((b0 as i32) << 8) + b1 as i32 + *prev
+            }
+        } else {
+            b as i32 + *prev
+        };
+        *prev = num;
+
+        Ok(if negative { -num } else { num })
+    }
+
+    pub async fn write_ndx<W: AsyncWriteExt + Unpin>(
+        &mut self,
+        tx: &mut W,
+        protocol: i32,
+        ndx: i32,
+    ) -> Result<()> {
+        if protocol < 30 {
+            tx.write_i32_le(ndx).await?;
+            return Ok(());
+        }
+
+        let mut b = Vec::with_capacity(6);
+        let (ndx, diff) = if ndx >= 0 {
+            let diff = ndx - self.prev_positive;
+            self.prev_positive = ndx;
+            (ndx, diff)
+        } else if ndx == NDX_DONE {
+            // Sent as a single zero byte, and doesn't affect the delta state.
+            tx.write_u8(0).await?;
+            return Ok(());
+        } else {
+            b.push(0xff);
+            let ndx = -ndx;
+            let diff = ndx - self.prev_negative;
+            self.prev_negative = ndx;
+            (ndx, diff)
+        };
+
+        // A diff of 1 - 253 is sent as a one-byte diff; a diff of 254 - 32767 or 0 is sent as a
+        // 0xfe + a two-byte diff; otherwise we send 0xfe & all 4 bytes of the num with the high
+        // bit set.
+        if diff > 0 && diff < 0xfe {
+            b.push(diff as u8);
+        } else if !(0..=0x7fff).contains(&diff) {
+            b.push(0xfe);
+            b.push((ndx >> 24) as u8 | 0x80);
+            b.push(ndx as u8);
+            b.push((ndx >> 8) as u8);
+            b.push((ndx >> 16) as u8);
+        } else {
+            b.push(0xfe);
+            b.push((diff >> 8) as u8);
+            b.push(diff as u8);
+        }
+        tx.write_all(&b).await?;
+        Ok(())
+    }
+}
@@ -1,8 +1,106 @@
 use std::path::PathBuf;
 
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::chksum::StrongHash;
+use crate::chmod::Chmod;
+use crate::delete::DeleteMode;
+use crate::envelope::Message;
+use crate::file_list::ListOptions;
 use crate::filter::Rule;
+use crate::uid_list::{IdMap, IdOptions};
+
+/// What to do with symlinks pointing outside of the transfer, see `is_unsafe_symlink`.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum UnsafeLinks {
+    /// Transfer them as they are.
+    #[default]
+    Keep,
+    /// `--safe-links`: the receiver ignores them.
+    Skip,
+    /// `--copy-unsafe-links`: the sender sends what they point to instead.
+    Copy,
+}
 
+#[derive(Default)]
 pub struct Opts {
     pub dest: PathBuf,
     pub filters: Vec<Rule>,
+    /// Only pull the paths listed in this file, relative to the source. `-` reads stdin.
+    pub files_from: Option<PathBuf>,
+    /// `files_from` names end with NUL instead of newlines.
+    pub from0: bool,
+    /// Delete local files the server doesn't have.
+    pub delete: Option<DeleteMode>,
+    /// Stop deleting after this many files.
+    pub max_delete: Option<usize>,
+    /// `-l`, recreate symlinks. They are skipped otherwise.
+    pub links: bool,
+    /// Policy for symlinks pointing outside of the transfer.
+    pub unsafe_links: UnsafeLinks,
+    /// Give received files and directories the sender's permissions.
+    pub perms: bool,
+    /// `-t`, give received entries the sender's mtimes.
+    pub times: bool,
+    /// Tweaks applied to the permissions we send or receive.
+    pub chmod: Option<Chmod>,
+    /// `-o`, give received files the sender's owner, by name. Only works as root.
+    pub owner: bool,
+    /// `-g`, same for the group.
+    pub group: bool,
+    /// Keep the sender's uid and gid as they are instead of matching names.
+    pub numeric_ids: bool,
+    pub usermap: Option<IdMap>,
+    pub groupmap: Option<IdMap>,
+    /// Create block and character devices, as root.
+    pub devices: bool,
+    /// Create FIFOs and sockets.
+    pub specials: bool,
+    /// `-H`, link received files that are hard linked on the server instead of copying each.
+    pub hard_links: bool,
+    /// `-A`, give received files and directories the sender's ACLs. Implies `perms`.
+    pub acls: bool,
+    /// `-X`, same for extended attributes. Only `user.*` ones unless root.
+    pub xattrs: bool,
+    /// `--block-size`, fixed block length for delta transfers instead of one from the file size.
+    pub block_size: Option<u32>,
+    /// `--checksum-choice`, strong checksum to use instead of negotiating one.
+    pub checksum_choice: Option<StrongHash>,
+    /// Daemon user, used when the url doesn't carry one.
+    pub user: Option<String>,
+    /// Daemon password. Takes precedence over `password_file` and `RSYNC_PASSWORD`.
+    pub password: Option<String>,
+    pub password_file: Option<PathBuf>,
+    /// Out-of-band messages from the server are sent here instead of being logged.
+    pub messages: Option<UnboundedSender<Message>>,
+}
+
+impl Opts {
+    pub fn list_options(&self) -> ListOptions {
+        ListOptions {
+            ids: IdOptions {
+                owner: self.owner,
+                group: self.group,
+                acls: self.acls,
+                numeric_ids: self.numeric_ids,
+            },
+            links: self.links,
+            devices: self.devices,
+            specials: self.specials,
+            hard_links: self.hard_links,
+            xattrs: self.xattrs,
+        }
+    }
+
+    /// Permission bits for a received entry of `mode`, if we are to set them.
+    pub fn perms_of(&self, mode: u32) -> Option<u32> {
+        if !self.perms && !self.acls {
+            return None;
+        }
+        let mode = match &self.chmod {
+            Some(chmod) => chmod.apply(mode),
+            None => mode,
+        };
+        Some(mode & 0o7777)
+    }
 }
@@ -0,0 +1,68 @@
+//! Negotiated protocol version, compatibility flags and checksum.
+
+use crate::chksum::StrongHash;
+
+/// The newest protocol version we speak.
+pub const PROTOCOL_VERSION: i32 = 31;
+/// The oldest protocol version we speak.
+pub const MIN_PROTOCOL_VERSION: i32 = 27;
+
+/// Capabilities advertised to the server via `-e` (protocol 30+), see `client_info` in rsync's
+/// compat.c. The leading dot stands for the (absent) pre-release sub-protocol.
+///
+/// f: safe file list end marker, x: no xattr hardlink optimization, C: fixed checksum seed order,
+/// v: varint file list flags and negotiated checksum.
+pub const CLIENT_INFO: &str = ".fxCv";
+
+pub const CF_SAFE_FLIST: u32 = 1 << 3;
+pub const CF_AVOID_XATTR_OPTIM: u32 = 1 << 4;
+pub const CF_CHKSUM_SEED_FIX: u32 = 1 << 5;
+pub const CF_VARINT_FLIST_FLAGS: u32 = 1 << 7;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Protocol {
+    pub version: i32,
+    /// Always 0 before protocol 30.
+    pub compat_flags: u32,
+    /// Strong checksum of block sums and whole files, see `negotiated_strings`.
+    pub checksum: StrongHash,
+}
+
+impl Protocol {
+    pub fn new(version: i32) -> Self {
+        Self {
+            version,
+            compat_flags: 0,
+            checksum: StrongHash::for_protocol(version),
+        }
+    }
+
+    /// Number of transfer phases after the first one. Protocol 29 added a phase for delayed updates.
+    pub fn max_phase(&self) -> i32 {
+        if self.version >= 29 {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// Whether the file list may end with an io error marker instead of a plain zero byte.
+    pub fn safe_flist(&self) -> bool {
+        self.version >= 31 || self.compat_flags & CF_SAFE_FLIST != 0
+    }
+
+    pub fn varint_flist_flags(&self) -> bool {
+        self.compat_flags & CF_VARINT_FLIST_FLAGS != 0
+    }
+
+    /// Whether both sides send their checksum choices after the compat flags, unless
+    /// `--checksum-choice` is given.
+    pub fn negotiated_strings(&self) -> bool {
+        self.compat_flags & CF_VARINT_FLIST_FLAGS != 0
+    }
+
+    /// Whether the checksum seed goes before the data in MD5 block sums.
+    pub fn proper_seed_order(&self) -> bool {
+        self.compat_flags & CF_CHKSUM_SEED_FIX != 0
+    }
+}
@@ -1,85 +1,175 @@
-use std::ffi::OsStr;
-use std::io::SeekFrom;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs::Permissions;
+use std::io::{ErrorKind, SeekFrom};
 use std::ops::{Deref, DerefMut};
 use std::os::unix::ffi::OsStrExt;
-use std::path::Path;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 
-use eyre::{ensure, Result};
+use eyre::{ensure, eyre, Context, Result};
 use filetime::FileTime;
-use md4::{Digest, Md4};
 use tempfile::tempfile;
-use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, BufWriter};
-use tokio::net::tcp::ReadHalf;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, BufWriter};
 use tracing::info;
 
-use crate::chksum::SumHead;
-use crate::envelope::EnvelopeRead;
+use crate::acls::set_acls;
+use crate::chksum::{FileHasher, SumHead};
+use crate::envelope::{EnvelopeRead, RsyncReadExt};
 use crate::file_list::{mod_time_eq, FileEntry};
+use crate::filter::XattrFilter;
+use crate::ndx::{
+    NdxState, ITEM_BASIS_TYPE_FOLLOWS, ITEM_REPORT_XATTR, ITEM_TRANSFER, ITEM_XNAME_FOLLOWS,
+    NDX_DONE,
+};
 use crate::opts::Opts;
+use crate::protocol::Protocol;
+use crate::uid_list::set_owner;
+use crate::xattrs::{recv_xattr_values, set_xattrs};
 
-pub struct Receiver<'a>(pub EnvelopeRead<BufReader<ReadHalf<'a>>>);
+pub struct Receiver<R: AsyncRead + Unpin + Send> {
+    pub rx: EnvelopeRead<BufReader<R>>,
+    pub protocol: Protocol,
+    pub ndx: NdxState,
+}
 
-impl<'a> Deref for Receiver<'a> {
-    type Target = EnvelopeRead<BufReader<ReadHalf<'a>>>;
+impl<R: AsyncRead + Unpin + Send> Deref for Receiver<R> {
+    type Target = EnvelopeRead<BufReader<R>>;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.rx
     }
 }
 
-impl<'a> DerefMut for Receiver<'a> {
+impl<R: AsyncRead + Unpin + Send> DerefMut for Receiver<R> {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.rx
     }
 }
 
-impl<'a> Receiver<'a> {
+impl<R: AsyncRead + Unpin + Send> Receiver<R> {
+    pub fn new(rx: EnvelopeRead<BufReader<R>>, protocol: Protocol) -> Self {
+        Self {
+            rx,
+            protocol,
+            ndx: NdxState::default(),
+        }
+    }
+
     pub async fn recv_task(
         &mut self,
         seed: i32,
         opts: &Opts,
         file_list: &[FileEntry],
     ) -> Result<()> {
+        let xattr_filter = XattrFilter::new(&opts.filters);
         let mut phase = 0;
         loop {
-            let idx = self.read_i32_le().await?;
+            let idx = self
+                .ndx
+                .read_ndx(&mut self.rx, self.protocol.version)
+                .await?;
 
-            if idx == -1 {
-                if phase == 0 {
-                    phase += 1;
-                    info!("recv file phase {}", phase);
-                    continue;
+            if idx == NDX_DONE {
+                phase += 1;
+                if phase > self.protocol.max_phase() {
+                    break;
                 }
-                break;
+                info!("recv file phase {}", phase);
+                continue;
             }
 
-            let entry = &file_list[idx as usize];
-            info!("recv file #{} ({})", idx, entry.name_lossy());
+            // The list may have been filtered locally, so look the entry up by its index.
+            let entry = file_list
+                .binary_search_by_key(&idx, |entry| entry.idx)
+                .map(|pos| &file_list[pos])
+                .map_err(|_| eyre!("invalid file index {} received", idx))?;
             // TODO unix only
-            // TODO s3 impl download file from storage in this step.
             let basis_path = opts.dest.join(Path::new(OsStr::from_bytes(&entry.name)));
-            let basis_file = File::open(&basis_path)
-                .await
-                .map(|f| Some(f))
-                .or_else(|f| {
-                    if f.kind() == std::io::ErrorKind::NotFound {
-                        Ok(None)
-                    } else {
-                        Err(f)
+
+            // Xattr values the generator asked for.
+            let mut xattr_values = HashMap::new();
+            if self.protocol.version >= 29 {
+                let iflags = self.read_u16_le().await?;
+                if iflags & ITEM_BASIS_TYPE_FOLLOWS != 0 {
+                    self.read_u8().await?;
+                }
+                if iflags & ITEM_XNAME_FOLLOWS != 0 {
+                    self.read_vstring().await?;
+                }
+                if iflags & ITEM_REPORT_XATTR != 0 && opts.xattrs {
+                    xattr_values = recv_xattr_values(&mut self.rx).await?;
+                }
+                if iflags & ITEM_TRANSFER == 0 {
+                    if iflags & ITEM_REPORT_XATTR != 0 && opts.xattrs {
+                        set_xattrs(
+                            &basis_path,
+                            entry,
+                            &xattr_values,
+                            &basis_path,
+                            &xattr_filter,
+                        )?;
                     }
-                })?;
+                    continue;
+                }
+            }
+
+            info!("recv file #{} ({})", idx, entry.name_lossy());
+            // TODO s3 impl download file from storage in this step.
+            let basis_file = File::open(&basis_path).await.map(Some).or_else(|f| {
+                if f.kind() == std::io::ErrorKind::NotFound {
+                    Ok(None)
+                } else {
+                    Err(f)
+                }
+            })?;
+
+            // Without perms, a replaced file keeps its mode.
+            let old_mode = match &basis_file {
+                Some(basis_file) => Some(basis_file.metadata().await?.mode() & 0o7777),
+                None => None,
+            };
 
             let mut target_file = BufReader::new(self.recv_data(seed, basis_file).await?);
 
             // TODO s3 impl upload file to storage in this step.
-            let mut dest = BufWriter::new(File::create(&basis_path).await?);
-            tokio::io::copy(&mut target_file, &mut dest).await?;
-            let old_mod_time = tokio::fs::metadata(&basis_path).await?.modified()?;
-            if !mod_time_eq(old_mod_time, entry.modify_time) {
-                filetime::set_file_mtime(&basis_path, FileTime::from_system_time(entry.modify_time))
+            // Written next to the destination and renamed over it, so read-only files and
+            // symlinks are replaced rather than written through.
+            let (tmp_path, tmp_file) = create_tmp_file(&basis_path, entry.mode & 0o777).await?;
+            let written = async {
+                let mut dest = BufWriter::new(tmp_file);
+                tokio::io::copy(&mut target_file, &mut dest).await?;
+                dest.flush().await?;
+                let old_mod_time = tokio::fs::metadata(&tmp_path).await?.modified()?;
+                if opts.times && !mod_time_eq(old_mod_time, entry.modify_time) {
+                    filetime::set_file_mtime(
+                        &tmp_path,
+                        FileTime::from_system_time(entry.modify_time),
+                    )
                     .expect("set mod time")
+                }
+                if opts.xattrs {
+                    // Long values the generator didn't ask for are the basis file's.
+                    set_xattrs(&tmp_path, entry, &xattr_values, &basis_path, &xattr_filter)?;
+                }
+                // Before the mode, chown drops setuid bits.
+                set_owner(&tmp_path, opts, entry)?;
+                if let Some(mode) = opts.perms_of(entry.mode).or(old_mode) {
+                    tokio::fs::set_permissions(&tmp_path, Permissions::from_mode(mode)).await?;
+                    // The mask of an access ACL is the group bits of the mode.
+                    if opts.acls {
+                        set_acls(&tmp_path, entry, mode)?;
+                    }
+                }
+                tokio::fs::rename(&tmp_path, &basis_path).await?;
+                Ok::<_, eyre::Report>(())
             }
+            .await;
+            if written.is_err() {
+                let _ = tokio::fs::remove_file(&tmp_path).await;
+            }
+            written?;
         }
 
         info!("recv finish");
@@ -92,14 +182,13 @@ impl<'a> Receiver<'a> {
             block_len,
             checksum_len: _,
             remainder_len,
-        } = SumHead::read_from(&mut self.0).await?;
+        } = SumHead::read_from(&mut self.rx).await?;
 
         // TODO security fix: this file should not be accessible to other users.
         let mut target_file = File::from_std(tempfile()?);
 
         // Hasher for final file consistency check.
-        let mut hasher = Md4::default();
-        hasher.update(&seed.to_le_bytes());
+        let mut hasher = FileHasher::new(seed, &self.protocol);
 
         let (mut transferred, mut copied) = (0u64, 0u64);
         loop {
@@ -122,7 +211,7 @@ impl<'a> Receiver<'a> {
 
                     let mut buf = vec![0; data_len as usize];
                     let local_basis = local_basis.as_mut().expect("incremental");
-                    local_basis.seek(SeekFrom::Start(offset as u64)).await?;
+                    local_basis.seek(SeekFrom::Start(offset)).await?;
                     local_basis.read_exact(&mut buf).await?;
 
                     hasher.update(&buf);
@@ -136,15 +225,13 @@ impl<'a> Receiver<'a> {
         let mut remote_checksum = vec![0; local_checksum.len()];
 
         self.read_exact(&mut remote_checksum).await?;
-        ensure!(&*local_checksum == remote_checksum, "checksum mismatch");
+        ensure!(local_checksum == remote_checksum, "checksum mismatch");
 
         info!(
             ratio = transferred as f64 / (transferred + copied) as f64,
             "transfer ratio"
         );
 
-        // No need to set perms because we'll upload it to s3.
-
         target_file.seek(SeekFrom::Start(0)).await?;
         Ok(target_file)
     }
@@ -163,6 +250,31 @@ impl<'a> Receiver<'a> {
     }
 }
 
+/// Create a file next to `path` to receive it in, like rsync's `.name.XXXXXX`. `mode` is subject to
+/// the umask.
+async fn create_tmp_file(path: &Path, mode: u32) -> Result<(PathBuf, File)> {
+    let mut attempt = 0;
+    loop {
+        let mut tmp_name = OsString::from(".");
+        tmp_name.push(path.file_name().unwrap_or_default());
+        tmp_name.push(format!(".{}.{}", std::process::id(), attempt));
+        let tmp_path = path.with_file_name(tmp_name);
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(&tmp_path)
+            .await
+        {
+            Ok(file) => return Ok((tmp_path, file)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => attempt += 1,
+            Err(e) => {
+                return Err(e).wrap_err_with(|| format!("can't create {}", tmp_path.display()))
+            }
+        }
+    }
+}
+
 enum FileToken {
     Data(Vec<u8>),
     Copied(u32),
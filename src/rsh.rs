@@ -0,0 +1,177 @@
+//! Remote shell transport: spawn `rsync --server` (possibly through ssh) and talk over its pipes.
+
+use std::path::Path;
+use std::process::{ExitStatus, Stdio};
+
+use eyre::{bail, eyre, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tracing::{debug, info};
+
+use crate::opts::Opts;
+use crate::protocol::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use crate::{run_transfer, run_upload, server_options, Conn, Role};
+
+/// How to start the server: through a remote shell, or directly when there is no host.
+#[derive(Debug, Clone)]
+pub struct RemoteShell {
+    /// The shell with its arguments, e.g. `ssh -p 2222`. Split on whitespace.
+    pub rsh: String,
+    pub host: Option<String>,
+    /// The rsync to run, e.g. `sudo rsync`.
+    pub rsync_path: String,
+}
+
+impl RemoteShell {
+    fn command(&self, server_args: &[String]) -> Command {
+        match &self.host {
+            Some(host) => {
+                let mut rsh = self.rsh.split_whitespace();
+                let mut command = Command::new(rsh.next().unwrap_or("ssh"));
+                command.args(rsh).arg(host);
+                // The remote shell joins its arguments into a command line, so quote ours.
+                let mut line = self.rsync_path.clone();
+                for arg in server_args {
+                    line.push(' ');
+                    line.push_str(&shell_quote(arg));
+                }
+                command.arg(line);
+                command
+            }
+            None => {
+                let mut rsync = self.rsync_path.split_whitespace();
+                let mut command = Command::new(rsync.next().unwrap_or("rsync"));
+                command.args(rsync).args(server_args);
+                command
+            }
+        }
+    }
+}
+
+/// Quote `arg` for a POSIX shell, unless it is made of harmless characters only.
+fn shell_quote(arg: &str) -> String {
+    let harmless = |c: char| c.is_ascii_alphanumeric() || "-_=+.,/:@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(harmless) {
+        return arg.to_string();
+    }
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// Pull `path` from a spawned server.
+pub async fn start_rsh_client(shell: &RemoteShell, path: &str, opts: &Opts) -> Result<()> {
+    // We don't know the remote version yet, so always advertise our capabilities.
+    let args = server_options(PROTOCOL_VERSION, path, Role::Receiver, opts);
+    let (mut child, conn) = spawn_server(shell.command(&args)).await?;
+    run_transfer(conn, opts).await?;
+    wait_server(child.wait().await?)
+}
+
+/// Push the contents of `src` to `path` on a spawned server.
+pub async fn start_rsh_sender(
+    shell: &RemoteShell,
+    src: &Path,
+    path: &str,
+    opts: &Opts,
+) -> Result<()> {
+    let args = server_options(PROTOCOL_VERSION, path, Role::Sender, opts);
+    let (mut child, conn) = spawn_server(shell.command(&args)).await?;
+    run_upload(conn, src, opts).await?;
+    wait_server(child.wait().await?)
+}
+
+async fn spawn_server(mut command: Command) -> Result<(Child, Conn<ChildStdout, ChildStdin>)> {
+    command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true);
+    debug!(?command, "spawn server");
+
+    let mut child = command.spawn()?;
+    let rx = child.stdout.take().expect("piped stdout");
+    let tx = child.stdin.take().expect("piped stdin");
+
+    let mut conn = Conn::new(rx, tx);
+    conn.exchange_binary_versions().await?;
+    Ok((child, conn))
+}
+
+fn wait_server(status: ExitStatus) -> Result<()> {
+    if !status.success() {
+        bail!("server exited with {}", status);
+    }
+    Ok(())
+}
+
+impl<R: AsyncRead + Unpin + Send, W: AsyncWrite + Unpin + Send> Conn<R, W> {
+    /// Version exchange without a daemon: both sides send their version as a plain int.
+    pub async fn exchange_binary_versions(&mut self) -> Result<()> {
+        self.tx.write_i32_le(PROTOCOL_VERSION).await?;
+        self.tx.flush().await?;
+
+        let remote_protocol = self
+            .rx
+            .read_i32_le()
+            .await
+            .map_err(|e| eyre!("can't read server version: {}", e))?;
+        if remote_protocol < MIN_PROTOCOL_VERSION {
+            bail!("Server version too old: {}", remote_protocol);
+        }
+        self.protocol = remote_protocol.min(PROTOCOL_VERSION);
+
+        info!(
+            remote_protocol,
+            local_protocol = PROTOCOL_VERSION,
+            protocol = self.protocol,
+            "Client Protocol"
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn argv(command: &Command) -> Vec<String> {
+        let command = command.as_std();
+        std::iter::once(command.get_program())
+            .chain(command.get_args())
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn quote() {
+        assert_eq!(shell_quote("-logDtpre.iLsfxCIvu"), "-logDtpre.iLsfxCIvu");
+        assert_eq!(shell_quote("mod/dir name"), "'mod/dir name'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote("$(rm -rf ~)"), "'$(rm -rf ~)'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    fn remote_command_line() {
+        let shell = RemoteShell {
+            rsh: String::from("ssh -p 2222"),
+            host: Some(String::from("user@host")),
+            rsync_path: String::from("sudo rsync"),
+        };
+        let args = [".", "a b;c"].map(String::from);
+        assert_eq!(
+            argv(&shell.command(&args)),
+            ["ssh", "-p", "2222", "user@host", "sudo rsync . 'a b;c'"]
+        );
+    }
+
+    #[test]
+    fn local_command_line() {
+        let shell = RemoteShell {
+            rsh: String::from("ssh"),
+            host: None,
+            rsync_path: String::from("rsync"),
+        };
+        let args = ["--server", "a b;c"].map(String::from);
+        assert_eq!(argv(&shell.command(&args)), ["rsync", "--server", "a b;c"]);
+    }
+}
@@ -0,0 +1,475 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use eyre::{ensure, Result};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tracing::{debug, info, warn};
+
+use crate::chksum::{checksum_2, FileHasher, RollingChecksum, SumHead};
+use crate::envelope::{EnvelopeRead, EnvelopeWrite, MsgCode, RsyncReadExt, RsyncWriteExt};
+use crate::file_list::FileEntry;
+use crate::ndx::{
+    NdxState, ITEM_BASIS_TYPE_FOLLOWS, ITEM_REPORT_XATTR, ITEM_TRANSFER, ITEM_XNAME_FOLLOWS,
+    NDX_DONE,
+};
+use crate::protocol::Protocol;
+use crate::xattrs::{recv_xattr_request, send_xattr_values};
+
+/// Item flags of a request, with what follows them.
+struct ItemAttrs {
+    iflags: u16,
+    /// `ITEM_BASIS_TYPE_FOLLOWS`, which file the generator used as basis.
+    basis_type: Option<u8>,
+    /// `ITEM_XNAME_FOLLOWS`, e.g. the name of that basis file.
+    xname: Option<Vec<u8>>,
+}
+
+impl ItemAttrs {
+    /// Before protocol 29 every request is a transfer.
+    fn transfer() -> Self {
+        Self {
+            iflags: ITEM_TRANSFER,
+            basis_type: None,
+            xname: None,
+        }
+    }
+
+    async fn read_from<R: AsyncRead + Unpin + Send>(rx: &mut R) -> Result<Self> {
+        let iflags = rx.read_u16_le().await?;
+        let basis_type = if iflags & ITEM_BASIS_TYPE_FOLLOWS != 0 {
+            Some(rx.read_u8().await?)
+        } else {
+            None
+        };
+        let xname = if iflags & ITEM_XNAME_FOLLOWS != 0 {
+            Some(rx.read_vstring().await?)
+        } else {
+            None
+        };
+        Ok(Self {
+            iflags,
+            basis_type,
+            xname,
+        })
+    }
+}
+
+/// Literal data is sent in chunks of at most this size.
+const CHUNK_SIZE: usize = 32 * 1024;
+
+/// Answers the peer generator's requests with file data, delta encoded against its block sums.
+pub struct Sender<R: AsyncRead + Unpin + Send, W: AsyncWrite + Unpin + Send> {
+    pub rx: EnvelopeRead<BufReader<R>>,
+    pub tx: EnvelopeWrite<W>,
+    pub protocol: Protocol,
+    /// Indices read from the generator.
+    pub rx_ndx: NdxState,
+    /// Indices sent to the receiver.
+    pub tx_ndx: NdxState,
+}
+
+impl<R: AsyncRead + Unpin + Send, W: AsyncWrite + Unpin + Send> Sender<R, W> {
+    pub fn new(rx: EnvelopeRead<BufReader<R>>, tx: EnvelopeWrite<W>, protocol: Protocol) -> Self {
+        Self {
+            rx,
+            tx,
+            protocol,
+            rx_ndx: NdxState::default(),
+            tx_ndx: NdxState::default(),
+        }
+    }
+
+    pub async fn send_task(
+        &mut self,
+        seed: i32,
+        root: &Path,
+        file_list: &[FileEntry],
+    ) -> Result<()> {
+        let mut phase = 0;
+        loop {
+            // Let the receiver work while we wait for the generator.
+            self.tx.flush().await?;
+
+            let idx = self
+                .rx_ndx
+                .read_ndx(&mut self.rx, self.protocol.version)
+                .await?;
+            if idx == NDX_DONE {
+                phase += 1;
+                if phase > self.protocol.max_phase() {
+                    break;
+                }
+                info!("send file phase {}", phase);
+                self.write_ndx(NDX_DONE).await?;
+                continue;
+            }
+
+            let entry = usize::try_from(idx).ok().and_then(|idx| file_list.get(idx));
+            ensure!(entry.is_some(), "invalid file index {} requested", idx);
+            let entry = entry.expect("checked above");
+
+            let attrs = if self.protocol.version >= 29 {
+                ItemAttrs::read_from(&mut self.rx).await?
+            } else {
+                ItemAttrs::transfer()
+            };
+            // Long xattr values the generator doesn't have, sent after the echoed flags.
+            let xattr_request = if attrs.iflags & ITEM_REPORT_XATTR != 0 {
+                Some(recv_xattr_request(&mut self.rx).await?)
+            } else {
+                None
+            };
+            if attrs.iflags & ITEM_TRANSFER == 0 {
+                self.write_ndx_and_attrs(idx, &attrs, entry, xattr_request.as_deref())
+                    .await?;
+                continue;
+            }
+            ensure!(
+                unix_mode::is_file(entry.mode),
+                "invalid file index {} requested",
+                idx
+            );
+
+            let sum_head = SumHead::read_from(&mut self.rx).await?;
+            let sums = self.read_sums(&sum_head).await?;
+
+            // TODO unix only
+            let path = root.join(Path::new(OsStr::from_bytes(&entry.name)));
+            let file = match File::open(&path).await {
+                Ok(file) => file,
+                Err(e) => {
+                    warn!(?path, "can't read file: {}", e);
+                    // Older receivers just never get the file.
+                    if self.protocol.version >= 30 {
+                        self.tx.write_msg_int(MsgCode::NoSend, idx).await?;
+                    }
+                    continue;
+                }
+            };
+            info!("send file #{} ({})", idx, entry.name_lossy());
+
+            self.write_ndx_and_attrs(idx, &attrs, entry, xattr_request.as_deref())
+                .await?;
+            sum_head.write_to(&mut self.tx).await?;
+            self.send_data(seed, file, &sum_head, &sums).await?;
+        }
+
+        self.write_ndx(NDX_DONE).await?;
+        self.tx.flush().await?;
+
+        info!("send finish");
+        Ok(())
+    }
+
+    async fn write_ndx(&mut self, ndx: i32) -> Result<()> {
+        self.tx_ndx
+            .write_ndx(&mut self.tx, self.protocol.version, ndx)
+            .await
+    }
+
+    /// Echo a request to the receiver, like rsync's `write_ndx_and_attrs`.
+    async fn write_ndx_and_attrs(
+        &mut self,
+        ndx: i32,
+        attrs: &ItemAttrs,
+        entry: &FileEntry,
+        xattr_request: Option<&[u32]>,
+    ) -> Result<()> {
+        self.write_ndx(ndx).await?;
+        if self.protocol.version < 29 {
+            return Ok(());
+        }
+        self.tx.write_u16_le(attrs.iflags).await?;
+        if let Some(basis_type) = attrs.basis_type {
+            self.tx.write_u8(basis_type).await?;
+        }
+        if let Some(xname) = &attrs.xname {
+            self.tx.write_vstring(xname).await?;
+        }
+        if let Some(nums) = xattr_request {
+            send_xattr_values(&mut self.tx, entry, nums).await?;
+        }
+        Ok(())
+    }
+
+    async fn read_sums(&mut self, sum_head: &SumHead) -> Result<Vec<BlockSum>> {
+        ensure!(
+            (0..=16).contains(&sum_head.checksum_len),
+            "invalid checksum length {}",
+            sum_head.checksum_len
+        );
+
+        let mut sums = Vec::with_capacity(sum_head.checksum_count.max(0) as usize);
+        for _ in 0..sum_head.checksum_count {
+            let sum1 = self.rx.read_u32_le().await?;
+            let mut sum2 = vec![0; sum_head.checksum_len as usize];
+            self.rx.read_exact(&mut sum2).await?;
+            sums.push(BlockSum { sum1, sum2 });
+        }
+        Ok(sums)
+    }
+
+    /// Send `file` as literal chunks and references to the receiver's blocks, then the whole file
+    /// checksum.
+    async fn send_data(
+        &mut self,
+        seed: i32,
+        file: File,
+        sum_head: &SumHead,
+        sums: &[BlockSum],
+    ) -> Result<()> {
+        let mut hasher = FileHasher::new(seed, &self.protocol);
+        let mut window = FileWindow::new(file, 2 * sum_head.block_len as usize + CHUNK_SIZE);
+
+        let (transferred, copied) = if sums.is_empty() {
+            (self.send_unmatched(&mut window, &mut hasher).await?, 0)
+        } else {
+            self.send_matched(seed, &mut window, &mut hasher, sum_head, sums)
+                .await?
+        };
+        self.tx.write_i32_le(0).await?;
+
+        self.tx.write_all(&hasher.finalize()).await?;
+
+        info!(
+            ratio = transferred as f64 / (transferred + copied) as f64,
+            "transfer ratio"
+        );
+        Ok(())
+    }
+
+    /// Without block sums the whole file is literal data. Returns its length.
+    async fn send_unmatched(
+        &mut self,
+        window: &mut FileWindow,
+        hasher: &mut FileHasher,
+    ) -> Result<u64> {
+        let mut offset = 0;
+        loop {
+            window.fill(offset, offset + CHUNK_SIZE, hasher).await?;
+            if window.end() == offset {
+                return Ok(offset as u64);
+            }
+            self.send_literal(window.get(offset, window.end())).await?;
+            offset = window.end();
+        }
+    }
+
+    /// Find the receiver's blocks, sliding a rolling checksum over the file and verifying its
+    /// matches with the strong one. Returns the number of bytes sent literally and copied from
+    /// the receiver's blocks.
+    async fn send_matched(
+        &mut self,
+        seed: i32,
+        window: &mut FileWindow,
+        hasher: &mut FileHasher,
+        sum_head: &SumHead,
+        sums: &[BlockSum],
+    ) -> Result<(u64, u64)> {
+        let mut by_sum1: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (block, sum) in sums.iter().enumerate() {
+            by_sum1.entry(sum.sum1).or_default().push(block);
+        }
+
+        let (mut transferred, mut copied) = (0u64, 0u64);
+        // Data from `literal_start` to `offset` isn't covered by a match yet, and `offset` to
+        // `end` is what the rolling checksum covers.
+        let full_len = sum_head.block_len as usize;
+        let mut literal_start = 0;
+        let mut offset = 0;
+        window.fill(0, full_len, hasher).await?;
+        let mut end = window.end().min(full_len);
+        let mut sum1 = RollingChecksum::new(window.get(0, end));
+        // Identical blocks are likely consecutive, so the one after the last match is preferred.
+        let mut want_block = 0;
+        while offset < end {
+            let data = window.get(offset, end);
+            let matched = by_sum1.get(&sum1.digest()).and_then(|candidates| {
+                let mut sum2 = None;
+                let mut matched = None;
+                for &block in candidates {
+                    if block_len(sum_head, block) != data.len() {
+                        continue;
+                    }
+                    let sum2 = sum2.get_or_insert_with(|| checksum_2(seed, data, &self.protocol));
+                    if sum2.starts_with(&sums[block].sum2)
+                        && (matched.is_none() || block == want_block)
+                    {
+                        matched = Some(block);
+                        if block == want_block {
+                            break;
+                        }
+                    }
+                }
+                matched
+            });
+
+            if let Some(block) = matched {
+                debug!(block, offset, "block matched");
+                transferred += (offset - literal_start) as u64;
+                self.send_literal(window.get(literal_start, offset)).await?;
+                self.tx.write_i32_le(-(block as i32 + 1)).await?;
+                copied += (end - offset) as u64;
+                want_block = block + 1;
+                offset = end;
+                literal_start = offset;
+                window.fill(offset, offset + full_len, hasher).await?;
+                end = window.end().min(offset + full_len);
+                sum1 = RollingChecksum::new(window.get(offset, end));
+                continue;
+            }
+
+            // Send unmatched data as it piles up, so the window only has to hold a chunk of it.
+            if offset - literal_start >= CHUNK_SIZE {
+                transferred += CHUNK_SIZE as u64;
+                self.send_literal(window.get(literal_start, literal_start + CHUNK_SIZE))
+                    .await?;
+                literal_start += CHUNK_SIZE;
+            }
+            window.fill(literal_start, end + 1, hasher).await?;
+            if end < window.end() {
+                sum1.roll(window.byte(offset), window.byte(end));
+                offset += 1;
+                end += 1;
+            } else {
+                // Near the end the window shrinks, for the short last block.
+                sum1.roll_out(window.byte(offset));
+                offset += 1;
+            }
+        }
+        transferred += (offset - literal_start) as u64;
+        self.send_literal(window.get(literal_start, offset)).await?;
+        Ok((transferred, copied))
+    }
+
+    async fn send_literal(&mut self, data: &[u8]) -> Result<()> {
+        for chunk in data.chunks(CHUNK_SIZE) {
+            self.tx.write_i32_le(chunk.len() as i32).await?;
+            self.tx.write_all(chunk).await?;
+        }
+        Ok(())
+    }
+}
+
+struct BlockSum {
+    sum1: u32,
+    /// Possibly truncated to `SumHead::checksum_len`.
+    sum2: Vec<u8>,
+}
+
+/// A window sliding over a file being sent, like rsync's `map_ptr`. Everything read passes
+/// through the whole file checksum.
+struct FileWindow {
+    file: File,
+    buf: Vec<u8>,
+    /// The file offset of `buf[0]`.
+    start: usize,
+    capacity: usize,
+    eof: bool,
+}
+
+impl FileWindow {
+    fn new(file: File, capacity: usize) -> Self {
+        Self {
+            file,
+            buf: Vec::with_capacity(capacity),
+            start: 0,
+            capacity,
+            eof: false,
+        }
+    }
+
+    /// Make the data from `from` up to `to` available, or up to the end of the file. Data before
+    /// `from` may be dropped, `to - from` must not exceed the capacity.
+    async fn fill(&mut self, from: usize, to: usize, hasher: &mut FileHasher) -> Result<()> {
+        if to <= self.end() || self.eof {
+            return Ok(());
+        }
+        self.buf.drain(..from - self.start);
+        self.start = from;
+        let mut filled = self.buf.len();
+        self.buf.resize(self.capacity, 0);
+        while filled < self.capacity {
+            let n = self.file.read(&mut self.buf[filled..]).await?;
+            if n == 0 {
+                self.eof = true;
+                break;
+            }
+            hasher.update(&self.buf[filled..filled + n]);
+            filled += n;
+        }
+        self.buf.truncate(filled);
+        Ok(())
+    }
+
+    /// The file offset after the available data.
+    fn end(&self) -> usize {
+        self.start + self.buf.len()
+    }
+
+    fn get(&self, from: usize, to: usize) -> &[u8] {
+        &self.buf[from - self.start..to - self.start]
+    }
+
+    fn byte(&self, offset: usize) -> u8 {
+        self.buf[offset - self.start]
+    }
+}
+
+fn block_len(sum_head: &SumHead, block: usize) -> usize {
+    if block == sum_head.checksum_count as usize - 1 && sum_head.remainder_len != 0 {
+        sum_head.remainder_len as usize
+    } else {
+        sum_head.block_len as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn echo_item_attrs() {
+        let protocol = Protocol::new(31);
+        let version = protocol.version;
+        // Nothing to transfer, but a basis type and name to echo, then the end of every phase.
+        let mut request = vec![];
+        let mut ndx = NdxState::default();
+        ndx.write_ndx(&mut request, version, 0).await.unwrap();
+        request
+            .write_u16_le(ITEM_BASIS_TYPE_FOLLOWS | ITEM_XNAME_FOLLOWS)
+            .await
+            .unwrap();
+        request.write_u8(1).await.unwrap();
+        request.write_vstring(b"basis").await.unwrap();
+        for _ in 0..=protocol.max_phase() {
+            ndx.write_ndx(&mut request, version, NDX_DONE)
+                .await
+                .unwrap();
+        }
+
+        let (mut generator, rx) = duplex(4096);
+        let (tx, mut receiver) = duplex(4096);
+        generator.write_all(&request).await.unwrap();
+        let mut sender = Sender::new(
+            EnvelopeRead::new(BufReader::new(rx), false),
+            EnvelopeWrite::new(tx, false),
+            protocol,
+        );
+        let file_list = [FileEntry::bare("dir", 0o40755)];
+        sender
+            .send_task(0, Path::new("/nonexistent"), &file_list)
+            .await
+            .unwrap();
+        drop(sender);
+
+        let mut echoed = vec![];
+        receiver.read_to_end(&mut echoed).await.unwrap();
+        assert_eq!(echoed, request);
+    }
+}
@@ -1,30 +1,490 @@
-//! TODO I saw this module in the go implementation, but when testing it doesn't seem to be used.
-//! Maybe this is only needed when preserve uid (gid) is enabled?
+//! uid/gid preservation: the id→name lists sent after the file list, and translating the sender's
+//! ids to local ones by name, as rsync's uidlist.c.
 
-use eyre::Result;
-use tokio::io::AsyncReadExt;
-use tracing::info;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::path::Path;
 
+use eyre::{bail, eyre, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::debug;
+
+use crate::envelope::{RsyncReadExt, RsyncWriteExt};
+use crate::file_list::FileEntry;
+use crate::filter::wildmatch;
+use crate::opts::Opts;
 use crate::EnvelopedConn;
 
-impl<'a> EnvelopedConn<'a> {
-    /// Consume id mapping. We don't need this info here.
-    pub async fn consume_uid_mapping(&mut self) -> Result<()> {
-        self.consume_uid_mapping_().await?; // uid
-        self.consume_uid_mapping_().await?; // gid
+/// Which ids the file list carries, `-o` and `-g`, and `-A` for the ids in ACLs. With
+/// `--numeric-ids` no names are sent.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct IdOptions {
+    pub owner: bool,
+    pub group: bool,
+    pub acls: bool,
+    pub numeric_ids: bool,
+}
+
+impl IdOptions {
+    /// Whether the id lists follow the file list.
+    fn send_names(&self) -> bool {
+        (self.owner || self.group || self.acls) && !self.numeric_ids
+    }
+
+    fn users(&self) -> bool {
+        self.owner || self.acls
+    }
+
+    fn groups(&self) -> bool {
+        self.group || self.acls
+    }
+}
+
+/// The sender's names for the ids in its file list.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct IdNames {
+    pub users: HashMap<u32, Vec<u8>>,
+    pub groups: HashMap<u32, Vec<u8>>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum IdKind {
+    User,
+    Group,
+}
+
+/// `--usermap` or `--groupmap`: comma separated `FROM:TO` pairs, the first matching one wins.
+/// `FROM` is a sender's id, an id range `LOW-HIGH`, `*`, or a name which may contain wildcards.
+/// `TO` is a local name or id.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct IdMap {
+    spec: String,
+    rules: Vec<(IdMatch, u32)>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum IdMatch {
+    Range(u32, u32),
+    Name(Vec<u8>),
+}
+
+impl IdMap {
+    pub fn parse_usermap(spec: &str) -> Result<Self> {
+        Self::parse(spec, IdKind::User)
+    }
+
+    pub fn parse_groupmap(spec: &str) -> Result<Self> {
+        Self::parse(spec, IdKind::Group)
+    }
+
+    fn parse(spec: &str, kind: IdKind) -> Result<Self> {
+        let mut rules = vec![];
+        for pair in spec.split(',') {
+            let Some((from, to)) = pair.split_once(':') else {
+                bail!("no colon found in map: {}", pair);
+            };
+            let from = if from == "*" {
+                IdMatch::Range(0, u32::MAX)
+            } else if let Some((low, high)) = from.split_once('-').filter(|_| is_id(from)) {
+                IdMatch::Range(low.parse()?, high.parse()?)
+            } else if is_id(from) {
+                let id = from.parse()?;
+                IdMatch::Range(id, id)
+            } else if !from.is_empty() {
+                IdMatch::Name(from.as_bytes().to_vec())
+            } else {
+                bail!("empty name in map: {}", pair);
+            };
+            let to = match to.parse::<u32>() {
+                Ok(id) => id,
+                Err(_) => lookup_id(kind, to.as_bytes())
+                    .ok_or_else(|| eyre!("unknown {} in map: {}", kind, to))?,
+            };
+            rules.push((from, to));
+        }
+        Ok(Self {
+            spec: spec.to_string(),
+            rules,
+        })
+    }
+
+    fn find(&self, id: u32, name: Option<&[u8]>) -> Option<u32> {
+        self.rules.iter().find_map(|(from, to)| {
+            let matched = match from {
+                IdMatch::Range(low, high) => (*low..=*high).contains(&id),
+                IdMatch::Name(pattern) => name.is_some_and(|name| wildmatch(pattern, name)),
+            };
+            matched.then_some(*to)
+        })
+    }
+}
+
+impl fmt::Display for IdMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.spec)
+    }
+}
+
+/// Digits, optionally a `LOW-HIGH` range of them.
+fn is_id(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|c| c.is_ascii_digit() || c == b'-')
+}
+
+impl fmt::Display for IdKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            IdKind::User => "user",
+            IdKind::Group => "group",
+        })
+    }
+}
+
+/// Translate the sender's ids in `list` to local ones, like rsync's `recv_id_list`: through the
+/// maps first, then by name unless `numeric_ids`. Ids without a local name are kept, and so is 0
+/// unless mapped.
+pub fn map_ids(
+    list: &mut [FileEntry],
+    names: &IdNames,
+    ids: IdOptions,
+    usermap: Option<&IdMap>,
+    groupmap: Option<&IdMap>,
+) {
+    let mut users = HashMap::new();
+    let mut groups = HashMap::new();
+    let mut map_user = |uid: u32| {
+        *users
+            .entry(uid)
+            .or_insert_with(|| map_id(IdKind::User, uid, &names.users, ids, usermap))
+    };
+    let mut map_group = |gid: u32| {
+        *groups
+            .entry(gid)
+            .or_insert_with(|| map_id(IdKind::Group, gid, &names.groups, ids, groupmap))
+    };
+    for entry in list {
+        if ids.owner {
+            entry.uid = map_user(entry.uid);
+        }
+        if ids.group {
+            entry.gid = map_group(entry.gid);
+        }
+        if ids.acls {
+            for name in entry
+                .acl
+                .names
+                .iter_mut()
+                .chain(&mut entry.default_acl.names)
+            {
+                name.id = if name.user {
+                    map_user(name.id)
+                } else {
+                    map_group(name.id)
+                };
+            }
+        }
+    }
+}
+
+fn map_id(
+    kind: IdKind,
+    id: u32,
+    names: &HashMap<u32, Vec<u8>>,
+    ids: IdOptions,
+    map: Option<&IdMap>,
+) -> u32 {
+    let name = names.get(&id).map(Vec::as_slice);
+    let local = map
+        .and_then(|map| map.find(id, name))
+        .or_else(|| {
+            name.filter(|_| !ids.numeric_ids && id != 0)
+                .and_then(|name| lookup_id(kind, name))
+        })
+        .unwrap_or(id);
+    if local != id {
+        debug!(%kind, id, local, "map id");
+    }
+    local
+}
+
+/// Give `path` the owner and group of `entry`, if we preserve them and are root. Symlinks
+/// themselves are changed, not what they point to.
+pub fn set_owner(path: &Path, opts: &Opts, entry: &FileEntry) -> Result<()> {
+    if !(opts.owner || opts.group) || !am_root() {
+        return Ok(());
+    }
+    let uid = opts.owner.then_some(entry.uid);
+    let gid = opts.group.then_some(entry.gid);
+    std::os::unix::fs::lchown(path, uid, gid)
+        .map_err(|e| eyre!("can't chown {}: {}", path.display(), e))
+}
+
+pub fn am_root() -> bool {
+    // SAFETY: geteuid can't fail.
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// The local id of user or group `name`.
+fn lookup_id(kind: IdKind, name: &[u8]) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    let mut buf = vec![0 as libc::c_char; 16384];
+    // SAFETY: the result points into `pwd`/`grp` and `buf`, which outlive it.
+    unsafe {
+        match kind {
+            IdKind::User => {
+                let mut pwd = std::mem::zeroed::<libc::passwd>();
+                let mut result = std::ptr::null_mut();
+                libc::getpwnam_r(
+                    name.as_ptr(),
+                    &mut pwd,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut result,
+                );
+                (!result.is_null()).then_some(pwd.pw_uid)
+            }
+            IdKind::Group => {
+                let mut grp = std::mem::zeroed::<libc::group>();
+                let mut result = std::ptr::null_mut();
+                libc::getgrnam_r(
+                    name.as_ptr(),
+                    &mut grp,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut result,
+                );
+                (!result.is_null()).then_some(grp.gr_gid)
+            }
+        }
+    }
+}
+
+/// The local name of user or group `id`.
+fn lookup_name(kind: IdKind, id: u32) -> Option<Vec<u8>> {
+    let mut buf = vec![0 as libc::c_char; 16384];
+    // SAFETY: the result points into `pwd`/`grp` and `buf`, which outlive it.
+    unsafe {
+        let name = match kind {
+            IdKind::User => {
+                let mut pwd = std::mem::zeroed::<libc::passwd>();
+                let mut result = std::ptr::null_mut();
+                libc::getpwuid_r(id, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result);
+                (!result.is_null()).then_some(pwd.pw_name)?
+            }
+            IdKind::Group => {
+                let mut grp = std::mem::zeroed::<libc::group>();
+                let mut result = std::ptr::null_mut();
+                libc::getgrgid_r(id, &mut grp, buf.as_mut_ptr(), buf.len(), &mut result);
+                (!result.is_null()).then_some(grp.gr_name)?
+            }
+        };
+        Some(CStr::from_ptr(name).to_bytes().to_vec())
+    }
+}
+
+impl<R: AsyncRead + Unpin + Send, W: AsyncWrite + Unpin + Send> EnvelopedConn<R, W> {
+    /// Send our names for the ids in `list`, after the file list. Root's are implied.
+    pub async fn send_id_lists(&mut self, list: &[FileEntry], ids: IdOptions) -> Result<()> {
+        if !ids.send_names() {
+            return Ok(());
+        }
+        for (kind, wanted) in [(IdKind::User, ids.users()), (IdKind::Group, ids.groups())] {
+            if !wanted {
+                continue;
+            }
+            let mut sent = vec![];
+            for entry in list {
+                let (id, sends_id) = match kind {
+                    IdKind::User => (entry.uid, ids.owner),
+                    IdKind::Group => (entry.gid, ids.group),
+                };
+                let acl_ids = entry
+                    .acl
+                    .names
+                    .iter()
+                    .chain(&entry.default_acl.names)
+                    .filter(|name| name.user == (kind == IdKind::User))
+                    .map(|name| name.id);
+                for id in sends_id.then_some(id).into_iter().chain(acl_ids) {
+                    if id == 0 || sent.contains(&id) {
+                        continue;
+                    }
+                    sent.push(id);
+                    let Some(name) = lookup_name(kind, id).filter(|name| name.len() <= 255) else {
+                        continue;
+                    };
+                    self.tx
+                        .write_varint30(self.protocol.version, id as i32)
+                        .await?;
+                    self.tx.write_u8(name.len() as u8).await?;
+                    self.tx.write_all(&name).await?;
+                }
+            }
+            self.tx.write_varint30(self.protocol.version, 0).await?;
+        }
         Ok(())
     }
-    async fn consume_uid_mapping_(&mut self) -> Result<()> {
-        info!("start consume uid");
+
+    /// The counterpart of `send_id_lists`.
+    pub async fn recv_id_lists(&mut self, ids: IdOptions, names: &mut IdNames) -> Result<()> {
+        if !ids.send_names() {
+            return Ok(());
+        }
+        if ids.users() {
+            self.recv_id_list(&mut names.users).await?;
+        }
+        if ids.groups() {
+            self.recv_id_list(&mut names.groups).await?;
+        }
+        Ok(())
+    }
+
+    async fn recv_id_list(&mut self, names: &mut HashMap<u32, Vec<u8>>) -> Result<()> {
         loop {
-            let b = self.rx.read_u32_le().await?;
-            info!(b);
-            if b == 0 {
+            let id = self.rx.read_varint30(self.protocol.version).await? as u32;
+            if id == 0 {
                 break;
             }
-            let len = self.rx.read_u8().await?;
-            self.rx.read_exact(&mut vec![0; len as usize]).await?;
+            let name = self.recv_id_name().await?;
+            names.insert(id, name);
         }
         Ok(())
     }
+
+    /// A name following its id, in the id lists or in a file entry.
+    pub async fn recv_id_name(&mut self) -> Result<Vec<u8>> {
+        let len = self.rx.read_u8().await?;
+        let mut name = vec![0; len as usize];
+        self.rx.read_exact(&mut name).await?;
+        Ok(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acls::AclName;
+
+    #[test]
+    fn parse_maps() {
+        let map = IdMap::parse_usermap("0-99:1000,ali*:1001,bob:root,500:1002,*:65534").unwrap();
+        assert_eq!(
+            map.to_string(),
+            "0-99:1000,ali*:1001,bob:root,500:1002,*:65534"
+        );
+        assert_eq!(map.find(0, Some(b"root")), Some(1000));
+        assert_eq!(map.find(99, None), Some(1000));
+        // The first match wins, names only match when the sender sent one.
+        assert_eq!(map.find(100, Some(b"alice")), Some(1001));
+        assert_eq!(map.find(100, Some(b"bob")), Some(0));
+        assert_eq!(map.find(500, Some(b"alice")), Some(1001));
+        assert_eq!(map.find(500, None), Some(1002));
+        assert_eq!(map.find(4242, Some(b"carol")), Some(65534));
+
+        let map = IdMap::parse_groupmap("root:5,7-9:6").unwrap();
+        assert_eq!(map.find(100, Some(b"root")), Some(5));
+        assert_eq!(map.find(8, Some(b"wheel")), Some(6));
+        assert_eq!(map.find(10, None), None);
+
+        for spec in ["", "1000", ":1", "1-2-3:4", "1:no-such-user-here", "a:1,b"] {
+            assert!(IdMap::parse_usermap(spec).is_err(), "{}", spec);
+        }
+    }
+
+    fn entry(uid: u32, gid: u32) -> FileEntry {
+        let mut entry = FileEntry::bare("f", 0o100644);
+        entry.uid = uid;
+        entry.gid = gid;
+        entry
+    }
+
+    fn names(ids: &[(u32, &str)]) -> HashMap<u32, Vec<u8>> {
+        ids.iter()
+            .map(|(id, name)| (*id, name.as_bytes().to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn map_by_name() {
+        let names = IdNames {
+            users: names(&[(5, "root"), (6, "no-such-user-here")]),
+            groups: names(&[(0, "bin"), (7, "root")]),
+        };
+        let ids = IdOptions {
+            owner: true,
+            group: true,
+            ..IdOptions::default()
+        };
+        let mapped = |ids: IdOptions, usermap: Option<&IdMap>| {
+            let mut list = [entry(5, 0), entry(6, 7), entry(8, 8)];
+            map_ids(&mut list, &names, ids, usermap, None);
+            list.map(|entry| (entry.uid, entry.gid))
+        };
+
+        // Unknown names and ids without a name are kept, and so is 0.
+        assert_eq!(mapped(ids, None), [(0, 0), (6, 0), (8, 8)]);
+        let numeric = IdOptions {
+            numeric_ids: true,
+            ..ids
+        };
+        assert_eq!(mapped(numeric, None), [(5, 0), (6, 7), (8, 8)]);
+        // Maps apply either way.
+        let usermap = IdMap::parse_usermap("no-such-*:9,8:10").unwrap();
+        assert_eq!(mapped(ids, Some(&usermap)), [(0, 0), (9, 0), (10, 8)]);
+        assert_eq!(mapped(numeric, Some(&usermap)), [(5, 0), (9, 7), (10, 8)]);
+    }
+
+    #[tokio::test]
+    async fn id_lists_round_trip() {
+        let mut list = [entry(0, 0), entry(1, 2), entry(2, 2), entry(4_000_000, 1)];
+        list[0].acl.names.push(AclName {
+            user: true,
+            id: 3,
+            perms: 0o7,
+        });
+        list[0].default_acl.names.push(AclName {
+            user: false,
+            id: 3,
+            perms: 0o5,
+        });
+        let ids = IdOptions {
+            owner: true,
+            group: true,
+            acls: true,
+            numeric_ids: false,
+        };
+        // Whatever names this system has, root's are implied.
+        let expected = |kind, ids: &[u32]| {
+            ids.iter()
+                .filter_map(|id| Some((*id, lookup_name(kind, *id)?)))
+                .collect::<HashMap<_, _>>()
+        };
+        let expected = IdNames {
+            users: expected(IdKind::User, &[1, 2, 3, 4_000_000]),
+            groups: expected(IdKind::Group, &[1, 2, 3]),
+        };
+
+        for version in [29, 31] {
+            let mut conn = EnvelopedConn::loopback(version);
+            conn.send_id_lists(&list, ids).await.unwrap();
+            conn.tx.flush().await.unwrap();
+            let mut names = IdNames::default();
+            conn.recv_id_lists(ids, &mut names).await.unwrap();
+            assert_eq!(names, expected, "{}", version);
+        }
+
+        // Nothing is sent with numeric ids.
+        let numeric = IdOptions {
+            numeric_ids: true,
+            ..ids
+        };
+        let mut conn = EnvelopedConn::loopback(31);
+        conn.send_id_lists(&list, numeric).await.unwrap();
+        conn.tx.write_u8(42).await.unwrap();
+        conn.tx.flush().await.unwrap();
+        conn.recv_id_lists(numeric, &mut IdNames::default())
+            .await
+            .unwrap();
+        assert_eq!(conn.rx.read_u8().await.unwrap(), 42);
+    }
 }
@@ -0,0 +1,420 @@
+//! Extended attributes, `-X`: the lists following file entries since protocol 30, as rsync's
+//! xattrs.c. Long values are sent as a digest, the generator asks for those it doesn't have.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use eyre::{bail, ensure, eyre, Result};
+use md5::{Digest, Md5};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::envelope::{RsyncReadExt, RsyncWriteExt};
+use crate::file_list::{FileEntry, IndexedValues};
+use crate::filter::XattrFilter;
+use crate::uid_list::am_root;
+use crate::EnvelopedConn;
+
+/// Longer values are sent as their MD5 digest.
+const MAX_FULL_DATUM: usize = 32;
+const DIGEST_LEN: usize = 16;
+/// Linux's `XATTR_NAME_MAX` and `XATTR_SIZE_MAX`, larger ones couldn't be set anyway.
+const MAX_XATTR_NAME: usize = 255;
+pub const MAX_XATTR_VALUE: usize = 64 * 1024;
+
+const USER_PREFIX: &[u8] = b"user.";
+/// ACLs, sent with `-A`.
+const SYSTEM_PREFIX: &[u8] = b"system.";
+/// rsync's own attributes, only copied with `-XX`.
+const RSYNC_PRIVATE_PREFIX: &[u8] = b"user.rsync.%";
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Xattr {
+    pub name: Vec<u8>,
+    pub value: XattrValue,
+    /// Position in the sender's list, from 1. Abbreviated values are asked for by it.
+    pub num: u32,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum XattrValue {
+    Full(Vec<u8>),
+    /// The length and digest of a long value.
+    Abbrev(usize, [u8; DIGEST_LEN]),
+}
+
+/// The xattrs of `path` to send, sorted by name. ACLs and rsync's own are left out.
+pub fn get_xattrs(path: &Path, filter: &XattrFilter) -> Result<Vec<Xattr>> {
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => return Ok(vec![]),
+        Err(e) => bail!("can't list xattrs of {}: {}", path.display(), e),
+    };
+    let mut xattrs = vec![];
+    for name in names {
+        // TODO unix only
+        let name = name.as_bytes();
+        if name.starts_with(SYSTEM_PREFIX)
+            || name.starts_with(RSYNC_PRIVATE_PREFIX)
+            || filter.is_excluded(name)
+        {
+            continue;
+        }
+        // It may be gone already.
+        if let Some(value) = get_xattr(path, name)? {
+            xattrs.push(Xattr {
+                name: name.to_vec(),
+                value: XattrValue::Full(value),
+                num: 0,
+            });
+        }
+    }
+    xattrs.sort_unstable_by(|x, y| x.name.cmp(&y.name));
+    for (num, xattr) in (1..).zip(&mut xattrs) {
+        xattr.num = num;
+    }
+    Ok(xattrs)
+}
+
+/// Whether we set and remove xattr `name`. Only root may set other namespaces than `user`.
+fn is_managed(name: &[u8], filter: &XattrFilter) -> bool {
+    (name.starts_with(USER_PREFIX) || am_root() && !name.starts_with(SYSTEM_PREFIX))
+        && !name.starts_with(RSYNC_PRIVATE_PREFIX)
+        && !filter.is_excluded(name)
+}
+
+/// The `num`s of the abbreviated values of `entry` which `path` doesn't have.
+pub fn missing_values(path: &Path, entry: &FileEntry, filter: &XattrFilter) -> Result<Vec<u32>> {
+    let mut nums = vec![];
+    for xattr in &entry.xattrs {
+        if !is_managed(&xattr.name, filter) {
+            continue;
+        }
+        if let XattrValue::Abbrev(len, digest) = &xattr.value {
+            if !has_value(path, &xattr.name, *len, digest)? {
+                nums.push(xattr.num);
+            }
+        }
+    }
+    Ok(nums)
+}
+
+/// Give `path` the xattrs of `entry`, and remove the others. Abbreviated values are taken from
+/// `fetched`, or from `basis` if it has them.
+pub fn set_xattrs(
+    path: &Path,
+    entry: &FileEntry,
+    fetched: &HashMap<u32, Vec<u8>>,
+    basis: &Path,
+    filter: &XattrFilter,
+) -> Result<()> {
+    for xattr in &entry.xattrs {
+        if !is_managed(&xattr.name, filter) {
+            continue;
+        }
+        let value = match &xattr.value {
+            XattrValue::Full(value) => Cow::Borrowed(value),
+            XattrValue::Abbrev(..) if fetched.contains_key(&xattr.num) => {
+                Cow::Borrowed(&fetched[&xattr.num])
+            }
+            XattrValue::Abbrev(len, digest) if has_value(basis, &xattr.name, *len, digest)? => {
+                let value = get_xattr(basis, &xattr.name)?;
+                Cow::Owned(value.unwrap_or_default())
+            }
+            XattrValue::Abbrev(..) => bail!(
+                "missing abbreviated xattr value {} for {}",
+                String::from_utf8_lossy(&xattr.name),
+                path.display()
+            ),
+        };
+        if get_xattr(path, &xattr.name)?.as_ref() != Some(&*value) {
+            xattr::set(path, OsStr::from_bytes(&xattr.name), &value)
+                .map_err(|e| eyre!("can't set xattrs of {}: {}", path.display(), e))?;
+        }
+    }
+
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(e) if e.kind() == io::ErrorKind::Unsupported && entry.xattrs.is_empty() => {
+            return Ok(())
+        }
+        Err(e) => bail!("can't list xattrs of {}: {}", path.display(), e),
+    };
+    for name in names {
+        let name = name.as_bytes();
+        if is_managed(name, filter) && !entry.xattrs.iter().any(|xattr| xattr.name == name) {
+            xattr::remove(path, OsStr::from_bytes(name))
+                .map_err(|e| eyre!("can't remove xattrs of {}: {}", path.display(), e))?;
+        }
+    }
+    Ok(())
+}
+
+fn get_xattr(path: &Path, name: &[u8]) -> Result<Option<Vec<u8>>> {
+    match xattr::get(path, OsStr::from_bytes(name)) {
+        Ok(value) => Ok(value),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(eyre!("can't read xattrs of {}: {}", path.display(), e)),
+    }
+}
+
+/// Whether `path` has the value abbreviated to `len` and `digest`.
+fn has_value(path: &Path, name: &[u8], len: usize, digest: &[u8; DIGEST_LEN]) -> Result<bool> {
+    Ok(get_xattr(path, name)?
+        .is_some_and(|value| value.len() == len && Md5::digest(&value)[..] == digest[..]))
+}
+
+impl<R: AsyncRead + Unpin + Send, W: AsyncWrite + Unpin + Send> EnvelopedConn<R, W> {
+    /// Send the xattrs of an entry after it, or the index of the same list sent before.
+    pub async fn send_xattrs(
+        &mut self,
+        xattrs: &[Xattr],
+        sent: &mut IndexedValues<Vec<Xattr>>,
+    ) -> Result<()> {
+        if let Some(ndx) = sent.find_or_insert(&xattrs.to_vec()) {
+            self.tx.write_varint(ndx as i32 + 1).await?;
+            return Ok(());
+        }
+        self.tx.write_varint(0).await?;
+        self.tx.write_varint(xattrs.len() as i32).await?;
+        for xattr in xattrs {
+            let XattrValue::Full(value) = &xattr.value else {
+                bail!("abbreviated xattr value to send");
+            };
+            // Names are NUL terminated.
+            self.tx.write_varint(xattr.name.len() as i32 + 1).await?;
+            self.tx.write_varint(value.len() as i32).await?;
+            self.tx.write_all(&xattr.name).await?;
+            self.tx.write_u8(0).await?;
+            if value.len() > MAX_FULL_DATUM {
+                self.tx.write_all(&Md5::digest(value)).await?;
+            } else {
+                self.tx.write_all(value).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The counterpart of `send_xattrs`.
+    pub async fn recv_xattrs(
+        &mut self,
+        received: &mut IndexedValues<Vec<Xattr>>,
+    ) -> Result<Vec<Xattr>> {
+        let ndx = self.rx.read_varint().await?;
+        if ndx != 0 {
+            return usize::try_from(ndx - 1)
+                .ok()
+                .and_then(|ndx| received.get(ndx))
+                .cloned()
+                .ok_or_else(|| eyre!("xattr index {} out of range", ndx));
+        }
+
+        let count = self.rx.read_varint().await?;
+        let mut xattrs = vec![];
+        for num in 1..=count.max(0) as u32 {
+            let name_len = self.rx.read_varint().await?;
+            let len = check_value_len(self.rx.read_varint().await?)?;
+            // With its NUL.
+            let name_len = usize::try_from(name_len)
+                .ok()
+                .filter(|len| *len <= MAX_XATTR_NAME + 1)
+                .ok_or_else(|| eyre!("invalid xattr name length {}", name_len))?;
+            let mut name = vec![0; name_len];
+            self.rx.read_exact(&mut name).await?;
+            ensure!(name.pop() == Some(0), "invalid xattr name received");
+            let value = if len > MAX_FULL_DATUM {
+                let mut digest = [0; DIGEST_LEN];
+                self.rx.read_exact(&mut digest).await?;
+                XattrValue::Abbrev(len, digest)
+            } else {
+                let mut value = vec![0; len];
+                self.rx.read_exact(&mut value).await?;
+                XattrValue::Full(value)
+            };
+            xattrs.push(Xattr { name, value, num });
+        }
+        received.push(xattrs.clone());
+        Ok(xattrs)
+    }
+}
+
+/// Ask for abbreviated values by `num`, after the index and item flags of an entry.
+pub async fn send_xattr_request<W: AsyncWrite + Unpin + Send>(
+    tx: &mut W,
+    nums: &[u32],
+) -> Result<()> {
+    let mut prev = 0;
+    for &num in nums {
+        tx.write_varint((num - prev) as i32).await?;
+        prev = num;
+    }
+    tx.write_u8(0).await?;
+    Ok(())
+}
+
+/// The counterpart of `send_xattr_request`.
+pub async fn recv_xattr_request<R: AsyncRead + Unpin + Send>(rx: &mut R) -> Result<Vec<u32>> {
+    let mut nums = vec![];
+    let mut num = 0;
+    loop {
+        let delta = rx.read_varint().await?;
+        if delta == 0 {
+            return Ok(nums);
+        }
+        num += delta;
+        nums.push(num as u32);
+    }
+}
+
+/// Answer a request for the values of `nums` of `entry`, after echoing its index and item flags.
+pub async fn send_xattr_values<W: AsyncWrite + Unpin + Send>(
+    tx: &mut W,
+    entry: &FileEntry,
+    nums: &[u32],
+) -> Result<()> {
+    let mut prev = 0;
+    for &num in nums {
+        let value = entry
+            .xattrs
+            .iter()
+            .find(|xattr| xattr.num == num)
+            .and_then(|xattr| match &xattr.value {
+                XattrValue::Full(value) => Some(value),
+                XattrValue::Abbrev(..) => None,
+            })
+            .ok_or_else(|| eyre!("could not find xattr #{} for {}", num, entry.name_lossy()))?;
+        tx.write_varint((num - prev) as i32).await?;
+        tx.write_varint(value.len() as i32).await?;
+        tx.write_all(value).await?;
+        prev = num;
+    }
+    tx.write_u8(0).await?;
+    Ok(())
+}
+
+/// The counterpart of `send_xattr_values`.
+pub async fn recv_xattr_values<R: AsyncRead + Unpin + Send>(
+    rx: &mut R,
+) -> Result<HashMap<u32, Vec<u8>>> {
+    let mut values = HashMap::new();
+    let mut num = 0;
+    loop {
+        let delta = rx.read_varint().await?;
+        if delta == 0 {
+            return Ok(values);
+        }
+        num += delta;
+        let len = check_value_len(rx.read_varint().await?)?;
+        let mut value = vec![0; len];
+        rx.read_exact(&mut value).await?;
+        values.insert(num as u32, value);
+    }
+}
+
+fn check_value_len(len: i32) -> Result<usize> {
+    usize::try_from(len)
+        .ok()
+        .filter(|len| *len <= MAX_XATTR_VALUE)
+        .ok_or_else(|| eyre!("invalid xattr value length {}", len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn xattr(name: &str, value: &[u8], num: u32) -> Xattr {
+        Xattr {
+            name: name.as_bytes().to_vec(),
+            value: XattrValue::Full(value.to_vec()),
+            num,
+        }
+    }
+
+    #[tokio::test]
+    async fn wire_round_trip() {
+        let long = [b'x'; MAX_FULL_DATUM + 1];
+        let lists = [
+            vec![xattr("user.a", b"1", 1), xattr("user.long", &long, 2)],
+            vec![],
+            vec![xattr("user.a", b"1", 1), xattr("user.long", &long, 2)],
+        ];
+        let mut conn = EnvelopedConn::loopback(30);
+        let mut sent = IndexedValues::default();
+        for xattrs in &lists {
+            conn.send_xattrs(xattrs, &mut sent).await.unwrap();
+        }
+        conn.tx.flush().await.unwrap();
+
+        let mut received = IndexedValues::default();
+        let first = conn.recv_xattrs(&mut received).await.unwrap();
+        assert_eq!(first[0], lists[0][0]);
+        // Long values are abbreviated.
+        let digest = Md5::digest(long).into();
+        assert_eq!(
+            first[1].value,
+            XattrValue::Abbrev(MAX_FULL_DATUM + 1, digest)
+        );
+        assert_eq!(first[1].num, 2);
+        assert!(conn.recv_xattrs(&mut received).await.unwrap().is_empty());
+        assert_eq!(conn.recv_xattrs(&mut received).await.unwrap(), first);
+    }
+
+    #[tokio::test]
+    async fn limits() {
+        let mut conn = EnvelopedConn::loopback(30);
+        let name_len = MAX_XATTR_NAME as i32 + 2;
+        let value_len = MAX_XATTR_VALUE as i32 + 1;
+        for (name_len, len) in [(name_len, 0), (2, value_len), (2, -1), (-1, 0)] {
+            conn.tx.write_all(&[0, 1]).await.unwrap();
+            conn.tx.write_varint(name_len).await.unwrap();
+            conn.tx.write_varint(len).await.unwrap();
+        }
+        conn.tx.flush().await.unwrap();
+
+        let mut received = IndexedValues::default();
+        for err in [
+            "invalid xattr name length 257",
+            "invalid xattr value length 65537",
+            "invalid xattr value length -1",
+            "invalid xattr name length -1",
+        ] {
+            let result = conn.recv_xattrs(&mut received).await;
+            assert_eq!(result.unwrap_err().to_string(), err);
+        }
+
+        let mut values = vec![1];
+        values.write_varint(value_len).await.unwrap();
+        assert!(recv_xattr_values(&mut &values[..]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn abbreviated_values() {
+        let mut request = vec![];
+        send_xattr_request(&mut request, &[2, 5]).await.unwrap();
+        let nums = recv_xattr_request(&mut &request[..]).await.unwrap();
+        assert_eq!(nums, [2, 5]);
+
+        let mut entry = FileEntry::bare("file", 0o100644);
+        entry.xattrs = (1..=5)
+            .map(|num| {
+                xattr(
+                    &format!("user.{}", num),
+                    &[b'x'; 40][..num as usize * 8],
+                    num,
+                )
+            })
+            .collect();
+        let mut values = vec![];
+        send_xattr_values(&mut values, &entry, &nums).await.unwrap();
+        let values = recv_xattr_values(&mut &values[..]).await.unwrap();
+        assert_eq!(values.len(), 2);
+        assert_eq!(values[&2], [b'x'; 16]);
+        assert_eq!(values[&5], [b'x'; 40]);
+
+        // Only what we have in full can be sent.
+        assert!(send_xattr_values(&mut vec![], &entry, &[6]).await.is_err());
+    }
+}